@@ -0,0 +1,181 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use serialport::SerialPort;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+// serial port which reads up to a chunk of bytes at once, and serves small reads, like the
+// byte-at-a-time reads of the framing, from its buffer
+pub struct BufferedPort {
+    inner: Box<dyn SerialPort>,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl BufferedPort {
+    pub fn new(inner: Box<dyn SerialPort>, chunk_size: usize) -> BufferedPort {
+        BufferedPort {
+            inner,
+            buffer: vec![0; chunk_size.max(1)],
+            start: 0,
+            end: 0,
+        }
+    }
+}
+
+impl Read for BufferedPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.start == self.end {
+            self.end = self.inner.read(&mut self.buffer)?;
+            self.start = 0;
+        }
+        let n = std::cmp::min(buf.len(), self.end - self.start);
+        buf[..n].copy_from_slice(&self.buffer[self.start..self.start + n]);
+        self.start += n;
+        Ok(n)
+    }
+}
+
+impl Write for BufferedPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl SerialPort for BufferedPort {
+    fn name(&self) -> Option<String> {
+        self.inner.name()
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        self.inner.baud_rate()
+    }
+
+    fn data_bits(&self) -> serialport::Result<serialport::DataBits> {
+        self.inner.data_bits()
+    }
+
+    fn flow_control(&self) -> serialport::Result<serialport::FlowControl> {
+        self.inner.flow_control()
+    }
+
+    fn parity(&self) -> serialport::Result<serialport::Parity> {
+        self.inner.parity()
+    }
+
+    fn stop_bits(&self) -> serialport::Result<serialport::StopBits> {
+        self.inner.stop_bits()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.inner.set_baud_rate(baud_rate)
+    }
+
+    fn set_data_bits(&mut self, data_bits: serialport::DataBits) -> serialport::Result<()> {
+        self.inner.set_data_bits(data_bits)
+    }
+
+    fn set_flow_control(
+        &mut self,
+        flow_control: serialport::FlowControl,
+    ) -> serialport::Result<()> {
+        self.inner.set_flow_control(flow_control)
+    }
+
+    fn set_parity(&mut self, parity: serialport::Parity) -> serialport::Result<()> {
+        self.inner.set_parity(parity)
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: serialport::StopBits) -> serialport::Result<()> {
+        self.inner.set_stop_bits(stop_bits)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.inner.write_request_to_send(level)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.inner.write_data_terminal_ready(level)
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        self.inner.read_clear_to_send()
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        self.inner.read_data_set_ready()
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        self.inner.read_ring_indicator()
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        self.inner.read_carrier_detect()
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.inner.bytes_to_read()? + (self.end - self.start) as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        self.inner.bytes_to_write()
+    }
+
+    fn clear(&self, buffer_to_clear: serialport::ClearBuffer) -> serialport::Result<()> {
+        self.inner.clear(buffer_to_clear)
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        // a clone can't share the buffered bytes
+        if self.start != self.end {
+            return Err(serialport::Error::new(
+                serialport::ErrorKind::Unknown,
+                "cannot clone a buffered port with unread bytes",
+            ));
+        }
+        let inner = self.inner.try_clone()?;
+        Ok(Box::new(BufferedPort::new(inner, self.buffer.len())))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        self.inner.set_break()
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        self.inner.clear_break()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferedPort;
+    use crate::test_serial_port::TestSerialPort;
+    use serialport::SerialPort;
+    use std::io::Read;
+
+    #[test]
+    fn test_clone_with_unread_bytes() {
+        let mut inner = TestSerialPort::new();
+        inner.queue_input(b"hello");
+        let mut port = BufferedPort::new(Box::new(inner), 16);
+        let mut byte = [0u8];
+        port.read_exact(&mut byte).unwrap();
+        assert_eq!(port.bytes_to_read().unwrap(), 4);
+        let e = port.try_clone().err().unwrap();
+        assert_eq!(e.kind, serialport::ErrorKind::Unknown);
+    }
+}
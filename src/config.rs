@@ -0,0 +1,140 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{bail, Error, Result};
+use log::info;
+
+use crate::nmp_hdr::*;
+use crate::transfer::check_rc;
+use crate::transfer::send_request;
+use crate::transfer::SerialSpecs;
+use serde_cbor::Value;
+
+fn config_request(specs: &SerialSpecs, op: NmpOp, id: NmpIdConfig) -> Result<(), Error> {
+    let body: Vec<u8> =
+        serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new()).unwrap();
+    let response_body = send_request(specs, op, NmpGroup::Config, id, &body)?;
+    check_rc(&response_body)
+}
+
+// the value of a setting, as bytes: Zephyr answers with a byte string, older firmware can use a
+// text string or an integer
+pub fn config_read(specs: &SerialSpecs, name: &str) -> Result<Vec<u8>, Error> {
+    info!("config read request");
+    let body = serde_cbor::to_vec(&ConfigValReq {
+        name: name.to_string(),
+        val: None,
+    })?;
+    let response_body = send_request(
+        specs,
+        NmpOp::Read,
+        NmpGroup::Config,
+        NmpIdConfig::Val,
+        &body,
+    )?;
+    check_rc(&response_body)?;
+    let ans: ConfigValRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+    match ans.val {
+        Some(val) => value_bytes(&val),
+        None => bail!("no value in the answer from device"),
+    }
+}
+
+// write a setting, with verify it is read back to find firmware which accepts it but ignores it
+pub fn config_write(
+    specs: &SerialSpecs,
+    name: &str,
+    value: &str,
+    verify: bool,
+) -> Result<(), Error> {
+    info!("config write request");
+    let body = serde_cbor::to_vec(&ConfigValReq {
+        name: name.to_string(),
+        val: Some(Value::Bytes(value.as_bytes().to_vec())),
+    })?;
+    let response_body = send_request(
+        specs,
+        NmpOp::Write,
+        NmpGroup::Config,
+        NmpIdConfig::Val,
+        &body,
+    )?;
+    check_rc(&response_body)?;
+
+    if verify {
+        let read = config_read(specs, name)?;
+        if read != value.as_bytes() {
+            bail!(
+                "{} reads back as {}, instead of the written {:?}",
+                name,
+                format_value(&read),
+                value
+            );
+        }
+    }
+    Ok(())
+}
+
+fn value_bytes(val: &Value) -> Result<Vec<u8>, Error> {
+    match val {
+        Value::Bytes(bytes) => Ok(bytes.clone()),
+        Value::Text(text) => Ok(text.as_bytes().to_vec()),
+        Value::Integer(number) => Ok(number.to_string().into_bytes()),
+        _ => bail!("unexpected type of the value: {:?}", val),
+    }
+}
+
+// text if the value is UTF-8, otherwise hex
+pub fn format_value(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(text) => format!("{:?}", text),
+        Err(_) => format!("0x{}", hex::encode(value)),
+    }
+}
+
+// apply the written settings, without storing them
+pub fn config_commit(specs: &SerialSpecs) -> Result<(), Error> {
+    info!("config commit request");
+    config_request(specs, NmpOp::Write, NmpIdConfig::Commit)
+}
+
+pub fn config_load(specs: &SerialSpecs) -> Result<(), Error> {
+    info!("config load request");
+    config_request(specs, NmpOp::Read, NmpIdConfig::LoadSave)
+}
+
+// store the settings, otherwise written settings are lost after a reboot
+pub fn config_save(specs: &SerialSpecs) -> Result<(), Error> {
+    info!("config save request");
+    config_request(specs, NmpOp::Write, NmpIdConfig::LoadSave)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{config_read, config_write};
+    use crate::transfer::SerialSpecs;
+
+    #[test]
+    fn test_config_read() {
+        let specs = SerialSpecs {
+            device: "test".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(config_read(&specs, "test/text").unwrap(), b"hello");
+        assert_eq!(config_read(&specs, "test/number").unwrap(), b"42");
+        assert!(config_read(&specs, "test/missing").is_err());
+    }
+
+    #[test]
+    fn test_config_write_verify() {
+        let specs = SerialSpecs {
+            device: "test".to_string(),
+            ..Default::default()
+        };
+        config_write(&specs, "test/text", "hello", true).unwrap();
+        config_write(&specs, "test/number", "42", true).unwrap();
+        config_write(&specs, "test/text", "other", false).unwrap();
+        let err = config_write(&specs, "test/text", "other", true).unwrap_err();
+        assert!(format!("{}", err).contains("reads back as \"hello\""));
+    }
+}
@@ -0,0 +1,33 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Error, Result};
+use log::{debug, info};
+
+use crate::nmp_hdr::*;
+use crate::transfer::check_rc;
+use crate::transfer::is_disconnected;
+use crate::transfer::is_timeout;
+use crate::transfer::send_request;
+use crate::transfer::SerialSpecs;
+
+// crash the device on purpose, with a crash type like "div0", "jump0", "ref0", "assert" or
+// "wdog", for testing the core dump handling. The device usually crashes before it answers.
+pub fn crash_trigger(specs: &SerialSpecs, crash_type: String) -> Result<(), Error> {
+    info!("crash trigger request");
+    let body = serde_cbor::to_vec(&CrashTriggerReq { crash_type })?;
+    let response_body = match send_request(
+        specs,
+        NmpOp::Write,
+        NmpGroup::Crash,
+        NmpIdCrash::Trigger,
+        &body,
+    ) {
+        Ok(ret) => ret,
+        Err(e) if is_timeout(&e) || is_disconnected(&e) => {
+            debug!("no answer to the crash request: {:#}", e);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    check_rc(&response_body)
+}
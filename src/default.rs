@@ -3,34 +3,87 @@
 use anyhow::{bail, Error, Result};
 use log::debug;
 use log::info;
-use serde_cbor;
-use serde_json;
+use serialport::SerialPort;
+use std::time::Duration;
 
 use crate::nmp_hdr::*;
+use crate::transfer::add_extra_fields;
+use crate::transfer::check_answer;
+use crate::transfer::check_rc;
 use crate::transfer::encode_request;
+use crate::transfer::get_rc;
+use crate::transfer::is_disconnected;
+use crate::transfer::is_timeout;
 use crate::transfer::next_seq_id;
 use crate::transfer::open_port;
+use crate::transfer::response_json;
+use crate::transfer::send_request;
 use crate::transfer::transceive;
 use crate::transfer::SerialSpecs;
 
-pub fn reset(specs: &SerialSpecs) -> Result<(), Error> {
+// the device may reset before its answer is sent, then the reset is assumed to be done
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResetOutcome {
+    pub acked: bool,
+}
+
+pub fn reset(specs: &SerialSpecs) -> Result<ResetOutcome, Error> {
     info!("send reset request");
 
     // open serial port
     let mut port = open_port(specs)?;
 
+    reset_with_port(specs, &mut *port, false)
+}
+
+// firmware without support for the boot mode ignores it and resets normally, or answers with an rc
+pub fn reset_to_bootloader(specs: &SerialSpecs) -> Result<ResetOutcome, Error> {
+    info!("send reset request to the bootloader");
+
+    // open serial port
+    let mut port = open_port(specs)?;
+
+    reset_with_port(specs, &mut *port, true)
+}
+
+fn reset_with_port(
+    specs: &SerialSpecs,
+    port: &mut dyn SerialPort,
+    to_bootloader: bool,
+) -> Result<ResetOutcome, Error> {
     // send request
-    let body = Vec::new();
+    let body = if to_bootloader {
+        serde_cbor::to_vec(&ResetReq {
+            boot_mode: Some(BOOT_MODE_BOOTLOADER),
+        })?
+    } else {
+        Vec::new()
+    };
+    let body = add_extra_fields(specs, body)?;
     let (data, request_header) = encode_request(
         specs.linelength,
+        specs.crc,
         NmpOp::Write,
         NmpGroup::Default,
         NmpIdDef::Reset,
         &body,
         next_seq_id(),
     )?;
-    let (response_header, response_body) = transceive(&mut *port, &data)?;
-    
+    let (response_header, response_body) = match transceive(
+        &mut *port,
+        specs.crc,
+        &data,
+        request_header.seq,
+        !specs.no_flush,
+    ) {
+        Ok(ret) => ret,
+        Err(e) if is_timeout(&e) || is_disconnected(&e) => {
+            debug!("no answer to the reset request: {:#}", e);
+            return Ok(ResetOutcome { acked: false });
+        }
+        Err(e) => return Err(e),
+    };
+
     // verify sequence id
     if response_header.seq != request_header.seq {
         bail!("wrong sequence number");
@@ -46,22 +99,413 @@ pub fn reset(specs: &SerialSpecs) -> Result<(), Error> {
         "response_body: {}",
         serde_json::to_string_pretty(&response_body)?
     );
-    if let serde_cbor::Value::Map(object) = response_body {
-        for (key, val) in object.iter() {
-            match key {
-                serde_cbor::Value::Text(rc_key) if rc_key == "rc" => {
-                    if let serde_cbor::Value::Integer(rc) = val {
-                        if *rc != 0 {
-                            bail!("rc = {}", rc);
-                        } else {
-                            info!("reset complete");
-                        }
-                    }
-                }
-                _ => (),
+    if let Some(rc) = get_rc(&response_body) {
+        if rc != 0 {
+            bail!("rc = {}, response: {}", rc, response_json(&response_body));
+        }
+    }
+
+    Ok(ResetOutcome { acked: true })
+}
+
+pub fn params(specs: &SerialSpecs) -> Result<McumgrParamsRsp, Error> {
+    info!("send MCUmgr parameters request");
+
+    // open serial port
+    let mut port = open_port(specs)?;
+
+    // send request
+    let body: Vec<u8> =
+        serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new()).unwrap();
+    let body = add_extra_fields(specs, body)?;
+    let (data, request_header) = encode_request(
+        specs.linelength,
+        specs.crc,
+        NmpOp::Read,
+        NmpGroup::Default,
+        NmpIdDef::McumgrParams,
+        &body,
+        next_seq_id(),
+    )?;
+    let (response_header, response_body) = transceive(
+        &mut *port,
+        specs.crc,
+        &data,
+        request_header.seq,
+        !specs.no_flush,
+    )?;
+
+    if !check_answer(&request_header, &response_header, specs.strict_id) {
+        bail!("wrong answer types")
+    }
+
+    check_rc(&response_body)?;
+    let ans: McumgrParamsRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+
+    Ok(ans)
+}
+
+pub fn echo(specs: &SerialSpecs, text: &str) -> Result<String, Error> {
+    info!("send echo request");
+    let body = serde_cbor::to_vec(&EchoReq {
+        text: text.to_string(),
+    })?;
+    let response_body = send_request(
+        specs,
+        NmpOp::Write,
+        NmpGroup::Default,
+        NmpIdDef::Echo,
+        &body,
+    )?;
+    check_rc(&response_body)?;
+
+    let ans: EchoRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+
+    Ok(ans.text)
+}
+
+// the console of the device can echo the received SMP bytes, which disturbs shared UARTs
+pub fn console_echo(specs: &SerialSpecs, enable: bool) -> Result<(), Error> {
+    info!("send console echo request");
+    let body = serde_cbor::to_vec(&ConsEchoCtrlReq {
+        echo: enable as u32,
+    })?;
+    let response_body = send_request(
+        specs,
+        NmpOp::Write,
+        NmpGroup::Default,
+        NmpIdDef::ConsEchoCtrl,
+        &body,
+    )?;
+    check_rc(&response_body)
+}
+
+// the format characters select the fields, like "sv" for the kernel name and version, or "a"
+// for all of them
+pub fn app_info(specs: &SerialSpecs, format: Option<String>) -> Result<String, Error> {
+    info!("send application info request");
+    let body = serde_cbor::to_vec(&AppInfoReq { format })?;
+    let response_body = send_request(
+        specs,
+        NmpOp::Read,
+        NmpGroup::Default,
+        NmpIdDef::AppInfo,
+        &body,
+    )?;
+    check_rc(&response_body)?;
+
+    let ans: AppInfoRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+
+    Ok(ans.output)
+}
+
+// name of the bootloader and, for MCUboot, its mode, or None if the device doesn't support the
+// bootloader info request
+pub fn bootloader_info(specs: &SerialSpecs) -> Result<Option<BootloaderInfoRsp>, Error> {
+    info!("send bootloader info request");
+    let mut ans = match bootloader_query(specs, None)? {
+        Some(ans) => ans,
+        None => return Ok(None),
+    };
+    if ans.bootloader.as_deref() == Some("MCUboot") {
+        if let Some(mode) = bootloader_query(specs, Some("mode"))? {
+            ans.mode = mode.mode;
+            ans.no_downgrade = mode.no_downgrade;
+        }
+    }
+    Ok(Some(ans))
+}
+
+fn bootloader_query(
+    specs: &SerialSpecs,
+    query: Option<&str>,
+) -> Result<Option<BootloaderInfoRsp>, Error> {
+    let body = serde_cbor::to_vec(&BootloaderInfoReq {
+        query: query.map(str::to_string),
+    })?;
+    let response_body = send_request(
+        specs,
+        NmpOp::Read,
+        NmpGroup::Default,
+        NmpIdDef::BootloaderInfo,
+        &body,
+    )?;
+    if get_rc(&response_body) == Some(NmpErr::ENotSup as u32) {
+        return Ok(None);
+    }
+    check_rc(&response_body)?;
+
+    let ans: BootloaderInfoRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+    Ok(Some(ans))
+}
+
+// the MCUboot mode numbers, as sent in the bootloader info
+pub fn bootloader_mode_name(mode: i32) -> &'static str {
+    match mode {
+        0 => "single application",
+        1 => "swap using scratch",
+        2 => "overwrite-only",
+        3 => "swap using move",
+        4 => "direct XIP",
+        5 => "direct XIP with revert",
+        6 => "RAM loader",
+        7 => "firmware loader",
+        8 => "swap using offset",
+        _ => "unknown",
+    }
+}
+
+// common baudrates, tried in this order by the baudrate probe
+const PROBE_BAUDRATES: [u32; 6] = [115_200, 1_000_000, 921_600, 460_800, 230_400, 57_600];
+
+// find the baudrate of a device by sending an echo request with each common baudrate. Any valid
+// answer counts, even with an error code, like when the firmware doesn't have echo.
+pub fn probe_baudrate(specs: &SerialSpecs) -> Result<u32, Error> {
+    for baudrate in PROBE_BAUDRATES {
+        info!("trying baudrate {}", baudrate);
+        let specs = SerialSpecs {
+            baudrate,
+            keep_open: false,
+            ..specs.clone()
+        };
+        match echo_answers(&specs) {
+            Ok(()) => {
+                info!("the device answers with baudrate {}", baudrate);
+                return Ok(baudrate);
             }
+            Err(e) => debug!("no valid answer with baudrate {}: {:#}", baudrate, e),
         }
     }
+    bail!(
+        "no valid answer with any of the baudrates {:?}, specify it with --baudrate",
+        PROBE_BAUDRATES
+    )
+}
 
+fn echo_answers(specs: &SerialSpecs) -> Result<(), Error> {
+    let mut port = open_port(specs)?;
+    port.set_timeout(specs.subsequent_timeout())?;
+    let body = serde_cbor::to_vec(&EchoReq {
+        text: "probe".to_string(),
+    })?;
+    let (data, request_header) = encode_request(
+        specs.linelength,
+        specs.crc,
+        NmpOp::Write,
+        NmpGroup::Default,
+        NmpIdDef::Echo,
+        &body,
+        next_seq_id(),
+    )?;
+    let (response_header, _) = transceive(
+        &mut *port,
+        specs.crc,
+        &data,
+        request_header.seq,
+        !specs.no_flush,
+    )?;
+    if !check_answer(&request_header, &response_header, specs.strict_id) {
+        bail!("wrong answer types")
+    }
     Ok(())
 }
+
+// MTU values tried by the probe, in increasing order
+const PROBE_MTUS: [usize; 7] = [128, 256, 512, 1024, 2048, 4096, 8192];
+
+// find the largest MTU for which the device echoes a request of this size
+pub fn probe_mtu(specs: &SerialSpecs) -> Result<usize, Error> {
+    let mut port = open_port(specs)?;
+    probe_mtu_with_port(specs, &mut *port)
+}
+
+fn probe_mtu_with_port(specs: &SerialSpecs, port: &mut dyn SerialPort) -> Result<usize, Error> {
+    let mut largest = None;
+    for mtu in PROBE_MTUS {
+        // fill the request up to the MTU
+        let mut text_length = mtu;
+        let (data, request_header, text) = loop {
+            let text = "x".repeat(text_length);
+            let body = serde_cbor::to_vec(&EchoReq { text: text.clone() })?;
+            let (data, request_header) = encode_request(
+                specs.linelength,
+                specs.crc,
+                NmpOp::Write,
+                NmpGroup::Default,
+                NmpIdDef::Echo,
+                &body,
+                next_seq_id(),
+            )?;
+            if data.len() <= mtu {
+                break (data, request_header, text);
+            }
+            let reduce = (data.len() - mtu) * 3 / 4 + 3;
+            if reduce > text_length {
+                bail!("MTU {} is too small for an echo request", mtu);
+            }
+            text_length -= reduce;
+        };
+
+        // the request and the echo, at 10 bits per byte, plus some time for the device
+        let transfer_ms = 2 * data.len() as u64 * 10 * 1000 / specs.baudrate as u64;
+        port.set_timeout(specs.subsequent_timeout() + Duration::from_millis(transfer_ms))?;
+
+        info!("trying MTU {}", mtu);
+        let (response_header, response_body) = match transceive(
+            &mut *port,
+            specs.crc,
+            &data,
+            request_header.seq,
+            !specs.no_flush,
+        ) {
+            Ok(ret) => ret,
+            Err(e) => {
+                debug!("no valid answer for MTU {}: {:#}", mtu, e);
+                break;
+            }
+        };
+        if !check_answer(&request_header, &response_header, specs.strict_id) {
+            bail!("wrong answer types")
+        }
+        if let Some(rc) = get_rc(&response_body) {
+            if rc == NmpErr::ENotSup as u32 {
+                bail!("echo is not supported by the firmware");
+            }
+            if rc != 0 {
+                debug!("rc = {} for MTU {}", rc, mtu);
+                break;
+            }
+        }
+        match serde_cbor::value::from_value::<EchoRsp>(response_body) {
+            Ok(ans) if ans.text == text => largest = Some(mtu),
+            _ => {
+                debug!("wrong echo for MTU {}", mtu);
+                break;
+            }
+        }
+    }
+
+    match largest {
+        Some(mtu) => Ok(mtu),
+        None => bail!(
+            "no valid answer for the smallest MTU of {} bytes",
+            PROBE_MTUS[0]
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        console_echo, probe_baudrate, probe_mtu_with_port, reset, reset_to_bootloader,
+        reset_with_port,
+    };
+    use crate::nmp_hdr::{NmpGroup, NmpIdDef};
+    use crate::test_serial_port::{
+        scenario_specs, set_device_baudrate, take_requests, TestSerialPort,
+    };
+    use crate::transfer::{device_rc, SerialSpecs};
+
+    #[test]
+    fn test_reset_outcome() {
+        let specs = SerialSpecs {
+            device: "test".to_string(),
+            ..Default::default()
+        };
+        let mut port = TestSerialPort::new();
+        assert!(reset_with_port(&specs, &mut port, false).unwrap().acked);
+
+        let mut port = TestSerialPort::new();
+        port.skip_responses = 1;
+        assert!(!reset_with_port(&specs, &mut port, false).unwrap().acked);
+
+        let mut port = TestSerialPort::new();
+        assert!(reset_with_port(&specs, &mut port, true).unwrap().acked);
+    }
+
+    #[test]
+    fn test_reset_to_bootloader() {
+        let specs = SerialSpecs {
+            device: "test".to_string(),
+            ..Default::default()
+        };
+        take_requests();
+        assert!(reset_to_bootloader(&specs).unwrap().acked);
+        assert!(reset(&specs).unwrap().acked);
+        let requests = take_requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests
+            .iter()
+            .all(|(header, _)| header.id == NmpIdDef::Reset as u8));
+        let body: std::collections::BTreeMap<String, u32> =
+            serde_cbor::value::from_value(requests[0].1.clone()).unwrap();
+        assert_eq!(body.get("boot_mode"), Some(&1));
+        // a normal reset has no body
+        assert_eq!(requests[1].1, serde_cbor::Value::Null);
+    }
+
+    #[test]
+    fn test_probe_baudrate() {
+        let specs = SerialSpecs {
+            device: "test".to_string(),
+            baudrate: 9600,
+            ..Default::default()
+        };
+        assert_eq!(probe_baudrate(&specs).unwrap(), 115_200);
+
+        // the device only answers at its baudrate
+        set_device_baudrate(921_600);
+        assert_eq!(probe_baudrate(&specs).unwrap(), 921_600);
+        set_device_baudrate(9600);
+        let e = probe_baudrate(&specs).unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("no valid answer with any of the baudrates"));
+        set_device_baudrate(115_200);
+    }
+
+    #[test]
+    fn test_probe_mtu() {
+        let specs = SerialSpecs {
+            device: "test".to_string(),
+            ..Default::default()
+        };
+        let mut port = TestSerialPort::new();
+        port.max_request_len = 3000;
+        assert_eq!(probe_mtu_with_port(&specs, &mut port).unwrap(), 2048);
+
+        let mut port = TestSerialPort::new();
+        port.max_request_len = 100;
+        assert!(probe_mtu_with_port(&specs, &mut port).is_err());
+    }
+
+    #[test]
+    fn test_console_echo() {
+        let specs = SerialSpecs {
+            device: "test".to_string(),
+            ..Default::default()
+        };
+        take_requests();
+        console_echo(&specs, false).unwrap();
+        console_echo(&specs, true).unwrap();
+        let requests = take_requests();
+        assert_eq!(requests.len(), 2);
+        for ((header, body), echo) in requests.iter().zip([0, 1]) {
+            assert_eq!(header.group, NmpGroup::Default);
+            assert_eq!(header.id, NmpIdDef::ConsEchoCtrl as u8);
+            let body: std::collections::BTreeMap<String, u32> =
+                serde_cbor::value::from_value(body.clone()).unwrap();
+            assert_eq!(body.get("echo"), Some(&echo));
+        }
+
+        // firmware without console echo control
+        let (specs, _scenario) =
+            scenario_specs(r#"[{"group": 0, "id": 1, "response": {"rc": 8}}]"#);
+        let e = console_echo(&specs, false).unwrap_err();
+        assert_eq!(device_rc(&e), Some(8));
+    }
+}
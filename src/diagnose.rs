@@ -0,0 +1,130 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Error, Result};
+use log::info;
+use serde::Serialize;
+
+use crate::default::{app_info, bootloader_info, params};
+use crate::image::{list, slot_info};
+use crate::logs::{log_level_list, log_module_list, log_show};
+use crate::nmp_hdr::NmpErr;
+use crate::run::run_list;
+use crate::split::split_status;
+use crate::transfer::{close_kept_port, device_rc, SerialSpecs};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiagnosticStatus {
+    Ok,
+    NotSupported,
+    Error,
+}
+
+// the answer of one request, or why there is none
+#[derive(Debug, Clone, Serialize)]
+pub struct DiagnosticEntry {
+    pub request: &'static str,
+    pub status: DiagnosticStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// the answers of a device to the requests of all groups, like for a support ticket
+#[derive(Debug, Clone, Serialize)]
+pub struct DiagnosticsBundle {
+    pub client_version: &'static str,
+    pub device: String,
+    pub entries: Vec<DiagnosticEntry>,
+}
+
+fn entry<T: Serialize>(request: &'static str, result: Result<T, Error>) -> DiagnosticEntry {
+    let result = result.and_then(|value| Ok(serde_json::to_value(value)?));
+    let (status, response, error) = match result {
+        Ok(value) => (DiagnosticStatus::Ok, Some(value), None),
+        Err(e) if device_rc(&e) == Some(NmpErr::ENotSup as u32) => {
+            (DiagnosticStatus::NotSupported, None, None)
+        }
+        Err(e) => (DiagnosticStatus::Error, None, Some(format!("{:#}", e))),
+    };
+    DiagnosticEntry {
+        request,
+        status,
+        response,
+        error,
+    }
+}
+
+// send the read requests of each group with one connection. A failed request doesn't stop
+// the others, its error is in the bundle.
+pub fn diagnose(specs: &SerialSpecs) -> DiagnosticsBundle {
+    info!("collecting diagnostics");
+    let session = SerialSpecs {
+        keep_open: true,
+        ..specs.clone()
+    };
+    let entries = vec![
+        entry("image list", list(&session)),
+        entry("image slot info", slot_info(&session)),
+        entry("mcumgr params", params(&session)),
+        entry("app info", app_info(&session, Some("a".to_string()))),
+        entry("bootloader info", bootloader_info(&session)),
+        entry("log show", log_show(&session, None, 0)),
+        entry("log modules", log_module_list(&session)),
+        entry("log levels", log_level_list(&session)),
+        entry("split status", split_status(&session)),
+        entry("run list", run_list(&session)),
+    ];
+    if !specs.keep_open {
+        close_kept_port();
+    }
+    DiagnosticsBundle {
+        client_version: env!("CARGO_PKG_VERSION"),
+        device: specs.device.clone(),
+        entries,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diagnose, entry, DiagnosticStatus};
+    use crate::nmp_hdr::NmpErr;
+    use crate::transfer::{DeviceError, SerialSpecs};
+
+    #[test]
+    fn test_diagnostic_entry() {
+        let ok = entry("ok", Ok(vec![1, 2]));
+        assert_eq!(ok.status, DiagnosticStatus::Ok);
+        assert_eq!(ok.response.unwrap(), serde_json::json!([1, 2]));
+
+        let not_supported = entry::<u32>(
+            "not supported",
+            Err(DeviceError {
+                rc: NmpErr::ENotSup as u32,
+                response: String::new(),
+            }
+            .into()),
+        );
+        assert_eq!(not_supported.status, DiagnosticStatus::NotSupported);
+
+        let failed = entry::<u32>("failed", Err(anyhow::anyhow!("no answer")));
+        assert_eq!(failed.status, DiagnosticStatus::Error);
+        assert_eq!(failed.error.unwrap(), "no answer");
+    }
+
+    #[test]
+    fn test_diagnose() {
+        let specs = SerialSpecs {
+            device: "test".to_string(),
+            ..Default::default()
+        };
+        let bundle = diagnose(&specs);
+        assert_eq!(bundle.entries.len(), 10);
+        assert_eq!(bundle.entries[0].request, "image list");
+        assert_eq!(bundle.entries[0].status, DiagnosticStatus::Ok);
+        let json = serde_json::to_value(&bundle).unwrap();
+        assert_eq!(json["device"], "test");
+        assert_eq!(json["entries"][2]["status"], "ok");
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use std::sync::RwLock;
+
+use crate::nmp_hdr::NmpHdr;
+
+/// Protocol events for embedders, like a GUI which shows a timeline of the requests. They are
+/// sent in addition to the log messages.
+#[derive(Debug, Clone)]
+pub enum ProtocolEvent {
+    /// a request was written, with the length of the framed request
+    RequestSent { header: NmpHdr, len: usize },
+    /// the response to a request was received
+    ResponseReceived { header: NmpHdr },
+    /// there was no answer, and the request is sent again
+    Retry { header: NmpHdr, retries_left: u32 },
+    /// the device answered with a non-zero result code
+    RcError { rc: u32 },
+}
+
+type EventHook = Box<dyn Fn(&ProtocolEvent) + Send + Sync>;
+
+static EVENT_HOOK: RwLock<Option<EventHook>> = RwLock::new(None);
+
+/// Registers the function which receives the protocol events of all connections, or removes it
+/// with None.
+pub fn set_event_hook(hook: Option<EventHook>) {
+    *EVENT_HOOK.write().unwrap_or_else(|e| e.into_inner()) = hook;
+}
+
+// true if a hook is registered, to skip preparing events nobody receives
+pub fn has_event_hook() -> bool {
+    EVENT_HOOK
+        .read()
+        .map(|hook| hook.is_some())
+        .unwrap_or(false)
+}
+
+pub fn emit_event(event: ProtocolEvent) {
+    if let Ok(hook) = EVENT_HOOK.read() {
+        if let Some(hook) = hook.as_ref() {
+            hook(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{set_event_hook, ProtocolEvent};
+    use crate::nmp_hdr::*;
+    use crate::test_serial_port::TestSerialPort;
+    use crate::transfer::{encode_request, transceive, Crc};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_event_hook() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let received = events.clone();
+        set_event_hook(Some(Box::new(move |event: &ProtocolEvent| {
+            received.lock().unwrap().push(event.clone());
+        })));
+
+        let body = serde_cbor::to_vec(&EchoReq {
+            text: "hello".to_string(),
+        })
+        .unwrap();
+        let (data, _) = encode_request(
+            128,
+            Crc::Xmodem,
+            NmpOp::Write,
+            NmpGroup::Default,
+            NmpIdDef::Echo,
+            &body,
+            211,
+        )
+        .unwrap();
+        transceive(&mut TestSerialPort::new(), Crc::Xmodem, &data, 211, true).unwrap();
+        set_event_hook(None);
+
+        // other tests can run at the same time, so only look at the events of this request
+        let events = events.lock().unwrap();
+        assert!(events.iter().any(|event| matches!(
+            event,
+            ProtocolEvent::RequestSent { header, len }
+                if header.seq == 211 && header.group == NmpGroup::Default && *len == data.len()
+        )));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            ProtocolEvent::ResponseReceived { header }
+                if header.seq == 211 && header.op == NmpOp::WriteRsp
+        )));
+    }
+}
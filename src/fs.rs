@@ -0,0 +1,49 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Error, Result};
+use log::info;
+
+use crate::nmp_hdr::*;
+use crate::transfer::check_rc;
+use crate::transfer::get_rc;
+use crate::transfer::send_request;
+use crate::transfer::SerialSpecs;
+
+// length of a file on the file system of the device, None if it doesn't exist
+pub fn fs_stat(specs: &SerialSpecs, path: String) -> Result<Option<u64>, Error> {
+    info!("fs status request");
+    let body = serde_cbor::to_vec(&FsStatusReq { name: path })?;
+    let response_body = send_request(specs, NmpOp::Read, NmpGroup::Fs, NmpIdFs::Status, &body)?;
+    if get_rc(&response_body) == Some(NmpErr::ENoEnt as u32) {
+        return Ok(None);
+    }
+    check_rc(&response_body)?;
+    let ans: FsStatusRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+    match ans.len {
+        Some(len) => Ok(Some(len)),
+        None => anyhow::bail!("no file length in the answer from device"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fs_stat;
+    use crate::transfer::SerialSpecs;
+
+    #[test]
+    fn test_fs_stat() {
+        let specs = SerialSpecs {
+            device: "test".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            fs_stat(&specs, "/lfs/test.txt".to_string()).unwrap(),
+            Some(42)
+        );
+        assert_eq!(
+            fs_stat(&specs, "/lfs/missing.txt".to_string()).unwrap(),
+            None
+        );
+    }
+}
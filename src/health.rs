@@ -0,0 +1,128 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::Error;
+use serde::Serialize;
+
+use crate::default::{echo, params};
+use crate::image::list;
+use crate::nmp_hdr::NmpErr;
+use crate::transfer::{device_rc, SerialSpecs};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum HealthStatus {
+    Pass,
+    Fail,
+    // the firmware doesn't have the command, which doesn't fail the check
+    NotSupported,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthCheck {
+    pub name: &'static str,
+    pub status: HealthStatus,
+    pub details: String,
+}
+
+// the results of the checks of a device, like for a pre-flight check in CI
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthReport {
+    pub fn passed(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| check.status != HealthStatus::Fail)
+    }
+}
+
+fn check<T>(
+    name: &'static str,
+    result: Result<T, Error>,
+    details: impl Fn(T) -> String,
+) -> HealthCheck {
+    let (status, details) = match result {
+        Ok(value) => (HealthStatus::Pass, details(value)),
+        Err(e) if device_rc(&e) == Some(NmpErr::ENotSup as u32) => (
+            HealthStatus::NotSupported,
+            "not supported by the firmware".to_string(),
+        ),
+        Err(e) => (HealthStatus::Fail, format!("{:#}", e)),
+    };
+    HealthCheck {
+        name,
+        status,
+        details,
+    }
+}
+
+// check if the device answers an echo, the MCUmgr parameters and the image list
+pub fn health(specs: &SerialSpecs) -> HealthReport {
+    const ECHO_TEXT: &str = "health";
+    let echo_result = echo(specs, ECHO_TEXT).and_then(|text| match text.as_str() {
+        ECHO_TEXT => Ok(text),
+        _ => Err(anyhow::format_err!("unexpected answer \"{}\"", text)),
+    });
+    let mut checks = vec![check("echo", echo_result, |_| {
+        "device answered".to_string()
+    })];
+    checks.push(check("params", params(specs), |p| {
+        format!("buffer size {}, buffer count {}", p.buf_size, p.buf_count)
+    }));
+    checks.push(check("list", list(specs), |state| {
+        let active = state
+            .images
+            .iter()
+            .find(|entry| entry.active)
+            .map(|entry| format!(", active version {}", entry.version))
+            .unwrap_or_default();
+        format!("{} images{}", state.images.len(), active)
+    }));
+    HealthReport { checks }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{health, HealthStatus};
+    use crate::test_serial_port::scenario_specs;
+    use crate::transfer::SerialSpecs;
+
+    #[test]
+    fn test_health() {
+        let specs = SerialSpecs {
+            device: "test".to_string(),
+            ..Default::default()
+        };
+        let report = health(&specs);
+        assert!(report.passed());
+        let names: Vec<_> = report.checks.iter().map(|check| check.name).collect();
+        assert_eq!(names, vec!["echo", "params", "list"]);
+        assert!(report
+            .checks
+            .iter()
+            .all(|check| check.status == HealthStatus::Pass));
+    }
+
+    #[test]
+    fn test_health_not_supported() {
+        // params isn't supported, and the image list fails
+        let (specs, _scenario) = scenario_specs(
+            r#"[
+                {"group": 0, "id": 6, "response": {"rc": 8}},
+                {"group": 1, "id": 0, "response": {"rc": 1}}
+            ]"#,
+        );
+        let report = health(&specs);
+        let statuses: Vec<_> = report.checks.iter().map(|check| check.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                HealthStatus::Pass,
+                HealthStatus::NotSupported,
+                HealthStatus::Fail
+            ]
+        );
+        assert!(!report.passed());
+    }
+}
@@ -1,133 +1,340 @@
 // Copyright © 2023-2024 Vouch.io LLC
 
-use anyhow::{bail, Error, Result};
+use anyhow::{bail, Context, Error, Result};
+use base64::{engine::general_purpose, Engine as _};
 use humantime::format_duration;
 use log::{debug, info, warn};
-use serde_cbor;
-use serde_json;
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
 use sha2::{Digest, Sha256};
+use std::fmt;
 use std::fs::read;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 use std::time::Duration;
 use std::time::Instant;
 
+use crate::events::{emit_event, ProtocolEvent};
+use crate::mcuboot::{image_hash, image_tlvs, is_mcuboot_image};
 use crate::nmp_hdr::*;
+use crate::transfer::add_extra_fields;
+use crate::transfer::canonical_cbor;
+use crate::transfer::check_answer;
+use crate::transfer::check_rc;
 use crate::transfer::encode_request;
+use crate::transfer::framed_len;
+use crate::transfer::get_rc;
+use crate::transfer::is_disconnected;
+use crate::transfer::is_timeout;
 use crate::transfer::next_seq_id;
 use crate::transfer::open_port;
+use crate::transfer::receive_response_timed;
+use crate::transfer::response_json;
+use crate::transfer::send_frame;
+use crate::transfer::send_request;
 use crate::transfer::transceive;
+use crate::transfer::usb_serial_number;
+use crate::transfer::wait_for_port;
+use crate::transfer::Framing;
 use crate::transfer::SerialSpecs;
+use crate::transfer::HEADER_LEN;
 
-fn get_rc(response_body: &serde_cbor::Value) -> Option<u32> {
-    let mut rc: Option<u32> = None;
-    if let serde_cbor::Value::Map(object) = response_body {
-        for (key, val) in object.iter() {
-            match key {
-                serde_cbor::Value::Text(rc_key) if rc_key == "rc" => {
-                    if let serde_cbor::Value::Integer(parsed_rc) = val {
-                        rc = Some(*parsed_rc as u32);
-                    }
-                }
-                _ => (),
-            }
-        }
-    }
-    rc
-}
-
-fn check_answer(request_header: &NmpHdr, response_header: &NmpHdr) -> bool {
-    // verify sequence id
-    if response_header.seq != request_header.seq {
-        log::debug!("wrong sequence number");
-        return false;
-    }
-
-    let expected_op_type = match request_header.op {
-        NmpOp::Read => NmpOp::ReadRsp,
-        NmpOp::Write => NmpOp::WriteRsp,
-        _ => return false,
-    };
-
-    // verify response
-    if response_header.op != expected_op_type || response_header.group != request_header.group {
-        log::debug!("wrong response types");
-        return false;
-    }
-
-    true
-}
-
-pub fn erase(specs: &SerialSpecs, slot: Option<u32>) -> Result<(), Error> {
+// result codes in ignore_rc are treated as success, like for an already erased slot
+pub fn erase(
+    specs: &SerialSpecs,
+    slot: Option<u32>,
+    ignore_rc: &[u32],
+) -> Result<ImageEraseRsp, Error> {
     info!("erase request");
 
     // open serial port
     let mut port = open_port(specs)?;
 
-    let req = ImageEraseReq { slot: slot };
-    let body = serde_cbor::to_vec(&req)?;
+    erase_with_port(specs, &mut *port, slot, ignore_rc)
+}
+
+fn erase_with_port(
+    specs: &SerialSpecs,
+    port: &mut dyn SerialPort,
+    slot: Option<u32>,
+    ignore_rc: &[u32],
+) -> Result<ImageEraseRsp, Error> {
+    let req = ImageEraseReq { slot };
+    let body = add_extra_fields(specs, serde_cbor::to_vec(&req)?)?;
     // send request
     let (data, request_header) = encode_request(
         specs.linelength,
+        specs.crc,
         NmpOp::Write,
         NmpGroup::Image,
         NmpIdImage::Erase,
         &body,
         next_seq_id(),
     )?;
-    let (response_header, response_body) = transceive(&mut *port, &data)?;
+    let (response_header, response_body) = transceive(
+        &mut *port,
+        specs.crc,
+        &data,
+        request_header.seq,
+        !specs.no_flush,
+    )?;
 
-    if !check_answer(&request_header, &response_header) {
+    if !check_answer(&request_header, &response_header, specs.strict_id) {
         bail!("wrong answer types")
     }
 
-    if let Some(rc) = get_rc(&response_body) {
-        if rc != 0 {
-            bail!("Error from device: {}", rc);
+    log::debug!("{:?}", response_body);
+    let response = response_json(&response_body);
+    let ans: ImageEraseRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+
+    if let Some(rc) = ans.rc {
+        if ignore_rc.contains(&rc) {
+            info!("ignoring rc {} from device", rc);
+        } else if rc != 0 {
+            bail!("Error from device: {}, response: {}", rc, response);
         }
     }
 
-    log::debug!("{:?}", response_body);
-    Ok(())
+    Ok(ans)
+}
+
+// with confirm false, the image is booted once at the next reset, and reverted after another
+// reset, unless it is confirmed. With confirm true, it is booted permanently.
+fn set_state(
+    specs: &SerialSpecs,
+    hash: Option<Vec<u8>>,
+    confirm: Option<bool>,
+) -> Result<ImageStateWriteRsp, Error> {
+    let req = ImageStateReq { hash, confirm };
+    let body = serde_cbor::to_vec(&req)?;
+    let response_body = send_request(
+        specs,
+        NmpOp::Write,
+        NmpGroup::Image,
+        NmpIdImage::State,
+        &body,
+    )?;
+    check_rc(&response_body)?;
+
+    let ans: ImageStateWriteRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+
+    Ok(ans)
 }
 
-pub fn test(specs: &SerialSpecs, hash: Vec<u8>, confirm: Option<bool>) -> Result<(), Error> {
+pub fn test(
+    specs: &SerialSpecs,
+    hash: Vec<u8>,
+    confirm: Option<bool>,
+) -> Result<ImageStateWriteRsp, Error> {
     info!("set image pending request");
+    set_state(specs, Some(hash), confirm)
+}
+
+// mark an image as permanent, or the running image, if no hash is given
+pub fn confirm(specs: &SerialSpecs, hash: Option<Vec<u8>>) -> Result<ImageStateWriteRsp, Error> {
+    info!("confirm image request");
+    set_state(specs, hash, Some(true))
+}
+
+// mark the image in the other slot of the running image as pending, to boot it again
+pub fn revert(specs: &SerialSpecs) -> Result<ImageStateWriteRsp, Error> {
+    let images = list(specs)?.images;
+    let active = images
+        .iter()
+        .find(|image| image.active)
+        .context("no active image found")?;
+    let other = images
+        .iter()
+        .find(|image| image.image == active.image && !image.active && !image.hash.is_empty())
+        .with_context(|| format!("no other image found for image {}", active.image))?;
+
+    info!(
+        "revert to image {} slot {}, version {}",
+        other.image, other.slot, other.version
+    );
+    set_state(specs, Some(other.hash.clone()), Some(false))
+}
+
+// parse an image hash as hex, with an optional 0x prefix and whitespace, as decimal byte list,
+// like [97, 221, ...], or as base64
+pub fn parse_hash(text: &str) -> Result<Vec<u8>, Error> {
+    let compact: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let hex = compact
+        .strip_prefix("0x")
+        .or_else(|| compact.strip_prefix("0X"))
+        .unwrap_or(&compact);
+    let hash = if let Ok(hash) = hex::decode(hex) {
+        hash
+    } else if compact.contains(',') {
+        compact
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .split(',')
+            .map(|byte| byte.parse::<u8>())
+            .collect::<Result<Vec<u8>, _>>()
+            .with_context(|| format!("invalid byte list in hash {}", text))?
+    } else if let Ok(hash) = general_purpose::STANDARD.decode(&compact) {
+        hash
+    } else {
+        bail!(
+            "invalid hash {}, expected hex like 0x61ddbce8..., a byte list like [97, 221, ...] or base64",
+            text
+        );
+    };
+    // SHA-256, SHA-384 or SHA-512
+    if ![32, 48, 64].contains(&hash.len()) {
+        bail!(
+            "invalid hash {}, expected 32 bytes for SHA-256, or 48 or 64 bytes, but got {} bytes",
+            text,
+            hash.len()
+        );
+    }
+    Ok(hash)
+}
+
+// the hash of an image file and of the image in a slot of the device
+#[derive(Debug, Clone)]
+pub struct VerifyResult {
+    pub file_hash: Vec<u8>,
+    // None if the slot is empty
+    pub slot_hash: Option<Vec<u8>>,
+}
+
+impl VerifyResult {
+    pub fn matches(&self) -> bool {
+        self.slot_hash.as_ref() == Some(&self.file_hash)
+    }
+}
+
+// compare an image file with the image in the slot, with the hash which MCUboot calculates
+pub fn verify(
+    specs: &SerialSpecs,
+    filename: &PathBuf,
+    image: u32,
+    slot: u32,
+) -> Result<VerifyResult, Error> {
+    let data = load_image(specs, filename)?;
+    let file_hash = if is_mcuboot_image(&data) {
+        image_hash(&data)?
+    } else {
+        warn!("no MCUboot image, using the hash of the whole file");
+        Sha256::digest(&data).to_vec()
+    };
+    let slot_hash = list(specs)?
+        .images
+        .into_iter()
+        .find(|entry| entry.image == image && entry.slot == slot)
+        .map(|entry| entry.hash)
+        .filter(|hash| !hash.is_empty());
+    Ok(VerifyResult {
+        file_hash,
+        slot_hash,
+    })
+}
+
+// the hash of the only bootable image which isn't running, like a just uploaded image
+pub fn inactive_image_hash(specs: &SerialSpecs) -> Result<Vec<u8>, Error> {
+    let images = list(specs)?.images;
+    let image = inactive_image(&images)?;
+    info!(
+        "using the hash of image {} slot {}, version {}",
+        image.image, image.slot, image.version
+    );
+    Ok(image.hash.clone())
+}
+
+fn inactive_image(images: &[ImageStateEntry]) -> Result<&ImageStateEntry, Error> {
+    let candidates: Vec<&ImageStateEntry> = images
+        .iter()
+        .filter(|image| !image.active && image.bootable && !image.hash.is_empty())
+        .collect();
+    match candidates.as_slice() {
+        [image] => Ok(image),
+        [] => bail!("no bootable image found which isn't running"),
+        _ => bail!(
+            "{} bootable images found which aren't running, please specify the hash",
+            candidates.len()
+        ),
+    }
+}
+
+// the swap the bootloader does at the next boot, derived from the image state flags
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SwapType {
+    None,
+    Test,
+    Permanent,
+    Revert,
+}
+
+impl fmt::Display for SwapType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SwapType::None => write!(f, "none"),
+            SwapType::Test => write!(f, "test the new image on next boot"),
+            SwapType::Permanent => write!(f, "use the new image permanently on next boot"),
+            SwapType::Revert => write!(f, "revert on next boot"),
+        }
+    }
+}
+
+// a pending image is swapped in, otherwise an unconfirmed running image is swapped back out
+pub fn swap_type(images: &[ImageStateEntry]) -> SwapType {
+    if let Some(image) = images.iter().find(|image| image.pending) {
+        if image.permanent {
+            return SwapType::Permanent;
+        }
+        return SwapType::Test;
+    }
+    if images.iter().any(|image| image.active && !image.confirmed) {
+        return SwapType::Revert;
+    }
+    SwapType::None
+}
+
+pub fn list(specs: &SerialSpecs) -> Result<ImageStateRsp, Error> {
+    info!("send image list request");
 
     // open serial port
     let mut port = open_port(specs)?;
 
-    let req = ImageStateReq {
-        hash: hash,
-        confirm: confirm,
-    };
-    let body = serde_cbor::to_vec(&req)?;
     // send request
+    let body: Vec<u8> =
+        serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new()).unwrap();
+    let body = add_extra_fields(specs, body)?;
     let (data, request_header) = encode_request(
         specs.linelength,
-        NmpOp::Write,
+        specs.crc,
+        NmpOp::Read,
         NmpGroup::Image,
         NmpIdImage::State,
         &body,
         next_seq_id(),
     )?;
-    let (response_header, response_body) = transceive(&mut *port, &data)?;
+    let (response_header, response_body) = transceive(
+        &mut *port,
+        specs.crc,
+        &data,
+        request_header.seq,
+        !specs.no_flush,
+    )?;
 
-    if !check_answer(&request_header, &response_header) {
+    if !check_answer(&request_header, &response_header, specs.strict_id) {
         bail!("wrong answer types")
     }
 
-    if let Some(rc) = get_rc(&response_body) {
-        if rc != 0 {
-            return Err(anyhow::format_err!("Error from device: {}", rc));
-        }
-    }
+    let ans: ImageStateRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
 
-    log::debug!("{:?}", response_body);
-    Ok(())
+    Ok(ans)
 }
 
-pub fn list(specs: &SerialSpecs) -> Result<ImageStateRsp, Error> {
-    info!("send image list request");
+pub fn slot_info(specs: &SerialSpecs) -> Result<SlotInfoRsp, Error> {
+    info!("send slot info request");
 
     // open serial port
     let mut port = open_port(specs)?;
@@ -135,32 +342,415 @@ pub fn list(specs: &SerialSpecs) -> Result<ImageStateRsp, Error> {
     // send request
     let body: Vec<u8> =
         serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new()).unwrap();
+    let body = add_extra_fields(specs, body)?;
     let (data, request_header) = encode_request(
         specs.linelength,
+        specs.crc,
         NmpOp::Read,
         NmpGroup::Image,
-        NmpIdImage::State,
+        NmpIdImage::SlotInfo,
         &body,
         next_seq_id(),
     )?;
-    let (response_header, response_body) = transceive(&mut *port, &data)?;
+    let (response_header, response_body) = transceive(
+        &mut *port,
+        specs.crc,
+        &data,
+        request_header.seq,
+        !specs.no_flush,
+    )?;
 
-    if !check_answer(&request_header, &response_header) {
+    if !check_answer(&request_header, &response_header, specs.strict_id) {
         bail!("wrong answer types")
     }
 
-    let ans: ImageStateRsp = serde_cbor::value::from_value(response_body)
+    match get_rc(&response_body) {
+        Some(rc) if rc == NmpErr::ENotSup as u32 => {
+            bail!("slot info is not supported by the firmware, use list instead")
+        }
+        Some(rc) if rc != 0 => bail!(
+            "Error from device: {}, response: {}",
+            rc,
+            response_json(&response_body)
+        ),
+        _ => (),
+    }
+
+    let ans: SlotInfoRsp = serde_cbor::value::from_value(response_body)
         .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
 
     Ok(ans)
 }
 
+// verify that the device accepts uploads of this length to the slots, before spending time on
+// the transfer
+fn check_upload_slots(specs: &SerialSpecs, uploads: &[(u8, usize)]) -> Result<(), Error> {
+    if specs.no_slot_check {
+        return Ok(());
+    }
+    match slot_info(specs) {
+        Ok(info) => check_upload_slots_info(&info, uploads),
+        Err(e) => {
+            info!("no slot check: {:#}", e);
+            Ok(())
+        }
+    }
+}
+
+fn check_upload_slots_info(info: &SlotInfoRsp, uploads: &[(u8, usize)]) -> Result<(), Error> {
+    let mut valid: Vec<u32> = info
+        .images
+        .iter()
+        .flat_map(|image| image.slots.iter())
+        .filter_map(|slot| slot.upload_image_id)
+        .collect();
+    // older firmware doesn't send the upload ids
+    if valid.is_empty() {
+        return Ok(());
+    }
+    valid.sort_unstable();
+    valid.dedup();
+    for (slot, len) in uploads {
+        if !valid.contains(&(*slot as u32)) {
+            let valid: Vec<String> = valid.iter().map(|slot| slot.to_string()).collect();
+            bail!(
+                "slot {} doesn't exist on the device, valid slots: {}, or use --no-slot-check",
+                slot,
+                valid.join(", ")
+            );
+        }
+        if let Some(capacity) = upload_slot_capacity(info, *slot) {
+            if *len as u64 > capacity {
+                bail!(
+                    "image ({} bytes) exceeds slot capacity ({} bytes), or use --no-slot-check",
+                    len,
+                    capacity
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// the largest image which fits in the upload slot: the maximum image size when the firmware
+// sends it, which leaves room for the MCUboot trailer, otherwise the size of the slot
+fn upload_slot_capacity(info: &SlotInfoRsp, slot: u8) -> Option<u64> {
+    info.images.iter().find_map(|image| {
+        let entry = image
+            .slots
+            .iter()
+            .find(|entry| entry.upload_image_id == Some(slot as u32))?;
+        Some(image.max_image_size.unwrap_or(entry.size))
+    })
+}
+
+// desired state of one image, as given in a manifest file
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ImageStateManifestEntry {
+    #[serde(default)]
+    pub image: u32,
+    pub slot: Option<u32>,
+    // hex encoded, or when missing, the hash of the image in the slot
+    pub hash: Option<String>,
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+// set the image states in the order of the manifest, and return the result for each entry
+pub fn apply_state(
+    specs: &SerialSpecs,
+    manifest: &[ImageStateManifestEntry],
+) -> Vec<Result<(), Error>> {
+    let mut images: Option<Vec<ImageStateEntry>> = None;
+    let mut results = Vec::new();
+    for entry in manifest {
+        let result = || -> Result<(), Error> {
+            let hash = match (&entry.hash, entry.slot) {
+                (Some(hash), _) => parse_hash(hash)?,
+                (None, Some(slot)) => {
+                    if images.is_none() {
+                        images = Some(list(specs)?.images);
+                    }
+                    images
+                        .iter()
+                        .flatten()
+                        .find(|image| image.image == entry.image && image.slot == slot)
+                        .map(|image| image.hash.clone())
+                        .with_context(|| {
+                            format!("no image {} found in slot {}", entry.image, slot)
+                        })?
+                }
+                (None, None) => bail!("either hash or slot is required"),
+            };
+            test(specs, hash, Some(entry.confirm)).map(|_| ())
+        }();
+        results.push(result);
+    }
+    results
+}
+
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "upload cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+// transfer statistics of an upload
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadStats {
+    pub bytes: u64,
+    pub elapsed: Duration,
+    pub bytes_per_sec: f64,
+    pub sent_blocks: u32,
+    pub confirmed_blocks: u32,
+    pub timing: UploadTiming,
+}
+
+// time spent in the phases of the requests of an upload, to find out why it is slow
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UploadTiming {
+    pub encode: Duration,
+    pub write: Duration,
+    pub wait: Duration,
+    pub decode: Duration,
+}
+
+impl UploadStats {
+    // percentage of the sent blocks without an answer, 0 if nothing was sent
+    pub fn packet_loss(&self) -> u32 {
+        if self.sent_blocks == 0 {
+            return 0;
+        }
+        100 - self.confirmed_blocks * 100 / self.sent_blocks
+    }
+}
+
+// the last answer of the device and the statistics of an upload
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadResult {
+    pub response: ImageUploadRsp,
+    pub stats: UploadStats,
+    // the hash which the device shows in the image list for the uploaded MCUboot image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_hash: Option<Vec<u8>>,
+}
+
+// a device with a smaller receive buffer than the SMP packets drops or rejects the request
+fn mtu_hint(specs: &SerialSpecs) -> String {
+    format!(
+        "the device buffer might be smaller than the SMP packets of up to {} bytes with MTU {}, \
+         try a smaller --mtu or --mtu auto",
+        specs.max_packet_len(),
+        specs.mtu
+    )
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+// read an image file, and decompress it, if it is gzip or xz compressed
+fn load_image(specs: &SerialSpecs, filename: &PathBuf) -> Result<Vec<u8>, Error> {
+    let data =
+        read(filename).with_context(|| format!("failed to read {}", filename.to_string_lossy()))?;
+    if specs.no_decompress {
+        return Ok(data);
+    }
+
+    let mut decompressed = Vec::new();
+    if data.starts_with(GZIP_MAGIC) {
+        flate2::read::GzDecoder::new(&data[..])
+            .read_to_end(&mut decompressed)
+            .with_context(|| format!("failed to decompress {}", filename.to_string_lossy()))?;
+    } else if data.starts_with(XZ_MAGIC) {
+        lzma_rs::xz_decompress(&mut &data[..], &mut decompressed)
+            .map_err(|e| anyhow::format_err!("{:?}", e))
+            .with_context(|| format!("failed to decompress {}", filename.to_string_lossy()))?;
+    } else {
+        return Ok(data);
+    }
+
+    info!(
+        "decompressed {} bytes to {} bytes",
+        data.len(),
+        decompressed.len()
+    );
+    Ok(decompressed)
+}
+
+// the MCUboot header has no image number, but an image can't depend on itself, so a dependency
+// on the target image hints at a wrong image number, like a network core image for image 0
+fn check_image_number(data: &[u8], image: u8) -> Option<String> {
+    if !is_mcuboot_image(data) {
+        return None;
+    }
+    let tlvs = image_tlvs(data)
+        .map_err(|e| debug!("no TLVs for the image number check: {:#}", e))
+        .ok()?;
+    tlvs.iter()
+        .filter_map(|tlv| tlv.dependency())
+        .find(|(dependency, _)| *dependency == image)
+        .map(|(_, version)| {
+            format!(
+                "the file depends on image {} version >= {}, but is uploaded as image {}, \
+                 check the image number",
+                image, version, image
+            )
+        })
+}
+
+// the image number the file is probably for on a device with two images, like the application
+// and network core of the nRF5340: the other image than the one of its dependencies
+fn image_number_hint(data: &[u8]) -> Option<u8> {
+    if !is_mcuboot_image(data) {
+        return None;
+    }
+    let mut dependencies: Vec<u8> = image_tlvs(data)
+        .ok()?
+        .iter()
+        .filter_map(|tlv| tlv.dependency())
+        .map(|(image, _)| image)
+        .collect();
+    dependencies.sort_unstable();
+    dependencies.dedup();
+    match dependencies[..] {
+        [0] => Some(1),
+        [1] => Some(0),
+        _ => None,
+    }
+}
+
+// the slot to upload to, slot 1 and 3 are the upload slots of image 0 and 1. With auto_image,
+// the slot of the image number hint of the file, otherwise the given slot with a warning if the
+// file doesn't fit to it
+fn upload_slot_for_file(
+    data: &[u8],
+    slot: u8,
+    slot_from_filename: bool,
+    auto_image: bool,
+) -> (u8, Option<String>) {
+    let warning = match check_image_number(data, slot / 2) {
+        Some(warning) => warning,
+        None => return (slot, None),
+    };
+    if auto_image {
+        if let Some(image) = image_number_hint(data) {
+            info!(
+                "--auto-image: uploading to slot {} of image {}",
+                image * 2 + 1,
+                image
+            );
+            return (image * 2 + 1, None);
+        }
+    }
+    let warning = if slot_from_filename {
+        format!("{}, slot {} is from the file name", warning, slot)
+    } else {
+        warning
+    };
+    (slot, Some(warning))
+}
+
+// the image number for an upload of multiple images, corrected with auto_image
+fn upload_image_for_file(data: &[u8], image: u8, auto_image: bool) -> (u8, Option<String>) {
+    let warning = match check_image_number(data, image) {
+        Some(warning) => warning,
+        None => return (image, None),
+    };
+    match image_number_hint(data) {
+        Some(hint) if auto_image => {
+            info!("--auto-image: uploading as image {}", hint);
+            (hint, None)
+        }
+        _ => (image, Some(warning)),
+    }
+}
+
+// the progress of an upload in a file, to continue it after the program was restarted
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+struct UploadState {
+    file_hash: String,
+    offset: u64,
+}
+
+// the offset to continue the upload of this image at, 0 without a state of the same image
+fn read_upload_state(path: &Path, file_hash: &str, len: usize) -> usize {
+    let state: UploadState = match std::fs::read(path)
+        .map_err(Error::from)
+        .and_then(|data| Ok(serde_json::from_slice(&data)?))
+    {
+        Ok(state) => state,
+        Err(e) => {
+            debug!("no upload state in {}: {:#}", path.to_string_lossy(), e);
+            return 0;
+        }
+    };
+    if state.file_hash != file_hash {
+        info!("the upload state is for another image, starting at 0");
+        return 0;
+    }
+    if state.offset as usize >= len {
+        return 0;
+    }
+    info!("continuing the upload at offset {}", state.offset);
+    state.offset as usize
+}
+
+fn write_upload_state(path: &Path, file_hash: &str, offset: u64) -> Result<(), Error> {
+    let state = UploadState {
+        file_hash: file_hash.to_string(),
+        offset,
+    };
+    std::fs::write(path, serde_json::to_vec(&state)?)
+        .with_context(|| format!("failed to write {}", path.to_string_lossy()))
+}
+
+/// Uploads the file to the device. When `cancel` is set, the upload stops before the next
+/// chunk with a [`Cancelled`] error, and the slot on the device is left partially written.
 pub fn upload<F>(
     specs: &SerialSpecs,
     filename: &PathBuf,
     slot: u8,
+    progress: Option<F>,
+    cancel: Option<&AtomicBool>,
+) -> Result<UploadResult, Error>
+where
+    F: FnMut(u64, u64),
+{
+    upload_file(specs, filename, slot, None, progress, cancel)
+}
+
+// like upload, and the confirmed offset is written to the state file after each chunk. When
+// the state file is for the same image, the upload continues at its offset, like after the
+// program was interrupted. The device must still have the state of the upload, otherwise it
+// answers with a lower offset and the upload continues there. The state file is deleted when
+// the upload is complete.
+pub fn upload_resumable<F>(
+    specs: &SerialSpecs,
+    filename: &PathBuf,
+    slot: u8,
+    state_file: &Path,
+    progress: Option<F>,
+    cancel: Option<&AtomicBool>,
+) -> Result<UploadResult, Error>
+where
+    F: FnMut(u64, u64),
+{
+    upload_file(specs, filename, slot, Some(state_file), progress, cancel)
+}
+
+fn upload_file<F>(
+    specs: &SerialSpecs,
+    filename: &PathBuf,
+    slot: u8,
+    state_file: Option<&Path>,
     mut progress: Option<F>,
-) -> Result<(), Error>
+    cancel: Option<&AtomicBool>,
+) -> Result<UploadResult, Error>
 where
     F: FnMut(u64, u64),
 {
@@ -170,104 +760,509 @@ where
     // special feature: if the name contains "slot1" or "slot3", then use this slot
     let filename_lowercase = filename_string.to_lowercase();
     let mut slot = slot;
+    let mut slot_from_filename = false;
     if filename_lowercase.contains(&"slot1".to_lowercase()) {
         slot = 1;
+        slot_from_filename = true;
     }
     if filename_lowercase.contains(&"slot3".to_lowercase()) {
         slot = 3;
+        slot_from_filename = true;
+    }
+
+    // load file
+    let data = load_image(specs, filename)?;
+    info!("{} bytes to transfer", data.len());
+    let (slot, warning) = upload_slot_for_file(&data, slot, slot_from_filename, specs.auto_image);
+    if let Some(warning) = warning {
+        warn!("{}", warning);
     }
     info!("flashing to slot {}", slot);
+    check_upload_slots(specs, &[(slot, data.len())])?;
 
     // open serial port
     let mut port = open_port(specs)?;
 
-    // load file
-    let data = read(filename)?;
-    info!("{} bytes to transfer", data.len());
+    if specs.reset_upload_state {
+        info!("erasing slot {} to clear the upload state", slot);
+        erase_with_port(specs, &mut *port, Some(slot as u32), &[])?;
+    }
+
+    let state_file = match state_file {
+        Some(path) => path,
+        None if specs.auto_recover => {
+            return upload_recovering(specs, port, &data, slot, 0, progress, cancel);
+        }
+        None => return upload_image(specs, &mut *port, &data, slot, progress, cancel),
+    };
+
+    let file_hash = hex::encode(Sha256::digest(&data));
+    let start = read_upload_state(state_file, &file_hash, data.len());
+    let saving_progress = |offset, total| {
+        if let Err(e) = write_upload_state(state_file, &file_hash, offset) {
+            warn!("{:#}", e);
+        }
+        if let Some(f) = progress.as_mut() {
+            f(offset, total);
+        }
+    };
+    let result = if specs.auto_recover {
+        upload_recovering(
+            specs,
+            port,
+            &data,
+            slot,
+            start,
+            Some(saving_progress),
+            cancel,
+        )
+    } else {
+        upload_data(
+            specs,
+            &mut *port,
+            &data,
+            slot,
+            0,
+            start,
+            Some(saving_progress),
+            cancel,
+        )
+    };
+    if result.is_ok() {
+        if let Err(e) = std::fs::remove_file(state_file) {
+            warn!("failed to delete {}: {}", state_file.to_string_lossy(), e);
+        }
+    }
+    result
+}
+
+// reopen the port when the device disconnects during the upload, like a bootloader which resets
+// after erasing the slot, and continue at the last confirmed offset. The number of reconnects is
+// limited by nb_retry, like the retries of a packet, as there is no separate option for it.
+fn upload_recovering<F>(
+    specs: &SerialSpecs,
+    mut port: Box<dyn SerialPort>,
+    data: &[u8],
+    slot: u8,
+    start: usize,
+    mut progress: Option<F>,
+    cancel: Option<&AtomicBool>,
+) -> Result<UploadResult, Error>
+where
+    F: FnMut(u64, u64),
+{
+    let serial_number = usb_serial_number(&specs.device);
+    let mut specs = specs.clone();
+    let mut attempts_left = specs.nb_retry;
+    let mut confirmed = start as u64;
+    loop {
+        let start = confirmed as usize;
+        let attempt_progress = |offset, total| {
+            confirmed = offset;
+            if let Some(f) = progress.as_mut() {
+                f(offset, total);
+            }
+        };
+        match upload_data(
+            &specs,
+            &mut *port,
+            data,
+            slot,
+            0,
+            start,
+            Some(attempt_progress),
+            cancel,
+        ) {
+            Err(e) if attempts_left > 0 && is_disconnected(&e) => {
+                attempts_left -= 1;
+                warn!(
+                    "device disconnected at offset {}, waiting for it to continue the upload, {} \
+                     attempts left: {:#}",
+                    confirmed, attempts_left, e
+                );
+                drop(port);
+                specs.device = wait_for_port(
+                    &specs.device,
+                    serial_number.as_deref(),
+                    specs.initial_timeout(),
+                )?;
+                info!("reconnecting to {}", specs.device);
+                port = open_port(&specs)?;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub fn upload_multi<F>(
+    specs: &SerialSpecs,
+    images: &[(PathBuf, u8)],
+    mut progress: Option<F>,
+    cancel: Option<&AtomicBool>,
+) -> Result<Vec<UploadResult>, Error>
+where
+    F: FnMut(u64, u64),
+{
+    // load all files first, for the total size of the progress
+    let mut files = Vec::new();
+    for (filename, image) in images {
+        let data = load_image(specs, filename)?;
+        let (image, warning) = upload_image_for_file(&data, *image, specs.auto_image);
+        if let Some(warning) = warning {
+            warn!("{}: {}", filename.to_string_lossy(), warning);
+        }
+        files.push((filename, image, data));
+    }
+    let total: u64 = files.iter().map(|(_, _, data)| data.len() as u64).sum();
+    let uploads: Vec<(u8, usize)> = files
+        .iter()
+        .map(|(_, image, data)| (*image, data.len()))
+        .collect();
+    check_upload_slots(specs, &uploads)?;
+
+    // open serial port
+    let mut port = open_port(specs)?;
+
+    if specs.reset_upload_state {
+        for (_, image, _) in &files {
+            info!("erasing slot {} to clear the upload state", image);
+            erase_with_port(specs, &mut *port, Some(*image as u32), &[])?;
+        }
+    }
+
+    let mut done: u64 = 0;
+    let mut answers = Vec::new();
+    for (filename, image, data) in files {
+        info!(
+            "upload file: {} to image {}, {} bytes to transfer",
+            filename.to_string_lossy(),
+            image,
+            data.len()
+        );
+        let image_progress = progress
+            .as_mut()
+            .map(|f| move |offset, _| f(done + offset, total));
+        let ans = upload_image(specs, &mut *port, &data, image, image_progress, cancel)
+            .with_context(|| {
+                format!(
+                    "upload of image {} ({}) failed",
+                    image,
+                    filename.to_string_lossy()
+                )
+            })?;
+        done += data.len() as u64;
+        answers.push(ans);
+    }
+
+    Ok(answers)
+}
+
+// upload the same file to several devices at once, like for flashing boards on a test rack.
+// Each device is handled by its own thread, with its own port and sequence ids. The progress
+// gets the index of the device. The results are in the order of the devices.
+pub fn upload_all<F>(
+    devices: Vec<SerialSpecs>,
+    filename: &PathBuf,
+    slot: u8,
+    progress: Option<F>,
+) -> Vec<Result<UploadResult, Error>>
+where
+    F: Fn(usize, u64, u64) + Sync,
+{
+    let progress = progress.as_ref();
+    thread::scope(|scope| {
+        let handles: Vec<_> = devices
+            .iter()
+            .enumerate()
+            .map(|(index, specs)| {
+                scope.spawn(move || {
+                    let device_progress =
+                        progress.map(|f| move |offset, total| f(index, offset, total));
+                    upload(specs, filename, slot, device_progress, None)
+                        .with_context(|| format!("upload to {} failed", specs.device))
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow::format_err!("upload thread panicked")))
+            })
+            .collect()
+    })
+}
+
+// advanced: write the data to the flash address of a custom bootloader, without checking if
+// this overwrites the bootloader itself
+pub fn raw_upload<F>(
+    specs: &SerialSpecs,
+    filename: &PathBuf,
+    address: u32,
+    progress: Option<F>,
+    cancel: Option<&AtomicBool>,
+) -> Result<UploadResult, Error>
+where
+    F: FnMut(u64, u64),
+{
+    let data = load_image(specs, filename)?;
+    warn!(
+        "raw upload of {} bytes to flash address 0x{:x}",
+        data.len(),
+        address
+    );
+
+    // open serial port
+    let mut port = open_port(specs)?;
+
+    upload_data(specs, &mut *port, &data, 0, address, 0, progress, cancel)
+}
+
+fn upload_image<F>(
+    specs: &SerialSpecs,
+    port: &mut dyn SerialPort,
+    data: &[u8],
+    slot: u8,
+    progress: Option<F>,
+    cancel: Option<&AtomicBool>,
+) -> Result<UploadResult, Error>
+where
+    F: FnMut(u64, u64),
+{
+    upload_data(specs, port, data, slot, 0, 0, progress, cancel)
+}
+
+// length of a CBOR byte string header for this number of bytes
+fn cbor_bytes_header_len(len: usize) -> usize {
+    match len {
+        0..=23 => 1,
+        24..=0xff => 2,
+        0x100..=0xffff => 3,
+        _ => 5,
+    }
+}
+
+// the largest number of image bytes in an upload request, for which the body is at most
+// max_payload bytes and the framed request at most MTU bytes. empty_body_len is the length of
+// the body without image data.
+fn max_chunk_len(specs: &SerialSpecs, empty_body_len: usize) -> usize {
+    let fits = |len: usize| {
+        let body_len = empty_body_len - cbor_bytes_header_len(0) + cbor_bytes_header_len(len) + len;
+        let request_len = match specs.framing {
+            Framing::Console => framed_len(specs.linelength, body_len),
+            Framing::Raw => HEADER_LEN + body_len,
+        };
+        body_len <= specs.max_payload.unwrap_or(usize::MAX) && request_len <= specs.mtu
+    };
+
+    // the request length grows with the data length, so search for the largest which fits
+    if !fits(0) {
+        return 0;
+    }
+    let (mut low, mut high) = (0, specs.mtu);
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if fits(mid) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    low
+}
+
+// upload the data in chunks, with the offsets starting at base. A start after 0 continues an
+// interrupted upload, at the offset which the device reports for it.
+#[allow(clippy::too_many_arguments)]
+fn upload_data<F>(
+    specs: &SerialSpecs,
+    port: &mut dyn SerialPort,
+    data: &[u8],
+    slot: u8,
+    base: u32,
+    start: usize,
+    mut progress: Option<F>,
+    cancel: Option<&AtomicBool>,
+) -> Result<UploadResult, Error>
+where
+    F: FnMut(u64, u64),
+{
+    // the offsets and the length are 32 bit, so all offsets fit if the end fits
+    let end = u32::try_from(data.len())
+        .ok()
+        .and_then(|len| base.checked_add(len));
+    if end.is_none() {
+        bail!(
+            "{} bytes at offset 0x{:x} are beyond the 32 bit offsets of the upload",
+            data.len(),
+            base
+        );
+    }
+
+    // the device erases the slot when receiving the first chunk, which can take a while
+    port.set_timeout(specs.initial_timeout())?;
 
     // transfer in blocks
-    let mut off: usize = 0;
+    let mut off: usize = start;
+    let mut resuming = start > 0;
     let start_time = Instant::now();
     let mut sent_blocks: u32 = 0;
+    let mut sent_bytes: u64 = 0;
     let mut confirmed_blocks: u32 = 0;
+    let mut timing = UploadTiming::default();
+    let data_sha = if specs.no_sha {
+        None
+    } else {
+        Some(Sha256::digest(data).to_vec())
+    };
+    let mut last_answer;
     loop {
+        if let Some(cancel) = cancel {
+            if cancel.load(Ordering::SeqCst) {
+                return Err(Cancelled.into());
+            }
+        }
+
         let mut nb_retry = specs.nb_retry;
         let off_start = off;
-        let mut try_length = specs.mtu;
-        debug!("try_length: {}", try_length);
         let seq_id = next_seq_id();
-        loop {
-            // get slot
-            let image_num = slot;
 
-            // create image upload request
-            if off + try_length > data.len() {
-                try_length = data.len() - off;
-            }
-            let chunk = data[off..off + try_length].to_vec();
-            let len = data.len() as u32;
-            let req = if off == 0 {
+        // the request fields besides the data, the first request also has the length and hash
+        let upload_req = |chunk: Vec<u8>| {
+            if off == 0 {
                 ImageUploadReq {
-                    image_num,
-                    off: off as u32,
-                    len: Some(len),
-                    data_sha: Some(Sha256::digest(&data).to_vec()),
+                    image_num: slot,
+                    off: base + off as u32,
+                    len: Some(data.len() as u32),
+                    data_sha: data_sha.clone(),
                     upgrade: None,
                     data: chunk,
                 }
             } else {
                 ImageUploadReq {
-                    image_num,
-                    off: off as u32,
-                    len: None,
+                    image_num: slot,
+                    off: base + off as u32,
+                    len: specs.len_every_chunk.then_some(data.len() as u32),
                     data_sha: None,
                     upgrade: None,
                     data: chunk,
                 }
-            };
-            debug!("req: {:?}", req);
-
-            // convert to bytes with CBOR
-            let body = serde_cbor::to_vec(&req)?;
-            let (chunk, request_header) = encode_request(
-                specs.linelength,
-                NmpOp::Write,
-                NmpGroup::Image,
-                NmpIdImage::Upload,
-                &body,
-                seq_id,
-            )?;
-
-            // test if too long
-            if chunk.len() > specs.mtu {
-                let reduce = chunk.len() - specs.mtu;
-                if reduce > try_length {
-                    bail!("MTU too small");
-                }
+            }
+        };
 
-                // number of bytes to reduce is base64 encoded, calculate back the number of bytes
-                // and then reduce a bit more for base64 filling and rounding
-                try_length -= reduce * 3 / 4 + 3;
-                debug!("new try_length: {}", try_length);
-                continue;
+        // the largest chunk for which the request fits, calculated from the request without data
+        let encode_start = Instant::now();
+        let empty_body = add_extra_fields(specs, serde_cbor::to_vec(&upload_req(Vec::new()))?)?;
+        let max_length = max_chunk_len(specs, empty_body.len());
+        if max_length == 0 && !data.is_empty() {
+            bail!(
+                "MTU too small: {} bytes leave no room for image data, try a larger --mtu",
+                specs.mtu
+            );
+        }
+        let try_length = max_length.min(data.len() - off);
+        debug!("try_length: {}", try_length);
+        let req = upload_req(data[off..off + try_length].to_vec());
+        debug!("req: {:?}", req);
+
+        // convert to bytes with CBOR
+        let mut body = add_extra_fields(specs, serde_cbor::to_vec(&req)?)?;
+        if specs.canonical_cbor {
+            body = canonical_cbor(&body)?;
+        }
+        let (chunk, request_header) = encode_request(
+            specs.linelength,
+            specs.crc,
+            NmpOp::Write,
+            NmpGroup::Image,
+            NmpIdImage::Upload,
+            &body,
+            seq_id,
+        )?;
+        timing.encode += encode_start.elapsed();
+
+        loop {
+            // guard against a device which never completes the upload
+            sent_bytes += try_length as u64;
+            if let Some(max) = specs.max_upload_bytes.filter(|max| sent_bytes > *max) {
+                bail!(
+                    "upload aborted after sending more than {} bytes, at offset {} of {}",
+                    max,
+                    off,
+                    data.len()
+                );
+            }
+            if let Some(max) = specs
+                .max_upload_duration
+                .filter(|max| start_time.elapsed() > *max)
+            {
+                bail!(
+                    "upload aborted after more than {}, at offset {} of {}",
+                    format_duration(max),
+                    off,
+                    data.len()
+                );
             }
 
             // send request
             sent_blocks += 1;
-            let (response_header, response_body) = match transceive(&mut *port, &chunk) {
+            let write_start = Instant::now();
+            send_frame(&mut *port, &chunk, !specs.no_flush)?;
+            let wait_start = Instant::now();
+            timing.write += wait_start - write_start;
+            let mut frame_decode = Duration::ZERO;
+            let received = receive_response_timed(
+                &mut *port,
+                specs.crc,
+                request_header.seq,
+                &mut frame_decode,
+            );
+            let decode_start = Instant::now();
+            timing.wait += (decode_start - wait_start).saturating_sub(frame_decode);
+            timing.decode += frame_decode;
+            let (response_header, response_body) = match received {
                 Ok(ret) => ret,
-                Err(e) if e.to_string() == "Operation timed out" => {
+                Err(e) if is_timeout(&e) => {
                     if nb_retry == 0 {
-                        return Err(e);
+                        let phase = if off_start == 0 {
+                            "initial"
+                        } else {
+                            "subsequent"
+                        };
+                        return Err(e.context(format!(
+                            "no answer from device ({}={}, after {} retries at off={}), {}",
+                            phase,
+                            format_duration(port.timeout()),
+                            specs.nb_retry,
+                            base as usize + off_start,
+                            mtu_hint(specs)
+                        )));
                     }
                     nb_retry -= 1;
                     debug!("missed answer, nb_retry: {}", nb_retry);
+                    emit_event(ProtocolEvent::Retry {
+                        header: request_header,
+                        retries_left: nb_retry,
+                    });
+
+                    // back off before retrying, to give a struggling device some time
+                    let delay = specs.retry_delay(specs.nb_retry - nb_retry - 1);
+                    if !delay.is_zero() {
+                        debug!("retrying in {}", format_duration(delay));
+                        thread::sleep(delay);
+                    }
+
+                    // the device may still be erasing the slot, which can take longer
+                    if off_start == 0 {
+                        port.set_timeout(specs.erase_timeout())?;
+                    }
                     continue;
                 }
                 Err(e) => return Err(e),
             };
 
-            if !check_answer(&request_header, &response_header) {
+            if !check_answer(&request_header, &response_header, specs.strict_id) {
                 bail!("wrong answer types")
             }
 
@@ -276,33 +1271,49 @@ where
                 "response_body: {}",
                 serde_json::to_string_pretty(&response_body)?
             );
-            if let serde_cbor::Value::Map(object) = response_body {
-                for (key, val) in object.iter() {
-                    match key {
-                        serde_cbor::Value::Text(rc_key) if rc_key == "rc" => {
-                            if let serde_cbor::Value::Integer(rc) = val {
-                                if *rc != 0 {
-                                    bail!("rc = {}", rc);
-                                }
-                            }
-                        }
-                        serde_cbor::Value::Text(off_key) if off_key == "off" => {
-                            if let serde_cbor::Value::Integer(off_val) = val {
-                                off = *off_val as usize;
-                            }
-                        }
-                        _ => (),
-                    }
+            let response = response_json(&response_body);
+            let ans: ImageUploadRsp = serde_cbor::value::from_value(response_body)
+                .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+            if let Some(rc) = ans.rc.filter(|rc| *rc != 0) {
+                emit_event(ProtocolEvent::RcError { rc });
+            }
+            match ans.rc {
+                Some(rc) if rc == NmpErr::EMsgSize as u32 => {
+                    bail!("rc = {}, {}, response: {}", rc, mtu_hint(specs), response)
                 }
+                Some(rc) if rc != 0 => bail!("rc = {}, response: {}", rc, response),
+                _ => (),
             }
+            if let Some(off_val) = ans.off {
+                if off_val < base {
+                    bail!("wrong offset received");
+                }
+                off = (off_val - base) as usize;
+            }
+            last_answer = ans;
             confirmed_blocks += 1;
+            timing.decode += decode_start.elapsed();
             break;
         }
 
-        // next chunk, next off should have been sent from the device
-        if off_start == off {
+        // next chunk, next off should have been sent from the device. A device which lost the
+        // upload state, like after a reset, continues at a lower offset.
+        if resuming && off < off_start {
+            warn!("the device continues the upload at offset {}", off);
+        } else if off <= off_start && !data.is_empty() {
             bail!("wrong offset received");
         }
+        resuming = false;
+
+        // the device may have accepted more than this chunk, then continue from its offset
+        if off > off_start + try_length {
+            debug!(
+                "device advanced offset to {}, beyond the sent chunk end {}",
+                off,
+                off_start + try_length
+            );
+            off = off.min(data.len());
+        }
 
         if let Some(ref mut f) = progress {
             f(off as u64, data.len() as u64);
@@ -315,19 +1326,1179 @@ where
 
         // The first packet was sent and the device has cleared its internal flash
         // We can now lower the timeout in case of failed transmission
-        port.set_timeout(Duration::from_millis(specs.subsequent_timeout_ms as u64))?;
+        port.set_timeout(specs.subsequent_timeout())?;
+    }
+
+    let elapsed = start_time.elapsed();
+    let stats = UploadStats {
+        bytes: data.len() as u64,
+        elapsed,
+        bytes_per_sec: if elapsed.is_zero() {
+            0.0
+        } else {
+            data.len() as f64 / elapsed.as_secs_f64()
+        },
+        sent_blocks,
+        confirmed_blocks,
+        timing,
+    };
+    let formatted_duration =
+        format_duration(Duration::from_secs(elapsed.as_secs_f64().round() as u64));
+    info!(
+        "upload took {}, {:.0} bytes/s",
+        formatted_duration, stats.bytes_per_sec
+    );
+    if stats.packet_loss() != 0 {
+        warn!("upload packet loss {}%", stats.packet_loss());
     }
 
-    let elapsed = start_time.elapsed().as_secs_f64().round();
-    let elapsed_duration = Duration::from_secs(elapsed as u64);
-    let formatted_duration = format_duration(elapsed_duration);
-    info!("upload took {}", formatted_duration);
-    if confirmed_blocks != sent_blocks {
-        warn!(
-            "upload packet loss {}%",
-            100 - confirmed_blocks * 100 / sent_blocks
+    // verify the image hash, if the device sent it
+    if last_answer.matched == Some(false) {
+        bail!("the device reports that the hash of the uploaded image does not match");
+    }
+    if let Some(sha) = &last_answer.sha {
+        let expected = Sha256::digest(data).to_vec();
+        if *sha != expected {
+            bail!(
+                "hash of the uploaded image on the device {} does not match the file hash {}",
+                hex::encode(sha),
+                hex::encode(expected)
+            );
+        }
+        debug!("hash of the uploaded image verified");
+    }
+
+    let image_hash = if is_mcuboot_image(data) {
+        image_hash(data)
+            .map_err(|e| debug!("no image hash: {:#}", e))
+            .ok()
+    } else {
+        None
+    };
+    Ok(UploadResult {
+        response: last_answer,
+        stats,
+        image_hash,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_image_number, check_upload_slots_info, erase_with_port, image_number_hint,
+        inactive_image, load_image, max_chunk_len, parse_hash, read_upload_state, swap_type,
+        upload, upload_all, upload_data, upload_image, upload_image_for_file, upload_multi,
+        upload_recovering, upload_resumable, upload_slot_for_file, write_upload_state, Cancelled,
+        SwapType,
+    };
+    use crate::mcuboot::image_hash;
+    use crate::nmp_hdr::{
+        ImageStateEntry, ImageUploadReq, NmpErr, SlotInfoImage, SlotInfoRsp, SlotInfoSlot,
+    };
+    use crate::nmp_hdr::{NmpGroup, NmpIdImage, NmpOp};
+    use crate::test_serial_port::{take_requests, TestScenario, TestSerialPort};
+    use crate::transfer::{
+        encode_request, framed_len, is_disconnected, is_timeout, Framing, SerialSpecs,
+    };
+    use base64::{engine::general_purpose, Engine as _};
+    use byteorder::{ByteOrder, LittleEndian};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use sha2::{Digest, Sha256};
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    fn test_specs() -> SerialSpecs {
+        SerialSpecs {
+            device: "test".to_string(),
+            retry_base_delay_ms: 1,
+            retry_max_delay_ms: 4,
+            ..Default::default()
+        }
+    }
+
+    fn image_entry(slot: u32, active: bool, confirmed: bool) -> ImageStateEntry {
+        ImageStateEntry {
+            image: 0,
+            slot,
+            version: "1.0.0".to_string(),
+            hash: vec![slot as u8; 32],
+            bootable: true,
+            pending: false,
+            confirmed,
+            active,
+            permanent: false,
+        }
+    }
+
+    #[test]
+    fn test_inactive_image() {
+        // only the running image
+        let running = image_entry(0, true, true);
+        let e = inactive_image(std::slice::from_ref(&running)).unwrap_err();
+        assert_eq!(e.to_string(), "no bootable image found which isn't running");
+
+        // a not bootable or empty slot doesn't count
+        let mut not_bootable = image_entry(1, false, false);
+        not_bootable.bootable = false;
+        let mut empty = image_entry(1, false, false);
+        empty.hash.clear();
+        assert!(inactive_image(&[running.clone(), not_bootable, empty]).is_err());
+
+        // one uploaded image
+        let uploaded = image_entry(1, false, false);
+        let images = [running.clone(), uploaded.clone()];
+        assert_eq!(inactive_image(&images).unwrap().hash, uploaded.hash);
+
+        // several images which aren't running, like on a device with two images
+        let mut other = image_entry(3, false, false);
+        other.image = 1;
+        let e = inactive_image(&[running, uploaded, other]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "2 bootable images found which aren't running, please specify the hash"
         );
     }
 
-    Ok(())
+    #[test]
+    fn test_max_chunk_len() {
+        for (mtu, linelength, max_payload) in [
+            (512, 128, None),
+            (4096, 8192, None),
+            (4096, 8192, Some(1000)),
+            (200, 100, None),
+        ] {
+            let specs = SerialSpecs {
+                mtu,
+                linelength,
+                max_payload,
+                ..test_specs()
+            };
+            for off in [0, 0x1234, 0x123456] {
+                let req = |data: Vec<u8>| ImageUploadReq {
+                    image_num: 1,
+                    off,
+                    len: None,
+                    data_sha: None,
+                    upgrade: None,
+                    data,
+                };
+                let encoded_len = |len: usize| {
+                    let body = serde_cbor::to_vec(&req(vec![0x55; len])).unwrap();
+                    (body.len(), framed_len(linelength, body.len()))
+                };
+                let empty_len = serde_cbor::to_vec(&req(Vec::new())).unwrap().len();
+                let len = max_chunk_len(&specs, empty_len);
+
+                // the largest chunk which fits
+                let (body_len, frame_len) = encoded_len(len);
+                assert!(frame_len <= mtu);
+                assert!(body_len <= max_payload.unwrap_or(usize::MAX));
+                let (body_len, frame_len) = encoded_len(len + 1);
+                assert!(frame_len > mtu || max_payload.is_some_and(|max| body_len > max));
+            }
+        }
+
+        let specs = SerialSpecs {
+            mtu: 30,
+            ..test_specs()
+        };
+        assert_eq!(max_chunk_len(&specs, 20), 0);
+
+        // a raw packet is the header and the body
+        let specs = SerialSpecs {
+            mtu: 512,
+            framing: Framing::Raw,
+            ..test_specs()
+        };
+        assert_eq!(max_chunk_len(&specs, 20), 512 - 8 - 20 - 2);
+    }
+
+    #[test]
+    fn test_max_chunk_len_tight_mtu() {
+        // MTUs just above the smallest request, where the header, checksum and length prefix
+        // are most of the frame
+        for linelength in [16, 128] {
+            for mtu in 30..120 {
+                let specs = SerialSpecs {
+                    mtu,
+                    linelength,
+                    ..test_specs()
+                };
+                let req = |data: Vec<u8>| ImageUploadReq {
+                    image_num: 0,
+                    off: 0x1000,
+                    len: None,
+                    data_sha: None,
+                    upgrade: None,
+                    data,
+                };
+                let frame_len = |len: usize| {
+                    let body = serde_cbor::to_vec(&req(vec![0xaa; len])).unwrap();
+                    let (data, _) = encode_request(
+                        linelength,
+                        specs.crc,
+                        NmpOp::Write,
+                        NmpGroup::Image,
+                        NmpIdImage::Upload,
+                        &body,
+                        0,
+                    )
+                    .unwrap();
+                    data.len()
+                };
+                let empty_len = serde_cbor::to_vec(&req(Vec::new())).unwrap().len();
+                let len = max_chunk_len(&specs, empty_len);
+                if len == 0 {
+                    assert!(frame_len(1) > mtu, "mtu {} linelength {}", mtu, linelength);
+                } else {
+                    assert!(
+                        frame_len(len) <= mtu,
+                        "mtu {} linelength {}",
+                        mtu,
+                        linelength
+                    );
+                    assert!(
+                        frame_len(len + 1) > mtu,
+                        "mtu {} linelength {}",
+                        mtu,
+                        linelength
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_upload_slots() {
+        let slot = |slot, upload_image_id| SlotInfoSlot {
+            slot,
+            size: 0x60000,
+            upload_image_id,
+        };
+        let info = SlotInfoRsp {
+            images: vec![SlotInfoImage {
+                image: 0,
+                slots: vec![slot(0, None), slot(1, Some(1))],
+                max_image_size: None,
+            }],
+        };
+        check_upload_slots_info(&info, &[(1, 1000)]).unwrap();
+        let e = check_upload_slots_info(&info, &[(1, 1000), (5, 1000)]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "slot 5 doesn't exist on the device, valid slots: 1, or use --no-slot-check"
+        );
+
+        // without upload ids, any slot is accepted
+        let info = SlotInfoRsp {
+            images: vec![SlotInfoImage {
+                image: 0,
+                slots: vec![slot(0, None), slot(1, None)],
+                max_image_size: None,
+            }],
+        };
+        check_upload_slots_info(&info, &[(5, 1000)]).unwrap();
+    }
+
+    #[test]
+    fn test_check_upload_slot_capacity() {
+        let slots = vec![
+            SlotInfoSlot {
+                slot: 0,
+                size: 0x60000,
+                upload_image_id: None,
+            },
+            SlotInfoSlot {
+                slot: 1,
+                size: 0x60000,
+                upload_image_id: Some(1),
+            },
+        ];
+        let mut info = SlotInfoRsp {
+            images: vec![SlotInfoImage {
+                image: 0,
+                slots,
+                max_image_size: None,
+            }],
+        };
+        check_upload_slots_info(&info, &[(1, 0x60000)]).unwrap();
+        let e = check_upload_slots_info(&info, &[(1, 0x60001)]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "image (393217 bytes) exceeds slot capacity (393216 bytes), or use --no-slot-check"
+        );
+
+        // the maximum image size of the firmware is smaller than the slot
+        info.images[0].max_image_size = Some(0x5f000);
+        check_upload_slots_info(&info, &[(1, 0x5f000)]).unwrap();
+        let e = check_upload_slots_info(&info, &[(1, 0x5f001)]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "image (389121 bytes) exceeds slot capacity (389120 bytes), or use --no-slot-check"
+        );
+    }
+
+    #[test]
+    fn test_parse_hash() {
+        let hash: Vec<u8> = (0..32).collect();
+        let hex = hex::encode(&hash);
+        assert_eq!(parse_hash(&hex).unwrap(), hash);
+        assert_eq!(parse_hash(&format!("0x{}", hex)).unwrap(), hash);
+        assert_eq!(
+            parse_hash(&format!(" {} {}\n", &hex[..32], &hex[32..])).unwrap(),
+            hash
+        );
+        let list: Vec<String> = hash.iter().map(|byte| byte.to_string()).collect();
+        assert_eq!(parse_hash(&format!("[{}]", list.join(", "))).unwrap(), hash);
+        assert_eq!(parse_hash(&list.join(",")).unwrap(), hash);
+        let base64 = general_purpose::STANDARD.encode(&hash);
+        assert_eq!(parse_hash(&base64).unwrap(), hash);
+
+        assert!(parse_hash("0x61dd")
+            .unwrap_err()
+            .to_string()
+            .contains("got 2 bytes"));
+        assert!(parse_hash("[1, 2, 300]").is_err());
+        assert!(parse_hash("not a hash!")
+            .unwrap_err()
+            .to_string()
+            .contains("expected hex"));
+    }
+
+    #[test]
+    fn test_swap_type() {
+        let mut images = vec![image_entry(0, true, true), image_entry(1, false, false)];
+        assert_eq!(swap_type(&images), SwapType::None);
+
+        images[1].pending = true;
+        assert_eq!(swap_type(&images), SwapType::Test);
+
+        images[1].permanent = true;
+        assert_eq!(swap_type(&images), SwapType::Permanent);
+
+        // booted the test image, which isn't confirmed yet
+        let images = vec![image_entry(0, true, false), image_entry(1, false, true)];
+        assert_eq!(swap_type(&images), SwapType::Revert);
+    }
+
+    #[test]
+    fn test_erase_ignore_rc() {
+        let mut port = TestSerialPort::new();
+        port.erase_rc = NmpErr::EBadState as u32;
+        assert!(erase_with_port(&test_specs(), &mut port, Some(1), &[]).is_err());
+
+        let mut port = TestSerialPort::new();
+        port.erase_rc = NmpErr::EBadState as u32;
+        let ans = erase_with_port(
+            &test_specs(),
+            &mut port,
+            Some(1),
+            &[NmpErr::EBadState as u32],
+        )
+        .unwrap();
+        assert_eq!(ans.rc, Some(NmpErr::EBadState as u32));
+    }
+
+    #[test]
+    fn test_upload_retries_after_timeouts() {
+        let specs = test_specs();
+        let mut port = TestSerialPort::new();
+        port.skip_responses = 2;
+        let data = vec![0x55u8; 2000];
+        let mut last_offset = 0;
+        upload_image(
+            &specs,
+            &mut port,
+            &data,
+            1,
+            Some(|offset, _total| last_offset = offset),
+            None,
+        )
+        .unwrap();
+        assert_eq!(last_offset, data.len() as u64);
+    }
+
+    #[test]
+    fn test_upload_backs_off_between_retries() {
+        // the test port times out right away, so the time is spent in the backoff
+        let specs = SerialSpecs {
+            retry_base_delay_ms: 20,
+            retry_max_delay_ms: 30,
+            ..test_specs()
+        };
+        let expected: Duration = (0..3).map(|attempt| specs.retry_delay(attempt)).sum();
+        assert_eq!(expected, Duration::from_millis(20 + 30 + 30));
+        let mut port = TestSerialPort::new();
+        port.skip_responses = 3;
+        let data = vec![0x55u8; 100];
+        let start = Instant::now();
+        upload_image(&specs, &mut port, &data, 1, None::<fn(u64, u64)>, None).unwrap();
+        assert!(start.elapsed() >= expected);
+    }
+
+    #[test]
+    fn test_upload_fails_when_retries_exhausted() {
+        let specs = SerialSpecs {
+            nb_retry: 1,
+            ..test_specs()
+        };
+        let mut port = TestSerialPort::new();
+        port.skip_responses = 2;
+        let data = vec![0x55u8; 100];
+        let e = upload_image(&specs, &mut port, &data, 1, None::<fn(u64, u64)>, None).unwrap_err();
+        assert!(is_timeout(&e));
+        assert!(e
+            .to_string()
+            .starts_with("no answer from device (initial=1m, after 1 retries at off=0)"));
+    }
+
+    #[test]
+    fn test_upload_erase_timeout() {
+        // the first answer arrives after the initial timeout, while the device erases
+        let data = vec![0x55u8; 2000];
+        let mut port = TestSerialPort::new();
+        port.erase_time = Duration::from_secs(90);
+        let e = upload_image(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            None::<fn(u64, u64)>,
+            None,
+        )
+        .unwrap_err();
+        assert!(is_timeout(&e));
+
+        // the retries of the first chunk wait longer
+        let specs = SerialSpecs {
+            erase_timeout_s: Some(120),
+            ..test_specs()
+        };
+        let mut port = TestSerialPort::new();
+        port.erase_time = Duration::from_secs(90);
+        upload_image(&specs, &mut port, &data, 1, None::<fn(u64, u64)>, None).unwrap();
+        assert_eq!(port.uploaded, data);
+    }
+
+    #[test]
+    fn test_upload_without_sha() {
+        let data = vec![0x55u8; 100];
+        let mut port = TestSerialPort::new();
+        port.reject_sha = true;
+        assert!(upload_image(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            None::<fn(u64, u64)>,
+            None
+        )
+        .is_err());
+
+        let specs = SerialSpecs {
+            no_sha: true,
+            ..test_specs()
+        };
+        let mut port = TestSerialPort::new();
+        port.reject_sha = true;
+        upload_image(&specs, &mut port, &data, 1, None::<fn(u64, u64)>, None).unwrap();
+    }
+
+    #[test]
+    fn test_upload_len_every_chunk() {
+        let data = vec![0x55u8; 2000];
+        let mut port = TestSerialPort::new();
+        port.require_len = true;
+        let e = upload_image(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            None::<fn(u64, u64)>,
+            None,
+        )
+        .unwrap_err();
+        assert!(e.to_string().starts_with("rc = 3"));
+
+        let specs = SerialSpecs {
+            len_every_chunk: true,
+            ..test_specs()
+        };
+        let mut port = TestSerialPort::new();
+        port.require_len = true;
+        upload_image(&specs, &mut port, &data, 1, None::<fn(u64, u64)>, None).unwrap();
+        assert_eq!(port.uploaded, data);
+    }
+
+    #[test]
+    fn test_upload_cancel() {
+        let cancel = AtomicBool::new(false);
+        let mut port = TestSerialPort::new();
+        let data = vec![0x55u8; 2000];
+        let e = upload_image(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            Some(|_offset, _total| cancel.store(true, Ordering::SeqCst)),
+            Some(&cancel),
+        )
+        .unwrap_err();
+        assert!(e.downcast_ref::<Cancelled>().is_some());
+    }
+
+    #[test]
+    fn test_upload_advanced_offset() {
+        let mut port = TestSerialPort::new();
+        port.extra_offset = 1000;
+        let data = vec![0x55u8; 2000];
+        let mut offsets = Vec::new();
+        upload_image(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            Some(|offset, _total| offsets.push(offset)),
+            None,
+        )
+        .unwrap();
+        assert_eq!(offsets.last(), Some(&(data.len() as u64)));
+        assert!(offsets.len() < 4);
+    }
+
+    #[test]
+    fn test_upload_raw_address() {
+        let mut port = TestSerialPort::new();
+        let data: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+        let mut offsets = Vec::new();
+        upload_data(
+            &test_specs(),
+            &mut port,
+            &data,
+            0,
+            0x10000,
+            0,
+            Some(|offset, _total| offsets.push(offset)),
+            None,
+        )
+        .unwrap();
+        assert_eq!(offsets.last(), Some(&(data.len() as u64)));
+        assert_eq!(port.uploaded, data);
+
+        // the end of the data must fit in the 32 bit offsets
+        let mut port = TestSerialPort::new();
+        let e = upload_data(
+            &test_specs(),
+            &mut port,
+            &data,
+            0,
+            u32::MAX - 1000,
+            0,
+            None::<fn(u64, u64)>,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "2000 bytes at offset 0xfffffc17 are beyond the 32 bit offsets of the upload"
+        );
+        assert!(port.uploaded.is_empty());
+    }
+
+    #[test]
+    fn test_upload_recovering() {
+        let data: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        take_requests();
+
+        // the device disconnects after the second chunk
+        let mut port = TestSerialPort::new();
+        port.disconnect_after = Some(2);
+        let mut offsets = Vec::new();
+        upload_recovering(
+            &test_specs(),
+            Box::new(port),
+            &data,
+            1,
+            0,
+            Some(|offset, _total| offsets.push(offset)),
+            None,
+        )
+        .unwrap();
+        assert_eq!(*offsets.last().unwrap(), data.len() as u64);
+
+        // the upload continues at the confirmed offset of the second chunk, not at 0
+        let upload_offsets: Vec<u64> = take_requests()
+            .into_iter()
+            .filter(|(header, _)| header.id == NmpIdImage::Upload as u8)
+            .map(|(_, body)| {
+                let request: ImageUploadReq = serde_cbor::value::from_value(body).unwrap();
+                request.off as u64
+            })
+            .collect();
+        assert_eq!(upload_offsets[0], 0);
+        assert_eq!(upload_offsets[1], offsets[0]);
+        assert_eq!(upload_offsets[2], offsets[1]);
+        assert!(offsets[1] > 0);
+
+        // without reconnects left, the error is returned
+        let mut port = TestSerialPort::new();
+        port.disconnect_after = Some(1);
+        let specs = SerialSpecs {
+            nb_retry: 0,
+            ..test_specs()
+        };
+        let e = upload_recovering(
+            &specs,
+            Box::new(port),
+            &data,
+            1,
+            0,
+            None::<fn(u64, u64)>,
+            None,
+        )
+        .unwrap_err();
+        assert!(is_disconnected(&e));
+    }
+
+    #[test]
+    fn test_upload_resume() {
+        let data: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+
+        // interrupt the upload after the first chunk, then continue on the same device
+        let mut port = TestSerialPort::new();
+        let cancel = AtomicBool::new(false);
+        let mut offsets = Vec::new();
+        upload_image(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            Some(|offset, _total| {
+                offsets.push(offset);
+                cancel.store(true, Ordering::SeqCst)
+            }),
+            Some(&cancel),
+        )
+        .unwrap_err();
+        let start = offsets[0] as usize;
+        let mut offsets = Vec::new();
+        let ans = upload_data(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            0,
+            start,
+            Some(|offset, _total| offsets.push(offset)),
+            None,
+        )
+        .unwrap();
+        assert_eq!(port.uploaded, data);
+        assert!(offsets[0] > start as u64);
+        assert_eq!(ans.stats.bytes, data.len() as u64);
+
+        // a device which lost the upload state, like after a reset, continues at 0
+        let mut port = TestSerialPort::new();
+        let mut offsets = Vec::new();
+        upload_data(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            0,
+            start,
+            Some(|offset, _total| offsets.push(offset)),
+            None,
+        )
+        .unwrap();
+        assert_eq!(offsets[0], 0);
+        assert_eq!(port.uploaded, data);
+    }
+
+    #[test]
+    fn test_upload_scenario() {
+        let scenario = TestScenario::new(
+            r#"[
+                {"group": 1, "id": 1, "response": {"rc": 0, "off": 100}},
+                {"group": 1, "id": 1, "response": {"rc": 3}}
+            ]"#,
+        );
+        let mut port = TestSerialPort::from_scenario(&scenario.path).unwrap();
+
+        let data = vec![0x55u8; 2000];
+        let mut offsets = Vec::new();
+        let e = upload_image(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            Some(|offset, _total| offsets.push(offset)),
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(offsets, vec![100]);
+        assert_eq!(e.to_string(), r#"rc = 3, response: {"rc":3}"#);
+    }
+
+    #[test]
+    fn test_invalid_scenario_hex() {
+        let scenario = TestScenario::new(r#"[{"group": 1, "id": 1, "response_hex": "a1x2"}]"#);
+        let e = TestSerialPort::from_scenario(&scenario.path).err().unwrap();
+        assert!(format!("{:#}", e).contains("invalid response_hex a1x2"));
+    }
+
+    #[test]
+    fn test_upload_empty() {
+        let mut port = TestSerialPort::new();
+        let mut offsets = Vec::new();
+        let ans = upload_image(
+            &test_specs(),
+            &mut port,
+            &[],
+            1,
+            Some(|offset, _total| offsets.push(offset)),
+            None,
+        )
+        .unwrap();
+        assert_eq!(offsets, vec![0]);
+        assert!(port.uploaded.is_empty());
+        assert_eq!(ans.stats.bytes, 0);
+        assert_eq!(ans.stats.sent_blocks, 1);
+        assert_eq!(ans.stats.packet_loss(), 0);
+        assert_eq!(ans.stats.bytes_per_sec, 0.0);
+    }
+
+    #[test]
+    fn test_upload_chunk_boundaries() {
+        let upload = |len: usize| {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut port = TestSerialPort::new();
+            let mut offsets = Vec::new();
+            let ans = upload_image(
+                &test_specs(),
+                &mut port,
+                &data,
+                1,
+                Some(|offset, _total| offsets.push(offset)),
+                None,
+            )
+            .unwrap();
+            assert_eq!(port.uploaded, data);
+            assert_eq!(offsets.last(), Some(&(len as u64)));
+            assert_eq!(ans.stats.packet_loss(), 0);
+            (offsets, ans.stats.sent_blocks)
+        };
+
+        // a single byte
+        assert_eq!(upload(1), (vec![1], 1));
+
+        // the first chunk of a larger file is the most which fits in one request
+        let (offsets, _) = upload(2000);
+        let chunk_len = offsets[0] as usize;
+        assert!(chunk_len > 1 && chunk_len < 2000);
+
+        // exactly one chunk, and one byte more
+        assert_eq!(upload(chunk_len), (vec![chunk_len as u64], 1));
+        assert_eq!(
+            upload(chunk_len + 1),
+            (vec![chunk_len as u64, chunk_len as u64 + 1], 2)
+        );
+    }
+
+    #[test]
+    fn test_upload_limits() {
+        let data = vec![0x55u8; 2000];
+        let upload = |specs: &SerialSpecs| {
+            let mut port = TestSerialPort::new();
+            upload_image(specs, &mut port, &data, 1, None::<fn(u64, u64)>, None)
+        };
+
+        // resent chunks count as well
+        let specs = SerialSpecs {
+            max_upload_bytes: Some(1000),
+            ..test_specs()
+        };
+        let e = upload(&specs).unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("upload aborted after sending more than 1000 bytes"));
+        let specs = SerialSpecs {
+            max_upload_bytes: Some(2000),
+            ..test_specs()
+        };
+        upload(&specs).unwrap();
+
+        // the test port simulates the transfer time
+        let specs = SerialSpecs {
+            max_upload_duration: Some(Duration::from_millis(1)),
+            ..test_specs()
+        };
+        let e = upload(&specs).unwrap_err();
+        assert!(e.to_string().starts_with("upload aborted after more than"));
+    }
+
+    #[test]
+    fn test_upload_timing() {
+        let mut port = TestSerialPort::new();
+        let data = vec![0x55u8; 2000];
+        let ans = upload_image(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            None::<fn(u64, u64)>,
+            None,
+        )
+        .unwrap();
+        let timing = &ans.stats.timing;
+        // the test port simulates the transfer time when writing
+        assert!(!timing.write.is_zero());
+        assert!(!timing.decode.is_zero());
+        assert!(timing.encode + timing.write + timing.wait + timing.decode <= ans.stats.elapsed);
+    }
+
+    // an image without code, which depends on version 1.0.0 of the image
+    fn dependent_image(dependency: u8) -> Vec<u8> {
+        let mut image = vec![0; 0x20];
+        LittleEndian::write_u32(&mut image[0..], 0x96f3b83d);
+        LittleEndian::write_u16(&mut image[8..], 0x20);
+        LittleEndian::write_u16(&mut image[10..], 20);
+        image.extend_from_slice(&[0x08, 0x69, 20, 0, 0x40, 0, 12, 0]);
+        image.extend_from_slice(&[dependency, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        image.extend_from_slice(&[0x07, 0x69, 4, 0]);
+        image
+    }
+
+    #[test]
+    fn test_check_image_number() {
+        let image = dependent_image(0);
+        assert!(check_image_number(&image, 0)
+            .unwrap()
+            .starts_with("the file depends on image 0 version >= 1.0.0"));
+        assert_eq!(check_image_number(&image, 1), None);
+        assert_eq!(check_image_number(&[0x55; 100], 0), None);
+        assert_eq!(image_number_hint(&image), Some(1));
+        assert_eq!(image_number_hint(&dependent_image(1)), Some(0));
+        assert_eq!(image_number_hint(&[0x55; 100]), None);
+    }
+
+    #[test]
+    fn test_upload_image_hash() {
+        // the hash of an MCUboot image, to mark it as pending after the upload
+        let image = dependent_image(1);
+        let mut port = TestSerialPort::new();
+        let ans = upload_image(
+            &test_specs(),
+            &mut port,
+            &image,
+            1,
+            None::<fn(u64, u64)>,
+            None,
+        )
+        .unwrap();
+        assert_eq!(ans.image_hash, Some(image_hash(&image).unwrap()));
+
+        // none for other files
+        let mut port = TestSerialPort::new();
+        let ans = upload_image(
+            &test_specs(),
+            &mut port,
+            &[0x55; 100],
+            1,
+            None::<fn(u64, u64)>,
+            None,
+        )
+        .unwrap();
+        assert_eq!(ans.image_hash, None);
+    }
+
+    #[test]
+    fn test_upload_slot_for_file() {
+        // an application for image 0 which depends on the network core image 1, uploaded to
+        // the default slot 1, the upload slot of image 0
+        let app = dependent_image(1);
+        assert_eq!(upload_slot_for_file(&app, 1, false, false), (1, None));
+
+        // a network core image uploaded to slot 1 of image 0
+        let net = dependent_image(0);
+        let (slot, warning) = upload_slot_for_file(&net, 1, false, false);
+        assert_eq!(slot, 1);
+        assert!(warning
+            .unwrap()
+            .starts_with("the file depends on image 0 version >= 1.0.0"));
+        assert_eq!(upload_slot_for_file(&net, 3, false, false), (3, None));
+
+        // the slot from the file name is mentioned
+        let (_, warning) = upload_slot_for_file(&net, 1, true, false);
+        assert!(warning.unwrap().ends_with(", slot 1 is from the file name"));
+
+        // --auto-image uploads it to the slot of image 1
+        assert_eq!(upload_slot_for_file(&net, 1, true, true), (3, None));
+        assert_eq!(upload_image_for_file(&net, 0, true), (1, None));
+        assert!(upload_image_for_file(&net, 0, false).1.is_some());
+        assert_eq!(upload_image_for_file(&app, 0, false), (0, None));
+    }
+
+    #[test]
+    fn test_upload_all() {
+        let filename = temp_file("upload-all.bin");
+        std::fs::write(&filename, vec![0x55u8; 2000]).unwrap();
+        let devices = vec![test_specs(), test_specs(), test_specs()];
+        let last_offsets = Mutex::new(vec![0; devices.len()]);
+        let results = upload_all(
+            devices,
+            &filename,
+            1,
+            Some(|index: usize, offset, _total| last_offsets.lock().unwrap()[index] = offset),
+        );
+        std::fs::remove_file(&filename).unwrap();
+        assert_eq!(results.len(), 3);
+        for result in results {
+            assert_eq!(result.unwrap().stats.bytes, 2000);
+        }
+        assert_eq!(*last_offsets.lock().unwrap(), vec![2000; 3]);
+    }
+
+    fn temp_file(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "mcumgr-client-test-{}-{}",
+            std::process::id(),
+            name
+        ))
+    }
+
+    // the slots of the erase requests received by the test device
+    fn erased_slots() -> Vec<Option<u64>> {
+        take_requests()
+            .into_iter()
+            .filter(|(header, _)| {
+                header.group == NmpGroup::Image && header.id == NmpIdImage::Erase as u8
+            })
+            .map(|(_, body)| match body {
+                serde_cbor::Value::Map(map) => {
+                    match map.get(&serde_cbor::Value::Text("slot".to_string())) {
+                        Some(serde_cbor::Value::Integer(slot)) => Some(*slot as u64),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_upload_reset_upload_state() {
+        let image_file = temp_file("reset-state-slot3.bin");
+        std::fs::write(&image_file, vec![0x55u8; 100]).unwrap();
+        let specs = SerialSpecs {
+            no_slot_check: true,
+            ..test_specs()
+        };
+
+        // without the option, nothing is erased
+        upload(&specs, &image_file, 1, None::<fn(u64, u64)>, None).unwrap();
+        assert!(erased_slots().is_empty());
+
+        // the slot from the file name is erased, not the default slot
+        let specs = SerialSpecs {
+            reset_upload_state: true,
+            ..specs
+        };
+        upload(&specs, &image_file, 1, None::<fn(u64, u64)>, None).unwrap();
+        assert_eq!(erased_slots(), vec![Some(3)]);
+
+        // each image of a multi image upload
+        let images = vec![(image_file.clone(), 1), (image_file.clone(), 3)];
+        upload_multi(&specs, &images, None::<fn(u64, u64)>, None).unwrap();
+        assert_eq!(erased_slots(), vec![Some(1), Some(3)]);
+        std::fs::remove_file(&image_file).unwrap();
+    }
+
+    #[test]
+    fn test_upload_state_file() {
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+        let image_file = temp_file("state.bin");
+        let state_file = temp_file("state.json");
+        std::fs::write(&image_file, &data).unwrap();
+        let _ = std::fs::remove_file(&state_file);
+
+        // interrupted after the first chunk, which leaves its offset in the state file
+        let cancel = AtomicBool::new(false);
+        let e = upload_resumable(
+            &test_specs(),
+            &image_file,
+            1,
+            &state_file,
+            Some(|offset, _total| {
+                if offset > 0 {
+                    cancel.store(true, Ordering::SeqCst);
+                }
+            }),
+            Some(&cancel),
+        )
+        .unwrap_err();
+        assert!(e.is::<Cancelled>());
+        let file_hash = hex::encode(Sha256::digest(&data));
+        let offset = read_upload_state(&state_file, &file_hash, data.len());
+        assert!(offset > 0 && offset < data.len());
+        assert_eq!(read_upload_state(&state_file, "other", data.len()), 0);
+
+        // the new test device has no upload state and continues at 0
+        let mut last_offset = 0;
+        upload_resumable(
+            &test_specs(),
+            &image_file,
+            1,
+            &state_file,
+            Some(|offset, _total| last_offset = offset),
+            None,
+        )
+        .unwrap();
+        assert_eq!(last_offset, data.len() as u64);
+        assert!(!state_file.exists());
+        std::fs::remove_file(&image_file).unwrap();
+    }
+
+    #[test]
+    fn test_upload_resumes_at_state_offset() {
+        let data = vec![0x55u8; 3000];
+        let state_file = temp_file("resume.json");
+        let file_hash = hex::encode(Sha256::digest(&data));
+        write_upload_state(&state_file, &file_hash, 1000).unwrap();
+        let start = read_upload_state(&state_file, &file_hash, data.len());
+        assert_eq!(start, 1000);
+        std::fs::remove_file(&state_file).unwrap();
+
+        // a device which still has the upload state continues at the offset
+        let mut port = TestSerialPort::new();
+        upload_data(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            0,
+            0,
+            None::<fn(u64, u64)>,
+            None,
+        )
+        .unwrap();
+        let mut offsets = Vec::new();
+        upload_data(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            0,
+            start,
+            Some(|offset, _total| offsets.push(offset)),
+            None,
+        )
+        .unwrap();
+        assert!(offsets[0] > 1000);
+        assert_eq!(*offsets.last().unwrap(), data.len() as u64);
+    }
+
+    #[test]
+    fn test_upload_verifies_hash() {
+        let data = vec![0x55u8; 1000];
+        let mut port = TestSerialPort::new();
+        port.report_sha = true;
+        let ans = upload_image(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            None::<fn(u64, u64)>,
+            None,
+        )
+        .unwrap();
+        assert!(ans.response.sha.is_some());
+
+        let mut port = TestSerialPort::new();
+        port.report_sha = true;
+        port.corrupt_upload = true;
+        let e = upload_image(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            None::<fn(u64, u64)>,
+            None,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("does not match"));
+    }
+
+    #[test]
+    fn test_upload_match() {
+        let data = vec![0x55u8; 1000];
+        let mut port = TestSerialPort::new();
+        port.report_match = true;
+        let ans = upload_image(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            None::<fn(u64, u64)>,
+            None,
+        )
+        .unwrap();
+        assert_eq!(ans.response.matched, Some(true));
+
+        let mut port = TestSerialPort::new();
+        port.report_match = true;
+        port.corrupt_upload = true;
+        let e = upload_image(
+            &test_specs(),
+            &mut port,
+            &data,
+            1,
+            None::<fn(u64, u64)>,
+            None,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("does not match"));
+    }
+
+    #[test]
+    fn test_upload_gzip_compressed() {
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+        let dir = std::env::temp_dir();
+        let raw_file = dir.join(format!("mcumgr-client-test-{}.bin", std::process::id()));
+        let gz_file = dir.join(format!("mcumgr-client-test-{}.bin.gz", std::process::id()));
+        std::fs::write(&raw_file, &data).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        std::fs::write(&gz_file, encoder.finish().unwrap()).unwrap();
+
+        let mut uploads = Vec::new();
+        for file in [&raw_file, &gz_file] {
+            let image = load_image(&test_specs(), file).unwrap();
+            let mut port = TestSerialPort::new();
+            upload_image(
+                &test_specs(),
+                &mut port,
+                &image,
+                1,
+                None::<fn(u64, u64)>,
+                None,
+            )
+            .unwrap();
+            uploads.push(port.uploaded);
+        }
+        let specs = SerialSpecs {
+            no_decompress: true,
+            ..test_specs()
+        };
+        let compressed = load_image(&specs, &gz_file).unwrap();
+        std::fs::remove_file(&raw_file).unwrap();
+        std::fs::remove_file(&gz_file).unwrap();
+
+        assert_eq!(uploads[0], data);
+        assert_eq!(uploads[1], data);
+        assert_ne!(compressed, data);
+    }
 }
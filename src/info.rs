@@ -0,0 +1,63 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Error, Result};
+use serde::Serialize;
+
+use crate::default::{app_info, params};
+use crate::image::list;
+use crate::nmp_hdr::{ImageStateRsp, McumgrParamsRsp, NmpErr};
+use crate::transfer::{close_kept_port, device_rc, SerialSpecs};
+
+// everything about a device, each part None if the firmware doesn't support its request
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    pub images: Option<ImageStateRsp>,
+    pub params: Option<McumgrParamsRsp>,
+    pub app_info: Option<String>,
+}
+
+// None if the firmware doesn't support the request
+fn supported<T>(result: Result<T, Error>) -> Result<Option<T>, Error> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if device_rc(&e) == Some(NmpErr::ENotSup as u32) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// the image list, MCUmgr parameters and application info, with one connection
+pub fn device_info(specs: &SerialSpecs) -> Result<DeviceInfo, Error> {
+    let session = SerialSpecs {
+        keep_open: true,
+        ..specs.clone()
+    };
+    let info = (|| {
+        Ok(DeviceInfo {
+            images: supported(list(&session))?,
+            params: supported(params(&session))?,
+            app_info: supported(app_info(&session, Some("a".to_string())))?,
+        })
+    })();
+    if !specs.keep_open {
+        close_kept_port();
+    }
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::device_info;
+    use crate::transfer::SerialSpecs;
+
+    #[test]
+    fn test_device_info() {
+        let specs = SerialSpecs {
+            device: "test".to_string(),
+            ..Default::default()
+        };
+        let info = device_info(&specs).unwrap();
+        assert_eq!(info.images.unwrap().images.len(), 1);
+        assert_eq!(info.params.unwrap().buf_size, 2048);
+        assert!(info.app_info.unwrap().starts_with("Zephyr"));
+    }
+}
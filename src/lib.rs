@@ -1,9 +1,48 @@
+mod buffered_port;
+mod config;
+mod crash;
 mod default;
+mod diagnose;
+mod events;
+mod fs;
+mod health;
 mod image;
-mod nmp_hdr;
-mod transfer;
+mod info;
+mod logs;
+pub mod mcuboot;
+pub mod nmp_hdr;
+mod raw_port;
+mod run;
+mod split;
 mod test_serial_port;
+mod transfer;
+mod transport_log;
 
-pub use crate::default::reset;
-pub use crate::image::{list, upload, test, erase};
-pub use crate::transfer::SerialSpecs;
\ No newline at end of file
+pub use crate::config::{
+    config_commit, config_load, config_read, config_save, config_write, format_value,
+};
+pub use crate::crash::crash_trigger;
+pub use crate::default::{
+    app_info, bootloader_info, bootloader_mode_name, console_echo, echo, params, probe_baudrate,
+    probe_mtu, reset, reset_to_bootloader, ResetOutcome,
+};
+pub use crate::diagnose::{diagnose, DiagnosticEntry, DiagnosticStatus, DiagnosticsBundle};
+pub use crate::events::{set_event_hook, ProtocolEvent};
+pub use crate::fs::fs_stat;
+pub use crate::health::{health, HealthCheck, HealthReport, HealthStatus};
+pub use crate::image::{
+    apply_state, confirm, erase, inactive_image_hash, list, parse_hash, raw_upload, revert,
+    slot_info, swap_type, test, upload, upload_all, upload_multi, upload_resumable, verify,
+    Cancelled, ImageStateManifestEntry, SwapType, UploadResult, UploadStats, UploadTiming,
+    VerifyResult,
+};
+pub use crate::info::{device_info, DeviceInfo};
+pub use crate::logs::{log_entry_text, log_level_list, log_module_list, log_show, log_watch};
+pub use crate::run::{run_list, run_test};
+pub use crate::split::{split_apply, split_status};
+pub use crate::transfer::{
+    close_kept_port, decode_frame, encode_raw_request, encode_request, resolve_device,
+    send_request, set_seq_id, with_reconnect, with_repeat, Crc, DeviceError, Framing, SerialSpecs,
+    MIN_LINE_LENGTH,
+};
+pub use crate::transport_log::{first_seq_id, read_transport_log, Direction, TransportLogEntry};
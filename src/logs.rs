@@ -0,0 +1,209 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Error, Result};
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::image::Cancelled;
+use crate::nmp_hdr::*;
+use crate::transfer::check_rc;
+use crate::transfer::send_request;
+use crate::transfer::SerialSpecs;
+
+// show the log entries starting at the index, of all logs, if no name is given
+pub fn log_show(
+    specs: &SerialSpecs,
+    log_name: Option<String>,
+    index: u32,
+) -> Result<LogShowRsp, Error> {
+    info!("log show request");
+    show(specs, log_name, index)
+}
+
+fn show(specs: &SerialSpecs, log_name: Option<String>, index: u32) -> Result<LogShowRsp, Error> {
+    let req = LogShowReq {
+        log_name,
+        timestamp: 0,
+        index,
+    };
+    let body = serde_cbor::to_vec(&req)?;
+    let response_body = send_request(specs, NmpOp::Read, NmpGroup::Log, NmpIdLog::Show, &body)?;
+    check_rc(&response_body)?;
+
+    let ans: LogShowRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+
+    Ok(ans)
+}
+
+// the log modules, sorted by number. MCUmgr can only list them, the levels can't be set with it.
+pub fn log_module_list(specs: &SerialSpecs) -> Result<Vec<(String, u32)>, Error> {
+    info!("log module list request");
+    let ans: LogModuleListRsp = list_request(specs, NmpIdLog::ModuleList)?;
+    Ok(sorted_by_number(ans.module_map))
+}
+
+// the log levels, sorted by number, to read the levels of the log entries
+pub fn log_level_list(specs: &SerialSpecs) -> Result<Vec<(String, u32)>, Error> {
+    info!("log level list request");
+    let ans: LogLevelListRsp = list_request(specs, NmpIdLog::LevelList)?;
+    Ok(sorted_by_number(ans.level_map))
+}
+
+fn list_request<T: serde::de::DeserializeOwned>(
+    specs: &SerialSpecs,
+    id: NmpIdLog,
+) -> Result<T, Error> {
+    let body = serde_cbor::to_vec(&BTreeMap::<String, String>::new())?;
+    let response_body = send_request(specs, NmpOp::Read, NmpGroup::Log, id, &body)?;
+    check_rc(&response_body)?;
+    serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))
+}
+
+fn sorted_by_number(map: BTreeMap<String, u32>) -> Vec<(String, u32)> {
+    let mut list: Vec<(String, u32)> = map.into_iter().collect();
+    list.sort_by_key(|(name, number)| (*number, name.clone()));
+    list
+}
+
+pub fn log_entry_text(entry: &LogEntry) -> String {
+    match &entry.msg {
+        serde_cbor::Value::Text(text) => text.clone(),
+        serde_cbor::Value::Bytes(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        msg => format!("{:?}", msg),
+    }
+}
+
+// the entries of the answer which are new since the cursor, and the next cursor. When the
+// next index of the device is lower than the cursor, the device was reset or its log was
+// cleared, then all entries are new.
+fn new_entries(ans: LogShowRsp, cursor: u32) -> (Vec<LogEntry>, u32) {
+    let cursor = if ans.next_index < cursor {
+        debug!(
+            "log index went back from {} to {}, starting again",
+            cursor, ans.next_index
+        );
+        0
+    } else {
+        cursor
+    };
+    let mut entries: Vec<LogEntry> = ans
+        .logs
+        .into_iter()
+        .flat_map(|log| log.entries)
+        .filter(|entry| entry.index >= cursor)
+        .collect();
+    entries.sort_by_key(|entry| entry.index);
+    let next = entries
+        .last()
+        .map(|entry| entry.index + 1)
+        .unwrap_or(cursor)
+        .max(ans.next_index);
+    (entries, next)
+}
+
+// poll the log like tail -f, and call on_entry for each new entry, until cancelled
+pub fn log_watch<F>(
+    specs: &SerialSpecs,
+    log_name: Option<String>,
+    interval: Duration,
+    mut on_entry: F,
+    cancel: Option<&AtomicBool>,
+) -> Result<(), Error>
+where
+    F: FnMut(&LogEntry),
+{
+    info!("watching the log, stop with Ctrl-C");
+    let mut cursor = 0;
+    loop {
+        if let Some(cancel) = cancel {
+            if cancel.load(Ordering::SeqCst) {
+                return Err(Cancelled.into());
+            }
+        }
+
+        let (entries, next) = new_entries(show(specs, log_name.clone(), cursor)?, cursor);
+        for entry in &entries {
+            on_entry(entry);
+        }
+        cursor = next;
+
+        thread::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{log_level_list, log_module_list, new_entries};
+    use crate::nmp_hdr::*;
+    use crate::transfer::SerialSpecs;
+
+    fn answer(indexes: &[u32], next_index: u32) -> LogShowRsp {
+        LogShowRsp {
+            next_index,
+            logs: vec![LogShowLog {
+                name: "log".to_string(),
+                entries: indexes
+                    .iter()
+                    .map(|index| LogEntry {
+                        msg: serde_cbor::Value::Text(format!("entry {}", index)),
+                        timestamp: 0,
+                        level: 0,
+                        index: *index,
+                        module: 0,
+                    })
+                    .collect(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_new_log_entries() {
+        let (entries, cursor) = new_entries(answer(&[0, 1, 2], 3), 0);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(cursor, 3);
+
+        // already seen entries are skipped
+        let (entries, cursor) = new_entries(answer(&[2, 3, 4], 5), cursor);
+        assert_eq!(
+            entries.iter().map(|e| e.index).collect::<Vec<u32>>(),
+            vec![3, 4]
+        );
+        assert_eq!(cursor, 5);
+
+        // no new entries
+        let (entries, cursor) = new_entries(answer(&[], 5), cursor);
+        assert!(entries.is_empty());
+        assert_eq!(cursor, 5);
+
+        // the device was reset, its index starts again
+        let (entries, cursor) = new_entries(answer(&[0, 1], 2), cursor);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(cursor, 2);
+    }
+
+    #[test]
+    fn test_log_lists() {
+        let specs = SerialSpecs {
+            device: "test".to_string(),
+            ..Default::default()
+        };
+        let modules = log_module_list(&specs).unwrap();
+        assert_eq!(
+            modules,
+            vec![
+                ("DEFAULT".to_string(), 0),
+                ("OS".to_string(), 1),
+                ("TEST".to_string(), 8)
+            ]
+        );
+        let levels = log_level_list(&specs).unwrap();
+        assert_eq!(levels.len(), 4);
+        assert_eq!(levels[0], ("DEBUG".to_string(), 0));
+        assert_eq!(levels[3], ("ERROR".to_string(), 3));
+    }
+}
@@ -1,21 +1,27 @@
 // Copyright © 2023-2024 Vouch.io LLC
 
-use anyhow::{Error, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Error, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 use indicatif::{ProgressBar, ProgressStyle};
-use log::{error, info, LevelFilter};
-use serialport::available_ports;
+use log::{debug, error, info, warn, LevelFilter};
+use serialport::{DataBits, FlowControl, Parity, StopBits};
 use simplelog::{ColorChoice, Config, SimpleLogger, TermLogger, TerminalMode};
 use std::env;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::str::FromStr;
+use std::time::Duration;
 
+use mcumgr_client::nmp_hdr::{
+    ImageStateEntry, ImageStateRsp, ImageStateWriteRsp, LogEntry, NmpGroup, NmpOp, RawId,
+};
 use mcumgr_client::*;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// device name
+    /// device name, "auto" to detect it, or usb:<serial number> for the port of a USB device
     #[arg(short, long, default_value = "")]
     device: String,
 
@@ -31,36 +37,529 @@ struct Cli {
     #[arg(short = 'u', long = "subsequent_timeout", default_value_t = 200)]
     subsequent_timeout_ms: u32,
 
+    /// timeout in seconds when the first upload chunk is sent again, for devices which take
+    /// longer to erase the slot than the initial timeout, default is the initial timeout
+    #[arg(long = "erase-timeout-s")]
+    erase_timeout_s: Option<u32>,
+
+    /// factor for all timeouts, like 10 for a slow radio link
+    #[arg(long, default_value_t = 1.0, value_parser = parse_timeout_scale)]
+    timeout_scale: f32,
+
     // number of retry per packet
     #[arg(long, default_value_t = 4)]
     nb_retry: u32,
 
-    /// maximum length per line
-    #[arg(short, long, default_value_t = 128)]
+    /// delay in msec before the first packet retry, doubled for each further retry
+    #[arg(long, default_value_t = 50)]
+    retry_base_delay_ms: u32,
+
+    /// maximum delay in msec between packet retries
+    #[arg(long, default_value_t = 2000)]
+    retry_max_delay_ms: u32,
+
+    /// random delay added to packet retries, as a fraction of the first retry delay, like 0.5
+    /// for flashing many devices in parallel
+    #[arg(long, default_value_t = 0.0)]
+    retry_jitter: f32,
+
+    /// maximum length per line, at least 8
+    #[arg(short, long, default_value_t = 128,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(MIN_LINE_LENGTH as u64..))]
     linelength: usize,
 
-    /// maximum length per request
-    #[arg(short, long, default_value_t = 512)]
-    mtu: usize,
+    /// maximum length per request as sent, with base64 encoding, line markers and newlines, or
+    /// the packet length with --framing raw, or "auto" for SMP packets as long as the buffer
+    /// of the device
+    #[arg(short, long, default_value = "512")]
+    mtu: Mtu,
+
+    /// maximum length of the SMP body per request, before the encoding, in addition to the MTU
+    #[arg(long)]
+    max_payload: Option<usize>,
+
+    /// baudrate, or "auto" to try common baudrates until the device answers
+    #[arg(short, long, default_value = "115200")]
+    baudrate: Baudrate,
+
+    /// number of times to wait for the device and run the command again, if it disconnects
+    #[arg(long, default_value_t = 0)]
+    reconnect: u32,
+
+    /// number of times to run list, reset and test again, if they fail
+    #[arg(long, default_value_t = 0)]
+    repeat_on_error: u32,
+
+    /// delay in msec before running a failed command again
+    #[arg(long, default_value_t = 500)]
+    repeat_delay_ms: u32,
+
+    /// don't send the SHA-256 of the image when uploading, for old MCUboot versions which reject it
+    #[arg(long)]
+    no_sha: bool,
+
+    /// upload .gz and .xz compressed files as they are, instead of decompressing them
+    #[arg(long)]
+    no_decompress: bool,
+
+    /// send the fields of upload requests in the canonical CBOR order, shorter names first,
+    /// for firmware which expects this order
+    #[arg(long)]
+    canonical_cbor: bool,
+
+    /// send the image length with every upload request, not only with the first, for firmware
+    /// which expects it in every chunk
+    #[arg(long)]
+    len_every_chunk: bool,
+
+    /// don't check with the slot info of the device if the upload slot exists and if the image
+    /// fits in it
+    #[arg(long)]
+    no_slot_check: bool,
+
+    /// upload a file which depends on the image of the upload slot to the other image, for
+    /// devices with two images, like the application and network core of the nRF5340
+    #[arg(long)]
+    auto_image: bool,
+
+    /// don't discard pending input before sending a request, for links with pipelined responses
+    #[arg(long)]
+    no_flush: bool,
+
+    /// when the device disconnects during an upload, like a bootloader which resets after
+    /// erasing the slot, wait for it and continue the upload at the offset of the device, up to
+    /// --nb-retry times
+    #[arg(long)]
+    auto_recover: bool,
+
+    /// abort an upload which sends more than this number of image bytes, counting resent chunks
+    #[arg(long)]
+    max_upload_bytes: Option<u64>,
+
+    /// abort an upload which takes longer than this number of seconds
+    #[arg(long = "max-upload-duration")]
+    max_upload_duration_s: Option<u64>,
+
+    /// sequence id of the first request, instead of a random one
+    #[arg(long)]
+    seq_start: Option<u8>,
+
+    /// number of data bits: 5, 6, 7 or 8
+    #[arg(long, default_value = "8", value_parser = parse_data_bits)]
+    data_bits: DataBits,
+
+    /// parity: none, odd or even
+    #[arg(long, default_value = "none", value_parser = parse_parity)]
+    parity: Parity,
+
+    /// number of stop bits: 1 or 2
+    #[arg(long, default_value = "1", value_parser = parse_stop_bits)]
+    stop_bits: StopBits,
 
-    /// baudrate
-    #[arg(short, long, default_value_t = 115_200)]
-    baudrate: u32,
+    /// flow control: none, software or hardware
+    #[arg(long, default_value = "none", value_parser = parse_flow_control)]
+    flow_control: FlowControl,
+
+    /// maximum number of bytes to read from the serial port at once
+    #[arg(long, default_value_t = 1024)]
+    read_chunk_size: usize,
+
+    /// reject answers with another command id than the request, instead of only warning
+    #[arg(long)]
+    strict_id: bool,
+
+    /// extra field for the CBOR map of the requests, like force=int:1, repeat it for more fields.
+    /// The type is int, str, bool or bytes (hex), or for no type, int, bool or str as it fits.
+    #[arg(long = "extra", value_parser = parse_extra_field)]
+    extra_fields: Vec<(String, serde_cbor::Value)>,
+
+    /// file to record the bytes sent to and received from the device, to replay the session
+    /// with -d replay:<file>
+    #[arg(long)]
+    transport_log: Option<PathBuf>,
+
+    /// JSON file with scripted responses for the "test" device
+    #[arg(long, hide = true)]
+    test_scenario: Option<PathBuf>,
+
+    /// checksum algorithm of the serial transport
+    #[arg(long, value_enum, default_value_t = Crc::Xmodem)]
+    crc: Crc,
+
+    /// serial transport of the device: console for the SMP console transport with base64 lines,
+    /// raw for the raw UART transport, which sends the packets without encoding
+    #[arg(long, value_enum, default_value_t = Framing::Console)]
+    framing: Framing,
+
+    /// print JSON answers as one line, without the "response: " prefix, for tools like jq
+    #[arg(long)]
+    json_compact: bool,
 
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Clone, Copy)]
+enum Mtu {
+    Auto,
+    Bytes(usize),
+}
+
+impl FromStr for Mtu {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mtu, String> {
+        if s == "auto" {
+            Ok(Mtu::Auto)
+        } else {
+            s.parse::<usize>()
+                .map(Mtu::Bytes)
+                .map_err(|_| format!("invalid MTU: {}, expected a number or \"auto\"", s))
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Baudrate {
+    Auto,
+    Rate(u32),
+}
+
+impl FromStr for Baudrate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Baudrate, String> {
+        if s == "auto" {
+            Ok(Baudrate::Auto)
+        } else {
+            s.parse::<u32>()
+                .map(Baudrate::Rate)
+                .map_err(|_| format!("invalid baudrate: {}, expected a number or \"auto\"", s))
+        }
+    }
+}
+
+// the names of the flags which are set
+fn image_flags(entry: &ImageStateEntry) -> Vec<&'static str> {
+    [
+        (entry.active, "active"),
+        (entry.confirmed, "confirmed"),
+        (entry.pending, "pending"),
+        (entry.permanent, "permanent"),
+        (entry.bootable, "bootable"),
+    ]
+    .into_iter()
+    .filter(|(flag, _)| *flag)
+    .map(|(_, name)| name)
+    .collect()
+}
+
+fn short_hash(entry: &ImageStateEntry) -> String {
+    hex::encode(entry.hash.iter().take(4).cloned().collect::<Vec<u8>>())
+}
+
+fn image_summary(entry: &ImageStateEntry) -> String {
+    let mut summary = format!("version {}, hash {}", entry.version, short_hash(entry));
+    for name in image_flags(entry) {
+        summary += ", ";
+        summary += name;
+    }
+    summary
+}
+
+// one line per slot, like "image=0 slot=0 v1.1.1 active confirmed 61ddbce8"
+fn slot_line(entry: &ImageStateEntry) -> String {
+    let mut words = vec![
+        format!("image={}", entry.image),
+        format!("slot={}", entry.slot),
+        format!("v{}", entry.version),
+    ];
+    words.extend(image_flags(entry).into_iter().map(str::to_string));
+    words.push(short_hash(entry));
+    words.join(" ")
+}
+
+// print the slots whose image changed
+fn print_image_changes(before: &ImageStateRsp, after: &ImageStateRsp) {
+    let mut changed = false;
+    for entry in &after.images {
+        let old = before
+            .images
+            .iter()
+            .find(|old| old.image == entry.image && old.slot == entry.slot);
+        let new_summary = image_summary(entry);
+        match old.map(image_summary) {
+            Some(old_summary) if old_summary == new_summary => continue,
+            Some(old_summary) => println!(
+                "image {} slot {}: {} -> {}",
+                entry.image, entry.slot, old_summary, new_summary
+            ),
+            None => println!(
+                "image {} slot {}: empty -> {}",
+                entry.image, entry.slot, new_summary
+            ),
+        }
+        changed = true;
+    }
+    if !changed {
+        println!("no image changes");
+    }
+    if after.images.iter().any(|entry| entry.pending) {
+        println!("swap pending: the new image is used after the next reset");
+    }
+}
+
+// a device buffer smaller than the SMP packets drops or rejects large requests, which makes
+// uploads fail with unclear errors. The MTU is for the framed requests, the buffer for the
+// decoded packets.
+fn warn_small_buffer(specs: &SerialSpecs, buf_size: u32) {
+    let packet_len = specs.max_packet_len();
+    if (buf_size as usize) < packet_len {
+        warn!(
+            "the device buffer size {} is smaller than the SMP packets of up to {} bytes with MTU {}, uploads will fail, use a smaller --mtu or --max-payload, or --mtu auto",
+            buf_size, packet_len, specs.mtu
+        );
+    }
+}
+
+fn print_profile(stats: &UploadStats) {
+    let total = stats.elapsed.as_secs_f64();
+    let timing = &stats.timing;
+    let measured = timing.encode + timing.write + timing.wait + timing.decode;
+    println!(
+        "profile of {} bytes in {} requests, {:.3} s:",
+        stats.bytes, stats.sent_blocks, total
+    );
+    for (phase, time) in [
+        ("encode", timing.encode),
+        ("write", timing.write),
+        ("wait for answer", timing.wait),
+        ("decode", timing.decode),
+        ("other", stats.elapsed.saturating_sub(measured)),
+    ] {
+        let percent = if total > 0.0 {
+            time.as_secs_f64() * 100.0 / total
+        } else {
+            0.0
+        };
+        println!(
+            "  {:<16} {:>9.3} s {:>5.1}%",
+            phase,
+            time.as_secs_f64(),
+            percent
+        );
+    }
+}
+
+fn upload_progress_bar() -> ProgressBar {
+    let pb = ProgressBar::new(1_u64);
+    pb.set_style(ProgressStyle::default_bar()
+    .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+    .unwrap().progress_chars("=> "));
+    pb
+}
+
+fn update_progress(pb: &ProgressBar, offset: u64, total: u64) {
+    if let Some(l) = pb.length() {
+        if l != total {
+            pb.set_length(total)
+        }
+    }
+
+    pb.set_position(offset);
+
+    if offset >= total {
+        pb.finish_with_message("upload complete");
+    }
+}
+
+fn parse_address(s: &str) -> Result<u32, String> {
+    let hex = s.strip_prefix("0x").unwrap_or(s);
+    u32::from_str_radix(hex, 16).map_err(|_| format!("invalid hex address: {}", s))
+}
+
+// any group number, also of vendor specific groups
+fn parse_group(s: &str) -> Result<NmpGroup, String> {
+    s.parse::<u16>()
+        .map(NmpGroup::from_u16)
+        .map_err(|_| format!("invalid group: {}", s))
+}
+
+// the CBOR request body of a JSON or CBOR file
+fn read_body_file(filename: &PathBuf) -> Result<Vec<u8>, Error> {
+    let data = std::fs::read(filename)?;
+    if let Ok(json) = serde_json::from_slice::<serde_json::Value>(&data) {
+        return Ok(serde_cbor::to_vec(&json)?);
+    }
+    serde_cbor::from_slice::<serde_cbor::Value>(&data).map_err(|e| {
+        anyhow::format_err!(
+            "{} is neither JSON nor CBOR: {}",
+            filename.to_string_lossy(),
+            e
+        )
+    })?;
+    Ok(data)
+}
+
+fn print_log_entry(entry: &LogEntry) {
+    println!(
+        "[{}] {} {}: {}",
+        entry.index,
+        entry.timestamp,
+        entry.level,
+        log_entry_text(entry)
+    );
+}
+
+// pretty JSON after "response: ", or with --json-compact one JSON line
+fn print_response<T: serde::Serialize>(cli: &Cli, ans: &T) -> Result<(), Error> {
+    if cli.json_compact {
+        println!("{}", serde_json::to_string(ans)?);
+    } else {
+        print!("response: {}", serde_json::to_string_pretty(ans)?);
+    }
+    Ok(())
+}
+
+// pretty JSON, or with --json-compact one JSON line
+fn print_json<T: serde::Serialize>(cli: &Cli, value: &T) -> Result<(), Error> {
+    if cli.json_compact {
+        println!("{}", serde_json::to_string(value)?);
+    } else {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    }
+    Ok(())
+}
+
+// print the new image states, if the device sent them
+fn print_state_write_response(cli: &Cli, ans: &ImageStateWriteRsp) -> Result<(), Error> {
+    if !ans.images.is_empty() {
+        print_response(cli, ans)?;
+    }
+    Ok(())
+}
+
+fn parse_extra_field(s: &str) -> Result<(String, serde_cbor::Value), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid extra field: {}, expected key=value", s))?;
+    let value = match value.split_once(':') {
+        Some(("int", v)) => v
+            .parse::<i64>()
+            .map(|v| serde_cbor::Value::Integer(v as i128))
+            .map_err(|_| format!("invalid int: {}", v))?,
+        Some(("str", v)) => serde_cbor::Value::Text(v.to_string()),
+        Some(("bool", v)) => v
+            .parse::<bool>()
+            .map(serde_cbor::Value::Bool)
+            .map_err(|_| format!("invalid bool: {}", v))?,
+        Some(("bytes", v)) => hex::decode(v)
+            .map(serde_cbor::Value::Bytes)
+            .map_err(|_| format!("invalid hex bytes: {}", v))?,
+        _ => {
+            if let Ok(v) = value.parse::<i64>() {
+                serde_cbor::Value::Integer(v as i128)
+            } else if let Ok(v) = value.parse::<bool>() {
+                serde_cbor::Value::Bool(v)
+            } else {
+                serde_cbor::Value::Text(value.to_string())
+            }
+        }
+    };
+    Ok((key.to_string(), value))
+}
+
+fn parse_timeout_scale(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(scale) if scale > 0.0 && scale.is_finite() => Ok(scale),
+        _ => Err(format!(
+            "invalid timeout scale: {}, expected a positive number",
+            s
+        )),
+    }
+}
+
+fn parse_data_bits(s: &str) -> Result<DataBits, String> {
+    match s {
+        "5" => Ok(DataBits::Five),
+        "6" => Ok(DataBits::Six),
+        "7" => Ok(DataBits::Seven),
+        "8" => Ok(DataBits::Eight),
+        _ => Err(format!("invalid number of data bits: {}", s)),
+    }
+}
+
+fn parse_parity(s: &str) -> Result<Parity, String> {
+    match s {
+        "none" => Ok(Parity::None),
+        "odd" => Ok(Parity::Odd),
+        "even" => Ok(Parity::Even),
+        _ => Err(format!("invalid parity: {}", s)),
+    }
+}
+
+fn parse_stop_bits(s: &str) -> Result<StopBits, String> {
+    match s {
+        "1" => Ok(StopBits::One),
+        "2" => Ok(StopBits::Two),
+        _ => Err(format!("invalid number of stop bits: {}", s)),
+    }
+}
+
+fn parse_flow_control(s: &str) -> Result<FlowControl, String> {
+    match s {
+        "none" => Ok(FlowControl::None),
+        "software" => Ok(FlowControl::Software),
+        "hardware" => Ok(FlowControl::Hardware),
+        _ => Err(format!("invalid flow control: {}", s)),
+    }
+}
+
 impl From<&Cli> for SerialSpecs {
     fn from(cli: &Cli) -> SerialSpecs {
         SerialSpecs {
             device: cli.device.clone(),
             initial_timeout_s: cli.initial_timeout_s,
             subsequent_timeout_ms: cli.subsequent_timeout_ms,
+            erase_timeout_s: cli.erase_timeout_s,
+            timeout_scale: cli.timeout_scale,
             nb_retry: cli.nb_retry,
             linelength: cli.linelength,
-            mtu: cli.mtu,
-            baudrate: cli.baudrate,
+            mtu: match cli.mtu {
+                Mtu::Bytes(mtu) => mtu,
+                Mtu::Auto => SerialSpecs::default().mtu,
+            },
+            max_payload: cli.max_payload,
+            baudrate: match cli.baudrate {
+                Baudrate::Rate(baudrate) => baudrate,
+                Baudrate::Auto => SerialSpecs::default().baudrate,
+            },
+            retry_base_delay_ms: cli.retry_base_delay_ms,
+            retry_max_delay_ms: cli.retry_max_delay_ms,
+            retry_jitter: cli.retry_jitter,
+            crc: cli.crc,
+            framing: cli.framing,
+            no_sha: cli.no_sha,
+            no_decompress: cli.no_decompress,
+            no_slot_check: cli.no_slot_check,
+            auto_image: cli.auto_image,
+            reset_upload_state: false,
+            len_every_chunk: cli.len_every_chunk,
+            canonical_cbor: cli.canonical_cbor,
+            keep_open: false,
+            no_flush: cli.no_flush,
+            auto_recover: cli.auto_recover,
+            max_upload_bytes: cli.max_upload_bytes,
+            max_upload_duration: cli.max_upload_duration_s.map(Duration::from_secs),
+            data_bits: cli.data_bits,
+            parity: cli.parity,
+            stop_bits: cli.stop_bits,
+            flow_control: cli.flow_control,
+            read_chunk_size: cli.read_chunk_size,
+            strict_id: cli.strict_id,
+            test_scenario: cli.test_scenario.clone(),
+            transport_log: cli.transport_log.clone(),
+            extra_fields: cli.extra_fields.clone(),
         }
     }
 }
@@ -68,160 +567,1018 @@ impl From<&Cli> for SerialSpecs {
 #[derive(Subcommand)]
 enum Commands {
     /// list slots on the device
-    List,
+    List {
+        /// sort the images by image number and slot, for output which can be compared
+        #[arg(long)]
+        sort: bool,
+
+        /// print one line per slot with the version, flags and the start of the hash, instead
+        /// of JSON
+        #[arg(long)]
+        slots_only: bool,
+    },
 
     /// reset the device
-    Reset,
+    Reset {
+        /// stay in the bootloader after the reset, if the firmware supports the boot mode
+        #[arg(long)]
+        to_bootloader: bool,
+    },
+
+    /// show the slot sizes of the images on the device
+    SlotInfo,
 
     /// upload a file to the device
     Upload {
-        filename: PathBuf,
+        #[arg(required_unless_present = "images", conflicts_with = "images")]
+        filename: Option<PathBuf>,
 
         /// slot number
         #[arg(short, long, default_value_t = 1)]
         slot: u8,
+
+        /// image number and file, repeat it to upload multiple images in one session
+        #[arg(long = "image", num_args = 2, value_names = ["IMAGE", "FILE"])]
+        images: Vec<String>,
+
+        /// don't list the images before and after the upload to show what changed
+        #[arg(long)]
+        no_summary: bool,
+
+        /// show how much of the upload time is spent encoding, writing, waiting and decoding
+        #[arg(long)]
+        profile: bool,
+
+        /// mark the uploaded image as pending after the upload, to boot it once after the
+        /// next reset, like the test command
+        #[arg(long = "test", conflicts_with = "images")]
+        pending: bool,
+
+        /// mark the uploaded image as permanent after the upload, like the confirm command.
+        /// Risky: it is booted after the next reset without a test boot, and there is no
+        /// automatic rollback if it doesn't work.
+        #[arg(long, conflicts_with_all = ["images", "pending"])]
+        confirm_permanent: bool,
+
+        /// what to do after the upload: "reset" marks the image as pending and resets the
+        /// device to boot it for testing, "confirm" marks it as permanent, like
+        /// --confirm-permanent
+        #[arg(long, value_enum, default_value_t = AfterUpload::None,
+            conflicts_with_all = ["images", "pending", "confirm_permanent"])]
+        after: AfterUpload,
+
+        /// clear the state of an interrupted upload on the device before the upload. MCUmgr
+        /// img_mgmt (Zephyr, MCUboot) has no command to abort an upload or erase its state,
+        /// so this erases the upload slot instead, which also clears the state
+        #[arg(long)]
+        reset_upload_state: bool,
+
+        /// file for the offset of the upload, to continue an interrupted upload of the same
+        /// image at this offset when run again, deleted when the upload is complete
+        #[arg(long, conflicts_with = "images")]
+        state_file: Option<PathBuf>,
     },
 
+    /// compare an image file with the image in a slot of the device, fails if they differ
+    Verify {
+        filename: PathBuf,
+
+        /// slot number, 0 for the running image
+        #[arg(short, long, default_value_t = 0)]
+        slot: u32,
+
+        /// image number, for devices with multiple images
+        #[arg(long, default_value_t = 0)]
+        image: u32,
+    },
+
+    /// advanced and unsafe: write a file to a flash address, for custom bootloaders
+    /// without slots. Nothing prevents overwriting the bootloader itself.
+    RawUpload {
+        filename: PathBuf,
+
+        /// flash address, like 0x10000
+        #[arg(long, value_parser = parse_address)]
+        address: u32,
+    },
+
+    /// advanced: send any request and print the response as JSON, like for commands which
+    /// have no subcommand here
+    Raw {
+        /// group, like 0 for the default group, 1 for images or 64 and up for vendor groups
+        #[arg(value_parser = parse_group)]
+        group: NmpGroup,
+
+        /// command id in the group
+        id: u8,
+
+        #[arg(value_enum)]
+        op: RawOp,
+
+        /// file with the request body, as JSON or CBOR, instead of an empty map
+        #[arg(long)]
+        body_file: Option<PathBuf>,
+    },
+
+    /// mark an image as pending, to boot it once after the next reset
     Test {
-        hash: String,
+        #[arg(required_unless_present = "hash_from_list")]
+        hash: Option<String>,
+        /// with true, mark it as permanent instead, like the confirm command
         #[arg(short, long)]
         confirm: Option<bool>,
+        /// use the hash of the bootable image which isn't running, like after an upload
+        #[arg(long, conflicts_with = "hash")]
+        hash_from_list: bool,
+    },
+
+    /// mark an image as permanent, or the running image, if no hash is given
+    Confirm {
+        hash: Option<String>,
+        /// use the hash of the bootable image which isn't running, like after an upload
+        #[arg(long, conflicts_with = "hash")]
+        hash_from_list: bool,
     },
+
+    /// mark the image in the other slot as pending, to boot the previous image again
+    Revert,
+
     Erase {
         #[arg(short, long)]
         slot: Option<u32>,
+
+        /// result codes to treat as success, like 6 for an already erased slot on some firmware
+        #[arg(long, value_delimiter = ',')]
+        ignore_rc: Vec<u32>,
+    },
+
+    /// set the image states as listed in a JSON manifest, like [{"slot": 1, "confirm": false}]
+    ApplyState { manifest: PathBuf },
+
+    /// show the MCUmgr buffer parameters of the device
+    Params,
+
+    /// WARNING: crashes the device on purpose, for testing the core dump handling
+    Crash {
+        /// crash type, like div0, jump0, ref0, assert or wdog
+        crash_type: String,
+    },
+
+    /// run commands from stdin line by line on one connection, until the end of the input or
+    /// quit
+    Repl,
+
+    /// show the kernel name, version, build date and board of the running application
+    AppInfo {
+        /// fields to show, like "svb" for the kernel name, version and build date, default "s"
+        format: Option<String>,
+    },
+
+    /// show the bootloader mode and the swap which is done at the next boot
+    Bootloader,
+
+    /// send a text to the device, which sends it back
+    Echo { text: String },
+
+    /// turn the echo of the device console on or off
+    ConsoleEcho {
+        #[arg(long, required_unless_present = "off", conflicts_with = "off")]
+        on: bool,
+
+        #[arg(long)]
+        off: bool,
+    },
+
+    /// find the largest MTU which works with the device, using echo requests
+    Probe,
+
+    /// show the image list, MCUmgr parameters and application info of the device as JSON
+    Info,
+
+    /// check if the device answers echo, parameter and image list requests, and print PASS or
+    /// FAIL, for a pre-flight check in CI
+    Health,
+
+    /// collect the answers of the device to the read requests of all groups in a JSON bundle,
+    /// for a support ticket
+    Diagnose {
+        /// file for the bundle, instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+
+    /// show the logs of the device
+    Log {
+        #[command(subcommand)]
+        command: LogCommands,
+    },
+
+    /// query and set the split image status
+    Split {
+        #[command(subcommand)]
+        command: SplitCommands,
+    },
+
+    /// list and run tests on the device
+    Run {
+        #[command(subcommand)]
+        command: RunCommands,
+    },
+
+    /// manage the settings on the device
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
+    /// decode a frame from the log, the base64 data of all its lines without the line markers,
+    /// like the "result string" of verbose mode. It doesn't need a device.
+    Decode { data: String },
+
+    /// show the frame of a request as it would be sent, in hex and base64, for comparing it
+    /// with other clients. It doesn't need a device.
+    Encode {
+        /// group, like 0 for the default group, 1 for images or 64 and up for vendor groups
+        #[arg(value_parser = parse_group)]
+        group: NmpGroup,
+
+        /// command id in the group
+        id: u8,
+
+        #[arg(value_enum)]
+        op: RawOp,
+
+        /// request body as JSON, instead of an empty map
+        #[arg(long)]
+        body_json: Option<String>,
+    },
+
+    /// show the TLVs of an MCUboot image file, like the hash, signature, security counter and
+    /// dependencies
+    Tlvs { filename: PathBuf },
+
+    /// access the file system of the device
+    Fs {
+        #[command(subcommand)]
+        command: FsCommands,
     },
 }
 
-fn main() {
-    // show program name, version and copyright
-    let name = env!("CARGO_PKG_NAME");
-    let version = env!("CARGO_PKG_VERSION");
-    println!("{} {}, Copyright © 2024 Vouch.io LLC", name, version);
-    println!("");
+impl Commands {
+    // commands for files and logs work without a device
+    fn needs_device(&self) -> bool {
+        !matches!(
+            self,
+            Commands::Tlvs { .. } | Commands::Decode { .. } | Commands::Encode { .. }
+        )
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RawOp {
+    Read,
+    Write,
+}
+
+impl From<RawOp> for NmpOp {
+    fn from(op: RawOp) -> NmpOp {
+        match op {
+            RawOp::Read => NmpOp::Read,
+            RawOp::Write => NmpOp::Write,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum AfterUpload {
+    None,
+    Reset,
+    Confirm,
+}
+
+#[derive(Subcommand)]
+enum LogCommands {
+    /// show the log entries on the device
+    Show {
+        /// name of the log, instead of all logs
+        #[arg(long)]
+        name: Option<String>,
+
+        /// index of the first entry
+        #[arg(long, default_value_t = 0)]
+        index: u32,
+    },
+
+    /// show new log entries as they appear, until interrupted
+    Watch {
+        /// name of the log, instead of all logs
+        #[arg(long)]
+        name: Option<String>,
+
+        /// delay in msec between log requests
+        #[arg(long, default_value_t = 1000)]
+        interval_ms: u64,
+    },
+
+    /// list the log modules with their numbers
+    Modules,
+
+    /// list the log levels with their numbers
+    Levels,
+}
+
+#[derive(Subcommand)]
+enum SplitCommands {
+    /// show if the split application matches the loader
+    Status,
 
+    /// boot the split application permanently after the next reset
+    Apply,
+}
+
+#[derive(Subcommand)]
+enum RunCommands {
+    /// list the test suites on the device
+    List,
+
+    /// run a test suite on the device, or all test suites
+    Test {
+        name: Option<String>,
+
+        /// token to identify the test run in the test results
+        #[arg(long)]
+        token: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// read a setting
+    Read {
+        /// name of the setting
+        name: String,
+    },
+
+    /// write a setting
+    Write {
+        /// name of the setting
+        name: String,
+
+        /// new value
+        value: String,
+
+        /// read the setting back and fail if it differs
+        #[arg(long)]
+        verify: bool,
+    },
+
+    /// apply the written settings
+    Commit,
+
+    /// load the settings from persistent storage
+    Load,
+
+    /// save the settings to persistent storage
+    Save,
+}
+
+#[derive(Subcommand)]
+enum FsCommands {
+    /// show if a file exists on the device, and its length
+    Stat {
+        /// path of the file, like /lfs/settings.txt
+        path: String,
+    },
+}
+
+fn main() {
     // parse command line arguments
     let mut cli = Cli::parse();
 
+    // show program name, version and copyright, except when stdout has only the JSON answers
+    if !cli.json_compact {
+        let name = env!("CARGO_PKG_NAME");
+        let version = env!("CARGO_PKG_VERSION");
+        println!("{} {}, Copyright © 2024 Vouch.io LLC", name, version);
+        println!();
+    }
+
     // initialize the logger with the desired level filter based on the verbose flag
     let level_filter = if cli.verbose {
         LevelFilter::Debug
     } else {
         LevelFilter::Info
     };
+    let terminal_mode = if cli.json_compact {
+        TerminalMode::Stderr
+    } else {
+        TerminalMode::Mixed
+    };
     TermLogger::init(
         level_filter,
         Config::default(),
-        TerminalMode::Mixed,
+        terminal_mode,
         ColorChoice::Auto,
     )
     .unwrap_or_else(|_| SimpleLogger::init(LevelFilter::Info, Default::default()).unwrap());
 
-    // if no device is specified, try to auto detect it
-    if cli.device.is_empty() {
-        let mut bootloaders = Vec::new();
-        match available_ports() {
-            Ok(ports) => {
-                for port in ports {
-                    let name = port.port_name;
-                    // on Mac, use only special names
-                    if env::consts::OS == "macos" {
-                        if name.contains("cu.usbmodem") {
-                            bootloaders.push(name);
-                        }
-                    } else {
-                        bootloaders.push(name);
-                    }
-                }
+    // if no device is specified, or "auto", try to auto detect it, and find the current port of
+    // a USB device, when given by its serial number
+    if cli.command.needs_device() || cli.device.starts_with("usb:") {
+        match resolve_device(&cli.device) {
+            Ok(device) => cli.device = device,
+            Err(e) => {
+                error!("{:#}", e);
+                process::exit(1);
             }
-            Err(_) => {}
         }
+    }
 
-        // if there is one bootloader device, then use it
-        if bootloaders.len() == 1 {
-            cli.device = bootloaders[0].clone();
-            info!(
-                "One bootloader device found, setting device to: {}",
-                cli.device
+    // each opened port appends to the transport log of this session
+    if let Some(path) = &cli.transport_log {
+        if let Err(e) = std::fs::File::create(path) {
+            error!(
+                "failed to create transport log {}: {}",
+                path.to_string_lossy(),
+                e
             );
-        } else {
-            // otherwise print all devices, and use a device, if there is only one device
-            if cli.device.is_empty() {
-                match available_ports() {
-                    Ok(ports) => match ports.len() {
-                        0 => {
-                            error!("No serial port found.");
-                            process::exit(1);
-                        }
-                        1 => {
-                            cli.device = ports[0].port_name.clone();
-                            info!(
-                                "Only one serial port found, setting device to: {}",
-                                cli.device
-                            );
-                        }
-                        _ => {
-                            error!("More than one serial port found, please specify one:");
-                            for p in ports {
-                                println!("{}", p.port_name);
-                            }
-                            process::exit(1);
-                        }
-                    },
-                    Err(e) => {
-                        println!("Error listing serial ports: {}", e);
-                        process::exit(1);
-                    }
+            process::exit(1);
+        }
+    }
+
+    let mut specs = SerialSpecs::from(&cli);
+
+    if let Some(seq_start) = cli.seq_start {
+        set_seq_id(seq_start);
+    } else if let Some(path) = cli.device.strip_prefix("replay:") {
+        // the answers in the log are for the sequence ids of the recorded session
+        let seq_start = read_transport_log(Path::new(path))
+            .ok()
+            .and_then(|entries| first_seq_id(&entries, cli.crc, cli.framing));
+        if let Some(seq_start) = seq_start {
+            set_seq_id(seq_start);
+        }
+    }
+
+    // find the baudrate of the device, if requested
+    if let Baudrate::Auto = cli.baudrate {
+        if cli.command.needs_device() {
+            match probe_baudrate(&specs) {
+                Ok(baudrate) => specs.baudrate = baudrate,
+                Err(e) => {
+                    error!("{:#}", e);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+
+    // limit the SMP packets to the buffer size of the device, if requested
+    if let Mtu::Auto = cli.mtu {
+        match params(&specs) {
+            Ok(p) => {
+                specs.use_buffer_size(p.buf_size as usize);
+                info!(
+                    "limiting the SMP packets to the device buffer size {}, MTU {}",
+                    p.buf_size, specs.mtu
+                );
+            }
+            Err(e) => warn!(
+                "failed to read the device buffer size, using MTU {}: {}",
+                specs.mtu, e
+            ),
+        }
+    }
+
+    // execute command, show error, if failed
+    if let Err(e) = with_reconnect(&specs, cli.reconnect, |specs| {
+        run(&cli, &cli.command, specs)
+    }) {
+        error!("Error: {:#}", e);
+        process::exit(1);
+    }
+}
+
+// a command line of the interactive mode
+#[derive(Parser)]
+#[command(no_binary_name = true)]
+struct ReplLine {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+// split a line in words at whitespace, with double quotes for words with spaces
+fn split_words(line: &str) -> Result<Vec<String>, Error> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quoted {
+        bail!("missing closing quote");
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+// run the commands from stdin on one connection, until the end of the input or quit
+fn repl(cli: &Cli, specs: &SerialSpecs) -> Result<(), Error> {
+    let specs = SerialSpecs {
+        keep_open: true,
+        ..specs.clone()
+    };
+    let stdin = std::io::stdin();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            println!();
+            break;
+        }
+        let words = match split_words(&line) {
+            Ok(words) => words,
+            Err(e) => {
+                error!("{:#}", e);
+                continue;
+            }
+        };
+        match words.first().map(String::as_str) {
+            None => continue,
+            Some("quit") | Some("exit") => break,
+            _ => (),
+        }
+        match ReplLine::try_parse_from(&words) {
+            Ok(ReplLine {
+                command: Commands::Repl,
+            }) => warn!("already in interactive mode"),
+            Ok(line) => {
+                if let Err(e) = run(cli, &line.command, &specs) {
+                    error!("Error: {:#}", e);
+                    // the device might have reset, then the port needs to be opened again
+                    close_kept_port();
                 }
             }
+            Err(e) => e.print()?,
         }
     }
+    close_kept_port();
+    Ok(())
+}
+
+fn run(cli: &Cli, command: &Commands, specs: &SerialSpecs) -> Result<(), Error> {
+    // list, reset and test can be safely sent again
+    let repeat_delay = Duration::from_millis(cli.repeat_delay_ms as u64);
 
-    let specs = SerialSpecs::from(&cli);
+    match command {
+        Commands::Repl => repl(cli, specs)?,
+        Commands::List { sort, slots_only } => {
+            let mut v = with_repeat(cli.repeat_on_error, repeat_delay, || list(specs))?;
+            if *sort {
+                v.images.sort_by_key(|entry| (entry.image, entry.slot));
+            }
+            if *slots_only {
+                for entry in &v.images {
+                    println!("{}", slot_line(entry));
+                }
+            } else {
+                print_response(cli, &v)?;
+            }
+        }
+        Commands::Reset { to_bootloader } => {
+            let outcome = with_repeat(cli.repeat_on_error, repeat_delay, || {
+                if *to_bootloader {
+                    reset_to_bootloader(specs)
+                } else {
+                    reset(specs)
+                }
+            })?;
+            if outcome.acked {
+                debug!("reset acknowledged by the device");
+            } else {
+                debug!("no answer from the device, assuming it reset");
+            }
+            info!("reset complete");
+        }
+        Commands::SlotInfo => {
+            let v = slot_info(specs)?;
+            print_response(cli, &v)?;
+        }
+        Commands::Upload {
+            filename,
+            slot,
+            images,
+            no_summary,
+            profile,
+            pending,
+            confirm_permanent,
+            after,
+            reset_upload_state,
+            state_file,
+        } => {
+            // with --mtu auto, the MTU is the buffer size already, and not all devices support
+            // the parameters request
+            if !matches!(cli.mtu, Mtu::Auto) {
+                match params(specs) {
+                    Ok(p) => warn_small_buffer(specs, p.buf_size),
+                    Err(e) => debug!("no device buffer size to check the MTU: {:#}", e),
+                }
+            }
 
-    // execute command
-    let result = match &cli.command {
-        Commands::List => || -> Result<(), Error> {
-            let v = list(&specs)?;
-            print!("response: {}", serde_json::to_string_pretty(&v)?);
-            Ok(())
-        }(),
-        Commands::Reset => reset(&specs),
-        Commands::Upload { filename, slot } => || -> Result<(), Error> {
-            // create a progress bar
-            let pb = ProgressBar::new(1 as u64);
-            pb.set_style(ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap().progress_chars("=> "));
+            // remember the image states, to show what changed
+            let before = if *no_summary {
+                None
+            } else {
+                list(specs)
+                    .map_err(|e| warn!("failed to list images, no summary: {:#}", e))
+                    .ok()
+            };
 
-            upload(
-                &specs,
-                filename,
-                *slot,
-                Some(|offset, total| {
-                    if let Some(l) = pb.length() {
-                        if l != total {
-                            pb.set_length(total as u64)
+            // the upload erases the slot it uploads to, and the saved offset is no longer valid
+            let specs = &SerialSpecs {
+                reset_upload_state: *reset_upload_state,
+                ..specs.clone()
+            };
+            if *reset_upload_state {
+                if let Some(path) = state_file.as_ref().filter(|path| path.exists()) {
+                    std::fs::remove_file(path)?;
+                }
+            }
+
+            let pb = upload_progress_bar();
+            let progress = |offset, total| update_progress(&pb, offset, total);
+
+            let mut uploaded_hash = None;
+            match filename {
+                Some(filename) => {
+                    let ans = match state_file {
+                        Some(state_file) => upload_resumable(
+                            specs,
+                            filename,
+                            *slot,
+                            state_file,
+                            Some(progress),
+                            None,
+                        )?,
+                        None => upload(specs, filename, *slot, Some(progress), None)?,
+                    };
+                    debug!("response: {}", serde_json::to_string(&ans)?);
+                    if *profile {
+                        print_profile(&ans.stats);
+                    }
+                    uploaded_hash = ans.image_hash;
+                }
+                None => {
+                    let mut pairs = Vec::new();
+                    for pair in images.chunks(2) {
+                        let image = pair[0].parse::<u8>().map_err(|_| {
+                            anyhow::format_err!("invalid image number: {}", pair[0])
+                        })?;
+                        pairs.push((PathBuf::from(&pair[1]), image));
+                    }
+                    let answers = upload_multi(specs, &pairs, Some(progress), None)?;
+                    debug!("responses: {}", serde_json::to_string(&answers)?);
+                    if *profile {
+                        for ans in &answers {
+                            print_profile(&ans.stats);
                         }
                     }
+                }
+            }
+
+            let permanent = *confirm_permanent || *after == AfterUpload::Confirm;
+            if *pending || permanent || *after == AfterUpload::Reset {
+                // the list is only needed for files without an MCUboot header
+                let hash = match uploaded_hash {
+                    Some(hash) => hash,
+                    None => inactive_image_hash(specs)?,
+                };
+                let ans = test(specs, hash.clone(), Some(permanent))?;
+                print_state_write_response(cli, &ans)?;
 
-                    pb.set_position(offset as u64);
+                // don't reboot into another image than the uploaded one
+                let pending = ans
+                    .images
+                    .iter()
+                    .any(|entry| entry.hash == hash && entry.pending);
+                if *after == AfterUpload::Reset && !ans.images.is_empty() && !pending {
+                    bail!("the uploaded image isn't pending, not resetting the device");
+                }
+            }
 
-                    if offset >= total {
-                        pb.finish_with_message("upload complete");
+            // the upload succeeded, so a failed list only skips the summary
+            if let Some(before) = before {
+                match list(specs) {
+                    Ok(after) => print_image_changes(&before, &after),
+                    Err(e) => warn!("failed to list images, no summary: {:#}", e),
+                }
+            }
+
+            if *after == AfterUpload::Reset {
+                reset(specs)?;
+                info!("reset to boot the new image");
+            }
+        }
+        Commands::Verify {
+            filename,
+            slot,
+            image,
+        } => {
+            let result = verify(specs, filename, *image, *slot)?;
+            println!("file hash: {}", hex::encode(&result.file_hash));
+            match &result.slot_hash {
+                Some(hash) => println!("slot hash: {}", hex::encode(hash)),
+                None => println!("slot hash: none, image {} slot {} is empty", image, slot),
+            }
+            if !result.matches() {
+                bail!("the image in the slot doesn't match the file");
+            }
+            println!("the image in the slot matches the file");
+        }
+        Commands::Raw {
+            group,
+            id,
+            op,
+            body_file,
+        } => {
+            let body = match body_file {
+                Some(filename) => read_body_file(filename)?,
+                None => serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new())?,
+            };
+            let response = send_request(specs, (*op).into(), *group, RawId(*id), &body)?;
+            print_json(cli, &response)?;
+        }
+        Commands::RawUpload { filename, address } => {
+            let pb = upload_progress_bar();
+            let progress = |offset, total| update_progress(&pb, offset, total);
+            let ans = raw_upload(specs, filename, *address, Some(progress), None)?;
+            debug!("response: {}", serde_json::to_string(&ans)?);
+        }
+        Commands::Test {
+            hash,
+            confirm,
+            hash_from_list,
+        } => {
+            let hash = match hash {
+                Some(hash) if !hash_from_list => parse_hash(hash)?,
+                _ => inactive_image_hash(specs)?,
+            };
+            let ans = with_repeat(cli.repeat_on_error, repeat_delay, || {
+                test(specs, hash.clone(), *confirm)
+            })?;
+            print_state_write_response(cli, &ans)?;
+        }
+        Commands::Confirm {
+            hash,
+            hash_from_list,
+        } => {
+            let hash = if *hash_from_list {
+                Some(inactive_image_hash(specs)?)
+            } else {
+                hash.as_deref().map(parse_hash).transpose()?
+            };
+            print_state_write_response(cli, &confirm(specs, hash)?)?;
+        }
+        Commands::Revert => print_state_write_response(cli, &revert(specs)?)?,
+        Commands::Erase { slot, ignore_rc } => {
+            let ans = erase(specs, *slot, ignore_rc)?;
+            debug!("response: {}", serde_json::to_string(&ans)?);
+        }
+        Commands::ApplyState { manifest } => {
+            let entries: Vec<ImageStateManifestEntry> =
+                serde_json::from_slice(&std::fs::read(manifest)?)?;
+            let results = apply_state(specs, &entries);
+            let mut failed = 0;
+            for (entry, result) in entries.iter().zip(results) {
+                let name = match (&entry.hash, entry.slot) {
+                    (Some(hash), _) => hash.clone(),
+                    (None, slot) => format!("image {} slot {}", entry.image, slot.unwrap_or(0)),
+                };
+                match result {
+                    Ok(()) => info!("{}: state set, confirm: {}", name, entry.confirm),
+                    Err(e) => {
+                        error!("{}: {:#}", name, e);
+                        failed += 1;
                     }
-                }),
-            )
-        }(),
-        Commands::Test { hash, confirm } => || -> Result<(), Error> { 
-            test(&specs, hex::decode(hash)?, *confirm)
-        }(),
-        Commands::Erase { slot } => erase(&specs, *slot),
-    };
+                }
+            }
+            if failed > 0 {
+                bail!("{} of {} state changes failed", failed, entries.len());
+            }
+        }
+        Commands::Params => {
+            let p = params(specs)?;
+            print_response(cli, &p)?;
+            warn_small_buffer(specs, p.buf_size);
+        }
+        Commands::Crash { crash_type } => {
+            warn!("crashing the device on purpose with {}", crash_type);
+            crash_trigger(specs, crash_type.clone())?;
+        }
+        Commands::AppInfo { format } => println!("{}", app_info(specs, format.clone())?),
+        Commands::Bootloader => {
+            match bootloader_info(specs)? {
+                Some(info) => {
+                    let name = info.bootloader.as_deref().unwrap_or("unknown");
+                    match info.mode {
+                        Some(mode) => {
+                            println!("bootloader: {}, mode: {}", name, bootloader_mode_name(mode))
+                        }
+                        None => println!("bootloader: {}", name),
+                    }
+                }
+                None => println!("bootloader: unknown, the device doesn't support the query"),
+            }
+            match swap_type(&list(specs)?.images) {
+                SwapType::None => println!("no swap pending"),
+                swap => println!("swap pending: {}", swap),
+            }
+        }
+        Commands::Echo { text } => println!("{}", echo(specs, text)?),
+        Commands::ConsoleEcho { on, .. } => console_echo(specs, *on)?,
+        Commands::Info => print_json(cli, &device_info(specs)?)?,
+        Commands::Diagnose { out } => {
+            let bundle = diagnose(specs);
+            for entry in &bundle.entries {
+                match &entry.error {
+                    Some(e) => warn!("{}: {:?}, {}", entry.request, entry.status, e),
+                    None => info!("{}: {:?}", entry.request, entry.status),
+                }
+            }
+            match out {
+                Some(filename) => {
+                    std::fs::write(filename, serde_json::to_string_pretty(&bundle)?)?;
+                    info!("diagnostics written to {}", filename.to_string_lossy());
+                }
+                None => print_json(cli, &bundle)?,
+            }
+        }
+        Commands::Health => {
+            let report = health(specs);
+            for check in &report.checks {
+                let status = match check.status {
+                    HealthStatus::Pass => "ok",
+                    HealthStatus::Fail => "failed",
+                    HealthStatus::NotSupported => "skipped",
+                };
+                println!("{}: {}, {}", check.name, status, check.details);
+            }
+            if !report.passed() {
+                println!("FAIL");
+                bail!("health check failed");
+            }
+            println!("PASS");
+        }
+        Commands::Probe => {
+            let mtu = probe_mtu(specs)?;
+            println!("largest working MTU: {}, use --mtu {}", mtu, mtu);
+        }
+        Commands::Log { command } => match command {
+            LogCommands::Show { name, index } => {
+                let ans = log_show(specs, name.clone(), *index)?;
+                for log in &ans.logs {
+                    for entry in &log.entries {
+                        print_log_entry(entry);
+                    }
+                }
+            }
+            LogCommands::Watch { name, interval_ms } => log_watch(
+                specs,
+                name.clone(),
+                Duration::from_millis(*interval_ms),
+                print_log_entry,
+                None,
+            )?,
+            LogCommands::Modules => {
+                for (name, number) in log_module_list(specs)? {
+                    println!("{:>3} {}", number, name);
+                }
+            }
+            LogCommands::Levels => {
+                for (name, number) in log_level_list(specs)? {
+                    println!("{:>3} {}", number, name);
+                }
+            }
+        },
+        Commands::Split { command } => match command {
+            SplitCommands::Status => println!("split status: {:?}", split_status(specs)?),
+            SplitCommands::Apply => split_apply(specs)?,
+        },
+        Commands::Run { command } => match command {
+            RunCommands::List => {
+                for name in run_list(specs)? {
+                    println!("{}", name);
+                }
+            }
+            RunCommands::Test { name, token } => {
+                let ans = run_test(specs, name.clone(), token.clone())?;
+                print_response(cli, &ans)?;
+            }
+        },
+        Commands::Config { command } => match command {
+            ConfigCommands::Read { name } => {
+                println!("{}", format_value(&config_read(specs, name)?));
+            }
+            ConfigCommands::Write {
+                name,
+                value,
+                verify,
+            } => config_write(specs, name, value, *verify)?,
+            ConfigCommands::Commit => config_commit(specs)?,
+            ConfigCommands::Load => config_load(specs)?,
+            ConfigCommands::Save => config_save(specs)?,
+        },
+        Commands::Decode { data } => {
+            let data: String = data.split_whitespace().collect();
+            let (header, body) = decode_frame(cli.crc, data.as_bytes())?;
+            println!("header: {:?}", header);
+            println!("body: {}", serde_json::to_string_pretty(&body)?);
+        }
+        Commands::Encode {
+            group,
+            id,
+            op,
+            body_json,
+        } => {
+            let body = match body_json {
+                Some(json) => {
+                    serde_cbor::to_vec(&serde_json::from_str::<serde_json::Value>(json)?)?
+                }
+                None => serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new())?,
+            };
+            let (frame, header, body) =
+                encode_raw_request(specs, (*op).into(), *group, *id, &body)?;
+            println!("header: {:?}", header);
+            println!("body (hex): {}", hex::encode(&body));
+            println!("frame (hex): {}", hex::encode(&frame));
+            // the raw framing has no console lines
+            let lines = match specs.framing {
+                Framing::Console => &frame[..],
+                Framing::Raw => &[],
+            };
+            for line in lines.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+                println!(
+                    "line: {:?} {}",
+                    &line[..2],
+                    String::from_utf8_lossy(&line[2..])
+                );
+            }
+        }
+        Commands::Tlvs { filename } => {
+            let data = std::fs::read(filename)?;
+            for tlv in mcuboot::image_tlvs(&data)? {
+                println!("{}", tlv);
+            }
+        }
+        Commands::Fs { command } => match command {
+            FsCommands::Stat { path } => match fs_stat(specs, path.clone())? {
+                Some(len) => println!("{}: {} bytes", path, len),
+                None => println!("{}: not found", path),
+            },
+        },
+    }
+    Ok(())
+}
 
-    // show error, if failed
-    if let Err(e) = result {
-        error!("Error: {}", e);
-        process::exit(1);
+#[cfg(test)]
+mod tests {
+    use super::split_words;
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(
+            split_words("  echo   hello\tworld ").unwrap(),
+            ["echo", "hello", "world"]
+        );
+        assert!(split_words("").unwrap().is_empty());
+        assert!(split_words(" \t ").unwrap().is_empty());
+
+        // quotes for words with spaces, also in the middle of a word, and empty words
+        assert_eq!(
+            split_words(r#"echo "hello world" a"b c"d """#).unwrap(),
+            ["echo", "hello world", "ab cd", ""]
+        );
+
+        let e = split_words(r#"echo "hello"#).unwrap_err();
+        assert_eq!(e.to_string(), "missing closing quote");
     }
 }
@@ -0,0 +1,291 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{bail, Error, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+const IMAGE_MAGIC: u32 = 0x96f3b83d;
+const HEADER_LEN: usize = 32;
+const TLV_INFO_MAGIC: u16 = 0x6907;
+const TLV_PROT_INFO_MAGIC: u16 = 0x6908;
+const TLV_DEPENDENCY: u16 = 0x40;
+const TLV_SEC_CNT: u16 = 0x50;
+
+// the header at the start of an image for MCUboot
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageHeader {
+    pub load_addr: u32,
+    pub hdr_size: u16,
+    pub protect_tlv_size: u16,
+    pub img_size: u32,
+    pub flags: u32,
+    pub version: String,
+}
+
+// true if the data starts with the MCUboot image magic
+pub fn is_mcuboot_image(data: &[u8]) -> bool {
+    data.len() >= 4 && LittleEndian::read_u32(data) == IMAGE_MAGIC
+}
+
+pub fn parse_header(data: &[u8]) -> Result<ImageHeader, Error> {
+    if data.len() < HEADER_LEN {
+        bail!(
+            "image too short for an MCUboot header: {} bytes",
+            data.len()
+        );
+    }
+    if !is_mcuboot_image(data) {
+        bail!(
+            "no MCUboot image, magic is 0x{:08x}",
+            LittleEndian::read_u32(data)
+        );
+    }
+    let header = ImageHeader {
+        load_addr: LittleEndian::read_u32(&data[4..]),
+        hdr_size: LittleEndian::read_u16(&data[8..]),
+        protect_tlv_size: LittleEndian::read_u16(&data[10..]),
+        img_size: LittleEndian::read_u32(&data[12..]),
+        flags: LittleEndian::read_u32(&data[16..]),
+        version: format_version(&data[20..28]),
+    };
+    if data.len() < header.hashed_len() {
+        bail!(
+            "image truncated: {} bytes, but the header needs {} bytes",
+            data.len(),
+            header.hashed_len()
+        );
+    }
+    Ok(header)
+}
+
+// like the version in the image list, with the build number only if it isn't 0
+fn format_version(data: &[u8]) -> String {
+    let build = LittleEndian::read_u32(&data[4..]);
+    let version = format!(
+        "{}.{}.{}",
+        data[0],
+        data[1],
+        LittleEndian::read_u16(&data[2..])
+    );
+    if build == 0 {
+        version
+    } else {
+        format!("{}.{}", version, build)
+    }
+}
+
+impl ImageHeader {
+    // MCUboot hashes the header, the image and the protected TLVs
+    pub fn hashed_len(&self) -> usize {
+        self.hdr_size as usize + self.img_size as usize + self.protect_tlv_size as usize
+    }
+}
+
+// the hash which the device shows in the image list for this image
+pub fn image_hash(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let header = parse_header(data)?;
+    Ok(Sha256::digest(&data[..header.hashed_len()]).to_vec())
+}
+
+// a TLV from the trailer after the image, like the hash, signature or dependencies
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageTlv {
+    pub kind: u16,
+    // protected TLVs are included in the image hash
+    pub protected: bool,
+    pub value: Vec<u8>,
+}
+
+impl ImageTlv {
+    pub fn name(&self) -> &'static str {
+        match self.kind {
+            0x01 => "key hash",
+            0x02 => "public key",
+            0x10 => "SHA-256",
+            0x11 => "SHA-384",
+            0x12 => "SHA-512",
+            0x20 => "RSA-2048 signature",
+            0x21 => "ECDSA-224 signature",
+            0x22 => "ECDSA signature",
+            0x23 => "RSA-3072 signature",
+            0x24 => "ED25519 signature",
+            0x30 => "encrypted key RSA-2048",
+            0x31 => "encrypted key KW",
+            0x32 => "encrypted key EC256",
+            0x33 => "encrypted key X25519",
+            TLV_DEPENDENCY => "dependency",
+            TLV_SEC_CNT => "security counter",
+            0x60 => "boot record",
+            _ => "unknown",
+        }
+    }
+
+    // the image number and minimum version of a dependency TLV
+    pub fn dependency(&self) -> Option<(u8, String)> {
+        if self.kind != TLV_DEPENDENCY || self.value.len() != 12 {
+            return None;
+        }
+        Some((self.value[0], format_version(&self.value[4..])))
+    }
+}
+
+impl fmt::Display for ImageTlv {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "type 0x{:02x} ({}", self.kind, self.name())?;
+        if self.protected {
+            write!(f, ", protected")?;
+        }
+        write!(f, "): ")?;
+        if let Some((image, version)) = self.dependency() {
+            return write!(f, "image {} version >= {}", image, version);
+        }
+        match (self.kind, self.value.len()) {
+            (TLV_SEC_CNT, 4) => write!(f, "{}", LittleEndian::read_u32(&self.value)),
+            _ => write!(f, "{}", hex::encode(&self.value)),
+        }
+    }
+}
+
+// the TLVs after the image, first the protected ones
+pub fn image_tlvs(data: &[u8]) -> Result<Vec<ImageTlv>, Error> {
+    let header = parse_header(data)?;
+    let mut off = header.hdr_size as usize + header.img_size as usize;
+    let mut tlvs = Vec::new();
+    if header.protect_tlv_size > 0 {
+        off = read_tlv_area(data, off, TLV_PROT_INFO_MAGIC, true, &mut tlvs)?;
+    }
+    read_tlv_area(data, off, TLV_INFO_MAGIC, false, &mut tlvs)?;
+    Ok(tlvs)
+}
+
+// read the TLVs of an area which starts with an info header with the total length, returns the
+// offset after the area
+fn read_tlv_area(
+    data: &[u8],
+    start: usize,
+    magic: u16,
+    protected: bool,
+    tlvs: &mut Vec<ImageTlv>,
+) -> Result<usize, Error> {
+    if data.len() < start + 4 {
+        bail!("image truncated: no TLV info at offset {}", start);
+    }
+    if LittleEndian::read_u16(&data[start..]) != magic {
+        bail!(
+            "no TLV info at offset {}, magic is 0x{:04x} instead of 0x{:04x}",
+            start,
+            LittleEndian::read_u16(&data[start..]),
+            magic
+        );
+    }
+    let end = start + LittleEndian::read_u16(&data[start + 2..]) as usize;
+    if data.len() < end {
+        bail!(
+            "image truncated: {} bytes, but the TLVs end at {}",
+            data.len(),
+            end
+        );
+    }
+    let mut off = start + 4;
+    while off < end {
+        if end < off + 4 {
+            bail!("invalid TLV at offset {}", off);
+        }
+        let kind = LittleEndian::read_u16(&data[off..]);
+        let len = LittleEndian::read_u16(&data[off + 2..]) as usize;
+        if end < off + 4 + len {
+            bail!(
+                "TLV at offset {} with {} bytes exceeds the TLV area",
+                off,
+                len
+            );
+        }
+        tlvs.push(ImageTlv {
+            kind,
+            protected,
+            value: data[off + 4..off + 4 + len].to_vec(),
+        });
+        off += 4 + len;
+    }
+    Ok(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{image_hash, image_tlvs, is_mcuboot_image, parse_header};
+    use byteorder::{ByteOrder, LittleEndian};
+    use sha2::{Digest, Sha256};
+
+    // an image with a header of 0x20 bytes, 100 bytes of code and a SHA-256 TLV
+    fn test_image() -> Vec<u8> {
+        let mut image = vec![0; 0x20];
+        LittleEndian::write_u32(&mut image[0..], 0x96f3b83d);
+        LittleEndian::write_u32(&mut image[4..], 0);
+        LittleEndian::write_u16(&mut image[8..], 0x20);
+        LittleEndian::write_u16(&mut image[10..], 0);
+        LittleEndian::write_u32(&mut image[12..], 100);
+        image[20] = 1;
+        image[21] = 2;
+        LittleEndian::write_u16(&mut image[22..], 3);
+        image.extend((0..100).map(|i| i as u8));
+        let hash = Sha256::digest(&image).to_vec();
+        image.extend_from_slice(&[0x07, 0x69, 40, 0]);
+        image.extend_from_slice(&[0x10, 0, 32, 0]);
+        image.extend_from_slice(&hash);
+        image
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let image = test_image();
+        assert!(is_mcuboot_image(&image));
+        let header = parse_header(&image).unwrap();
+        assert_eq!(header.hdr_size, 0x20);
+        assert_eq!(header.img_size, 100);
+        assert_eq!(header.version, "1.2.3");
+
+        assert!(!is_mcuboot_image(b"<html>"));
+        assert!(parse_header(&[0x55; 64]).is_err());
+        assert!(parse_header(&image[..100]).is_err());
+    }
+
+    #[test]
+    fn test_image_tlvs() {
+        let image = test_image();
+        let tlvs = image_tlvs(&image).unwrap();
+        assert_eq!(tlvs.len(), 1);
+        assert_eq!(tlvs[0].kind, 0x10);
+        assert!(!tlvs[0].protected);
+        assert_eq!(tlvs[0].value, image[image.len() - 32..]);
+
+        // a protected security counter and dependency before the hash
+        let mut image = test_image();
+        image.truncate(0x20 + 100);
+        let mut protected = vec![0x08, 0x69, 28, 0];
+        protected.extend_from_slice(&[0x50, 0, 4, 0, 7, 0, 0, 0]);
+        protected.extend_from_slice(&[0x40, 0, 12, 0, 1, 0, 0, 0, 2, 3, 4, 0, 0, 0, 0, 0]);
+        LittleEndian::write_u16(&mut image[10..], protected.len() as u16);
+        image.extend_from_slice(&protected);
+        image.extend_from_slice(&[0x07, 0x69, 4, 0]);
+        let tlvs = image_tlvs(&image).unwrap();
+        assert_eq!(tlvs.len(), 2);
+        assert_eq!(
+            tlvs[0].to_string(),
+            "type 0x50 (security counter, protected): 7"
+        );
+        assert_eq!(
+            tlvs[1].to_string(),
+            "type 0x40 (dependency, protected): image 1 version >= 2.3.4"
+        );
+
+        assert!(image_tlvs(&image[..image.len() - 2]).is_err());
+    }
+
+    #[test]
+    fn test_image_hash() {
+        // the hash in the TLV, which the device reports
+        let image = test_image();
+        assert_eq!(image_hash(&image).unwrap(), image[image.len() - 32..]);
+    }
+}
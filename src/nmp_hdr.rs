@@ -1,10 +1,11 @@
 // Copyright © 2023-2024 Vouch.io LLC
 
-use hex_buffer_serde::{Hex as _, HexForm};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use num;
+use hex_buffer_serde::{Hex as _, HexForm};
 use num_derive::FromPrimitive;
 use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::collections::BTreeMap;
 use std::io::Cursor;
 
 #[repr(u8)]
@@ -26,28 +27,78 @@ pub enum NmpErr {
     EInvalid = 3,
     ETimeout = 4,
     ENoEnt = 5,
+    EBadState = 6,
+    EMsgSize = 7,
+    ENotSup = 8,
 }
 
-#[repr(u16)]
-#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
 pub enum NmpGroup {
-    Default = 0,
-    Image = 1,
-    Stat = 2,
-    Config = 3,
-    Log = 4,
-    Crash = 5,
-    Split = 6,
-    Run = 7,
-    Fs = 8,
-    Shell = 9,
-    PerUser = 64,
+    Default,
+    Image,
+    Stat,
+    Config,
+    Log,
+    Crash,
+    Split,
+    Run,
+    Fs,
+    Shell,
+    PerUser,
+    // a group without a name here, like a vendor specific group
+    Other(u16),
+}
+
+impl NmpGroup {
+    pub fn from_u16(group: u16) -> NmpGroup {
+        match group {
+            0 => NmpGroup::Default,
+            1 => NmpGroup::Image,
+            2 => NmpGroup::Stat,
+            3 => NmpGroup::Config,
+            4 => NmpGroup::Log,
+            5 => NmpGroup::Crash,
+            6 => NmpGroup::Split,
+            7 => NmpGroup::Run,
+            8 => NmpGroup::Fs,
+            9 => NmpGroup::Shell,
+            64 => NmpGroup::PerUser,
+            group => NmpGroup::Other(group),
+        }
+    }
+
+    pub fn to_u16(self) -> u16 {
+        match self {
+            NmpGroup::Default => 0,
+            NmpGroup::Image => 1,
+            NmpGroup::Stat => 2,
+            NmpGroup::Config => 3,
+            NmpGroup::Log => 4,
+            NmpGroup::Crash => 5,
+            NmpGroup::Split => 6,
+            NmpGroup::Run => 7,
+            NmpGroup::Fs => 8,
+            NmpGroup::Shell => 9,
+            NmpGroup::PerUser => 64,
+            NmpGroup::Other(group) => group,
+        }
+    }
 }
 
 pub trait NmpId {
     fn to_u8(&self) -> u8;
 }
 
+// a command id as a number, for commands without an enum here
+#[derive(Debug, Copy, Clone)]
+pub struct RawId(pub u8);
+
+impl NmpId for RawId {
+    fn to_u8(&self) -> u8 {
+        self.0
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
@@ -58,6 +109,9 @@ pub enum NmpIdDef {
     MpStat = 3,
     DateTimeStr = 4,
     Reset = 5,
+    McumgrParams = 6,
+    AppInfo = 7,
+    BootloaderInfo = 8,
 }
 
 impl NmpId for NmpIdDef {
@@ -75,6 +129,7 @@ pub enum NmpIdImage {
     CoreList = 3,
     CoreLoad = 4,
     Erase = 5,
+    SlotInfo = 6,
 }
 
 impl NmpId for NmpIdImage {
@@ -96,6 +151,15 @@ pub enum NmpIdStat {
 #[allow(dead_code)]
 pub enum NmpIdConfig {
     Val = 0,
+    Delete = 1,
+    Commit = 2,
+    LoadSave = 3,
+}
+
+impl NmpId for NmpIdConfig {
+    fn to_u8(&self) -> u8 {
+        *self as u8
+    }
 }
 
 #[repr(u8)]
@@ -110,6 +174,12 @@ pub enum NmpIdLog {
     List = 5,
 }
 
+impl NmpId for NmpIdLog {
+    fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
@@ -117,6 +187,25 @@ pub enum NmpIdCrash {
     Trigger = 0,
 }
 
+impl NmpId for NmpIdCrash {
+    fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
+pub enum NmpIdSplit {
+    Split = 0,
+}
+
+impl NmpId for NmpIdSplit {
+    fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
@@ -125,11 +214,24 @@ pub enum NmpIdRun {
     List = 1,
 }
 
+impl NmpId for NmpIdRun {
+    fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 #[allow(dead_code)]
 pub enum NmpIdFs {
     File = 0,
+    Status = 1,
+}
+
+impl NmpId for NmpIdFs {
+    fn to_u8(&self) -> u8 {
+        *self as u8
+    }
 }
 
 #[repr(u8)]
@@ -161,22 +263,26 @@ impl NmpHdr {
         }
     }
 
+    // the length and the group are sent in network byte order (big-endian)
     pub fn serialize(&self) -> Result<Vec<u8>, bincode::Error> {
         let mut buffer = Vec::new();
         buffer.write_u8(self.op as u8)?;
         buffer.write_u8(self.flags)?;
         buffer.write_u16::<BigEndian>(self.len)?;
-        buffer.write_u16::<BigEndian>(self.group as u16)?;
+        buffer.write_u16::<BigEndian>(self.group.to_u16())?;
         buffer.write_u8(self.seq)?;
         buffer.write_u8(self.id)?;
         Ok(buffer)
     }
 
+    // an unknown op is an error, unknown groups are kept as NmpGroup::Other
     pub fn deserialize(cursor: &mut Cursor<&Vec<u8>>) -> Result<NmpHdr, bincode::Error> {
-        let op = num::FromPrimitive::from_u8(cursor.read_u8()?).unwrap();
+        let op = cursor.read_u8()?;
+        let op = num::FromPrimitive::from_u8(op)
+            .ok_or_else(|| bincode::ErrorKind::Custom(format!("unknown op {}", op)))?;
         let flags = cursor.read_u8()?;
         let len = cursor.read_u16::<BigEndian>()?;
-        let group = num::FromPrimitive::from_u16(cursor.read_u16::<BigEndian>()?).unwrap();
+        let group = NmpGroup::from_u16(cursor.read_u16::<BigEndian>()?);
         let seq = cursor.read_u8()?;
         let id = cursor.read_u8()?;
         Ok(NmpHdr {
@@ -191,17 +297,43 @@ impl NmpHdr {
 }
 
 #[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[allow(dead_code)]
 pub struct NmpBase {
     pub hdr: NmpHdr,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, FromPrimitive, Deserialize, Serialize)]
+// sent as an integer
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, FromPrimitive, Deserialize_repr, Serialize_repr)]
 pub enum SplitStatus {
     NotApplicable = 0,
     NotMatching = 1,
     Matching = 2,
 }
 
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, FromPrimitive, Deserialize_repr, Serialize_repr)]
+pub enum SplitMode {
+    Loader = 0,
+    App = 1,
+    TestLoader = 2,
+    TestApp = 3,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SplitReq {
+    #[serde(rename = "splitMode")]
+    pub split_mode: SplitMode,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SplitRsp {
+    #[serde(rename = "splitMode", default, skip_serializing_if = "Option::is_none")]
+    pub split_mode: Option<SplitMode>,
+    #[serde(rename = "splitStatus")]
+    pub split_status: SplitStatus,
+}
+
 fn default_0() -> u32 {
     0
 }
@@ -234,11 +366,112 @@ pub struct ImageStateEntry {
     pub permanent: bool,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct McumgrParamsRsp {
+    pub buf_size: u32,
+    pub buf_count: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CrashTriggerReq {
+    #[serde(rename = "t")]
+    pub crash_type: String,
+}
+
+// Zephyr reads boot_mode 1 as a request to stay in the bootloader after the reset, when built
+// with CONFIG_MCUMGR_GRP_OS_RESET_BOOT_MODE
+pub const BOOT_MODE_BOOTLOADER: u8 = 1;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ResetReq {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_mode: Option<u8>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AppInfoReq {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AppInfoRsp {
+    pub output: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ConfigValReq {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub val: Option<serde_cbor::Value>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ConfigValRsp {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rc: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub val: Option<serde_cbor::Value>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FsStatusReq {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FsStatusRsp {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rc: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub len: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BootloaderInfoReq {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BootloaderInfoRsp {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rc: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bootloader: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<i32>,
+    #[serde(
+        rename = "no-downgrade",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub no_downgrade: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EchoReq {
+    #[serde(rename = "d")]
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EchoRsp {
+    #[serde(rename = "r")]
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ConsEchoCtrlReq {
+    // 1 to enable the echo of the console, 0 to disable it
+    pub echo: u32,
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ImageStateReq {
-    #[serde(with = "serde_bytes")]
-    pub hash: Vec<u8>,
+    // when missing, the state of the running image is changed
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub hash: Option<Vec<u8>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub confirm: Option<bool>,
 }
@@ -250,6 +483,27 @@ pub struct ImageStateRsp {
     pub split_status: Option<SplitStatus>,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SlotInfoSlot {
+    pub slot: u32,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_image_id: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SlotInfoImage {
+    pub image: u32,
+    pub slots: Vec<SlotInfoSlot>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_image_size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SlotInfoRsp {
+    pub images: Vec<SlotInfoImage>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ImageUploadReq {
     #[serde(rename = "data", with = "serde_bytes")]
@@ -276,3 +530,136 @@ pub struct ImageEraseReq {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub slot: Option<u32>,
 }
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ImageEraseRsp {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rc: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ImageUploadRsp {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rc: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub off: Option<u32>,
+    // sent by some firmware with the last chunk, if the SHA-256 of the image matched
+    #[serde(rename = "match", default, skip_serializing_if = "Option::is_none")]
+    pub matched: Option<bool>,
+    // sent by some firmware with the last chunk, the SHA-256 of the received image
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub sha: Option<Vec<u8>>,
+}
+
+// some firmware answers with the new image states, like for a list request
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ImageStateWriteRsp {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rc: Option<u32>,
+    #[serde(default)]
+    pub images: Vec<ImageStateEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RunTestReq {
+    #[serde(rename = "testname", skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+// the answer to a test run, the firmware might add the results of the tests to the rc, like
+// Mynewt, which writes them to its test log
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RunTestRsp {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rc: Option<u32>,
+    #[serde(flatten)]
+    pub results: BTreeMap<String, serde_cbor::Value>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RunListRsp {
+    #[serde(rename = "run_list", default)]
+    pub tests: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogShowReq {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_name: Option<String>,
+    #[serde(rename = "ts")]
+    pub timestamp: i64,
+    pub index: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogEntry {
+    // text, or bytes with a type field, depending on the firmware
+    pub msg: serde_cbor::Value,
+    #[serde(rename = "ts")]
+    pub timestamp: i64,
+    #[serde(default)]
+    pub level: u8,
+    pub index: u32,
+    #[serde(default)]
+    pub module: u8,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogShowLog {
+    pub name: String,
+    #[serde(default)]
+    pub entries: Vec<LogEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogShowRsp {
+    #[serde(default)]
+    pub next_index: u32,
+    #[serde(default)]
+    pub logs: Vec<LogShowLog>,
+}
+
+// the numbers of the log modules by name
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogModuleListRsp {
+    #[serde(default)]
+    pub module_map: BTreeMap<String, u32>,
+}
+
+// the numbers of the log levels by name, as used in the log entries
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogLevelListRsp {
+    #[serde(default)]
+    pub level_map: BTreeMap<String, u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NmpGroup, NmpHdr, NmpIdDef, NmpOp};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = NmpHdr::new_req(NmpOp::Read, NmpGroup::PerUser, NmpIdDef::Reset);
+        let data = header.serialize().unwrap();
+        assert_eq!(data[4..6], [0, 64]);
+        let decoded = NmpHdr::deserialize(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(decoded.group, NmpGroup::PerUser);
+        assert_eq!(decoded.id, NmpIdDef::Reset as u8);
+    }
+
+    #[test]
+    fn test_header_unknown_group() {
+        let data = vec![1, 0, 0, 0, 0, 100, 0, 0];
+        let decoded = NmpHdr::deserialize(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(decoded.group, NmpGroup::Other(100));
+        assert_eq!(decoded.serialize().unwrap(), data);
+        assert_eq!(NmpGroup::from_u16(1), NmpGroup::Image);
+
+        let data = vec![9, 0, 0, 0, 0, 1, 0, 0];
+        let e = NmpHdr::deserialize(&mut Cursor::new(&data)).unwrap_err();
+        assert_eq!(e.to_string(), "unknown op 9");
+    }
+}
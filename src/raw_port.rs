@@ -0,0 +1,251 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use byteorder::{BigEndian, ByteOrder};
+use serialport::SerialPort;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use crate::transfer::{decode_base64, decode_packet, encode_frame, frame_base64, Crc, HEADER_LEN};
+
+// serial port for the raw SMP transport, which sends the SMP packets as they are, without
+// base64, line markers, length and checksum. The rest of the code uses the frames of the console
+// transport, so requests are converted to packets when writing, and the packets of responses
+// are converted to frames when reading.
+pub struct RawPort {
+    inner: Box<dyn SerialPort>,
+    crc: Crc,
+    // the written part of the current request frame
+    request: Vec<u8>,
+    // the frame of the last response, and how much of it was read
+    response: Vec<u8>,
+    position: usize,
+}
+
+impl RawPort {
+    pub fn new(inner: Box<dyn SerialPort>, crc: Crc) -> RawPort {
+        RawPort {
+            inner,
+            crc,
+            request: Vec::new(),
+            response: Vec::new(),
+            position: 0,
+        }
+    }
+
+    // the SMP packet of the request frame, None if it isn't complete yet
+    fn request_packet(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        if !self.request.ends_with(b"\n") {
+            return Ok(None);
+        }
+        let decoded = decode_base64(&frame_base64(&self.request)?)?;
+        if decoded.len() < 2 || (BigEndian::read_u16(&decoded) as usize) > decoded.len() - 2 {
+            return Ok(None);
+        }
+        Ok(Some(decode_packet(self.crc, &decoded)?))
+    }
+
+    // read the next packet and frame it like a response of the console transport
+    fn read_response(&mut self) -> std::io::Result<()> {
+        let mut packet = vec![0; HEADER_LEN];
+        self.inner.read_exact(&mut packet)?;
+        let len = BigEndian::read_u16(&packet[2..]) as usize;
+        packet.resize(HEADER_LEN + len, 0);
+        self.inner.read_exact(&mut packet[HEADER_LEN..])?;
+        self.response = encode_frame(4096, self.crc, packet).map_err(std::io::Error::other)?;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl Read for RawPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.response.len() {
+            self.read_response()?;
+        }
+        let n = std::cmp::min(buf.len(), self.response.len() - self.position);
+        buf[..n].copy_from_slice(&self.response[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl Write for RawPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.request.extend_from_slice(buf);
+        let packet = self.request_packet().map_err(|e| {
+            self.request.clear();
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+        })?;
+        if let Some(packet) = packet {
+            self.request.clear();
+            self.inner.write_all(&packet)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl SerialPort for RawPort {
+    fn name(&self) -> Option<String> {
+        self.inner.name()
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        self.inner.baud_rate()
+    }
+
+    fn data_bits(&self) -> serialport::Result<serialport::DataBits> {
+        self.inner.data_bits()
+    }
+
+    fn flow_control(&self) -> serialport::Result<serialport::FlowControl> {
+        self.inner.flow_control()
+    }
+
+    fn parity(&self) -> serialport::Result<serialport::Parity> {
+        self.inner.parity()
+    }
+
+    fn stop_bits(&self) -> serialport::Result<serialport::StopBits> {
+        self.inner.stop_bits()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.inner.set_baud_rate(baud_rate)
+    }
+
+    fn set_data_bits(&mut self, data_bits: serialport::DataBits) -> serialport::Result<()> {
+        self.inner.set_data_bits(data_bits)
+    }
+
+    fn set_flow_control(
+        &mut self,
+        flow_control: serialport::FlowControl,
+    ) -> serialport::Result<()> {
+        self.inner.set_flow_control(flow_control)
+    }
+
+    fn set_parity(&mut self, parity: serialport::Parity) -> serialport::Result<()> {
+        self.inner.set_parity(parity)
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: serialport::StopBits) -> serialport::Result<()> {
+        self.inner.set_stop_bits(stop_bits)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.inner.write_request_to_send(level)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.inner.write_data_terminal_ready(level)
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        self.inner.read_clear_to_send()
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        self.inner.read_data_set_ready()
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        self.inner.read_ring_indicator()
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        self.inner.read_carrier_detect()
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.inner.bytes_to_read()? + (self.response.len() - self.position) as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        self.inner.bytes_to_write()
+    }
+
+    fn clear(&self, buffer_to_clear: serialport::ClearBuffer) -> serialport::Result<()> {
+        self.inner.clear(buffer_to_clear)
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        // a clone can't share the state of the framing
+        Err(serialport::Error::new(
+            serialport::ErrorKind::Unknown,
+            "cannot clone a raw port",
+        ))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        self.inner.set_break()
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        self.inner.clear_break()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RawPort;
+    use crate::nmp_hdr::*;
+    use crate::test_serial_port::TestSerialPort;
+    use crate::transfer::{encode_request, receive_response, Crc};
+    use serialport::SerialPort;
+    use std::io::Write;
+
+    #[test]
+    fn test_raw_port() {
+        let body = serde_cbor::to_vec(&EchoReq {
+            text: "hello".to_string(),
+        })
+        .unwrap();
+        let (frame, header) = encode_request(
+            20,
+            Crc::Xmodem,
+            NmpOp::Write,
+            NmpGroup::Default,
+            NmpIdDef::Echo,
+            &body,
+            42,
+        )
+        .unwrap();
+        let mut packet = header.serialize().unwrap();
+        packet.extend_from_slice(&body);
+
+        // the frame is written in parts, the packet is sent when it is complete
+        let mut port = RawPort::new(Box::new(TestSerialPort::new()), Crc::Xmodem);
+        let (first, rest) = frame.split_at(frame.len() / 2);
+        port.write_all(first).unwrap();
+        assert_eq!(port.request_packet().unwrap(), None);
+        port.request.extend_from_slice(rest);
+        assert_eq!(port.request_packet().unwrap(), Some(packet.clone()));
+
+        // a response packet is read as a frame
+        let mut inner = TestSerialPort::new();
+        inner.queue_input(&packet);
+        let mut port = RawPort::new(Box::new(inner), Crc::Xmodem);
+        let (response_header, response_body) =
+            receive_response(&mut port, Crc::Xmodem, 42).unwrap();
+        assert_eq!(response_header.id, NmpIdDef::Echo as u8);
+        assert_eq!(response_body, serde_cbor::from_slice(&body).unwrap());
+    }
+
+    #[test]
+    fn test_raw_port_clone() {
+        let port = RawPort::new(Box::new(TestSerialPort::new()), Crc::Xmodem);
+        assert!(port.try_clone().is_err());
+    }
+}
@@ -0,0 +1,78 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Error, Result};
+use log::info;
+
+use crate::nmp_hdr::*;
+use crate::transfer::check_rc;
+use crate::transfer::send_request;
+use crate::transfer::SerialSpecs;
+
+// run a test suite on the device, or all test suites, if no name is given
+pub fn run_test(
+    specs: &SerialSpecs,
+    name: Option<String>,
+    token: Option<String>,
+) -> Result<RunTestRsp, Error> {
+    info!("run test request");
+    let body = serde_cbor::to_vec(&RunTestReq { name, token })?;
+    let response_body = send_request(specs, NmpOp::Write, NmpGroup::Run, NmpIdRun::Test, &body)?;
+    check_rc(&response_body)?;
+
+    let ans: RunTestRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+
+    Ok(ans)
+}
+
+pub fn run_list(specs: &SerialSpecs) -> Result<Vec<String>, Error> {
+    info!("run list request");
+    let body: Vec<u8> =
+        serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new()).unwrap();
+    let response_body = send_request(specs, NmpOp::Read, NmpGroup::Run, NmpIdRun::List, &body)?;
+    check_rc(&response_body)?;
+
+    let ans: RunListRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+
+    Ok(ans.tests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_list, run_test};
+    use crate::test_serial_port::scenario_specs;
+    use crate::transfer::{device_rc, SerialSpecs};
+
+    fn test_specs() -> SerialSpecs {
+        SerialSpecs {
+            device: "test".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_run_test() {
+        let specs = test_specs();
+        for name in run_list(&specs).unwrap() {
+            let ans = run_test(&specs, Some(name), None).unwrap();
+            assert_eq!(ans.rc, Some(0));
+        }
+        assert_eq!(run_test(&specs, None, None).unwrap().rc, Some(0));
+        let e = run_test(&specs, Some("missing".to_string()), None).unwrap_err();
+        assert_eq!(device_rc(&e), Some(5));
+    }
+
+    #[test]
+    fn test_run_test_results() {
+        // other fields of the answer are kept as the results
+        let (specs, _scenario) =
+            scenario_specs(r#"[{"group": 7, "id": 0, "response": {"rc": 0, "passed": 3}}]"#);
+        let ans = run_test(&specs, None, None).unwrap();
+        assert_eq!(ans.rc, Some(0));
+        assert_eq!(
+            ans.results.get("passed"),
+            Some(&serde_cbor::Value::Integer(3))
+        );
+    }
+}
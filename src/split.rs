@@ -0,0 +1,44 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Error, Result};
+use log::info;
+
+use crate::nmp_hdr::*;
+use crate::transfer::check_rc;
+use crate::transfer::send_request;
+use crate::transfer::SerialSpecs;
+
+pub fn split_status(specs: &SerialSpecs) -> Result<SplitStatus, Error> {
+    info!("split status request");
+    let body: Vec<u8> =
+        serde_cbor::to_vec(&std::collections::BTreeMap::<String, String>::new()).unwrap();
+    let response_body = send_request(
+        specs,
+        NmpOp::Read,
+        NmpGroup::Split,
+        NmpIdSplit::Split,
+        &body,
+    )?;
+    check_rc(&response_body)?;
+
+    let ans: SplitRsp = serde_cbor::value::from_value(response_body)
+        .map_err(|e| anyhow::format_err!("unexpected answer from device | {}", e))?;
+
+    Ok(ans.split_status)
+}
+
+// boot the split application permanently after the next reset, instead of only the loader
+pub fn split_apply(specs: &SerialSpecs) -> Result<(), Error> {
+    info!("split apply request");
+    let body = serde_cbor::to_vec(&SplitReq {
+        split_mode: SplitMode::App,
+    })?;
+    let response_body = send_request(
+        specs,
+        NmpOp::Write,
+        NmpGroup::Split,
+        NmpIdSplit::Split,
+        &body,
+    )?;
+    check_rc(&response_body)
+}
@@ -1,28 +1,198 @@
 // Copyright © 2023-2024 Vouch.io LLC
 
+use anyhow::{Context, Error};
 use base64::engine::{general_purpose::STANDARD, Engine};
 use byteorder::{BigEndian, ByteOrder};
-use crc16::State;
-use crc16::XMODEM;
-use hex;
+use serde::Deserialize;
 use serialport::DataBits;
 use serialport::FlowControl;
 use serialport::Parity;
 use serialport::SerialPort;
 use serialport::StopBits;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::io::Cursor;
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
 use crate::nmp_hdr::*;
 use crate::transfer::encode_request;
+use crate::transfer::Crc;
+#[cfg(test)]
+use crate::transfer::SerialSpecs;
+use crate::transfer::HEADER_LEN;
+use crate::transport_log::{read_transport_log, Direction, TransportLogEntry};
+
+type ReplayEntries = Arc<Mutex<VecDeque<TransportLogEntry>>>;
+
+thread_local! {
+    // the remaining entries of the replayed transport log, shared by the ports of the session
+    static REPLAY: RefCell<Option<(PathBuf, ReplayEntries)>> = const { RefCell::new(None) };
+}
+
+#[cfg(test)]
+thread_local! {
+    // the requests received by the test ports of the thread, for checking them in the tests
+    static REQUESTS: RefCell<Vec<(NmpHdr, serde_cbor::Value)>> = const { RefCell::new(Vec::new()) };
+}
+
+// the requests received by the test ports of the thread since the last call
+#[cfg(test)]
+pub fn take_requests() -> Vec<(NmpHdr, serde_cbor::Value)> {
+    REQUESTS.with(|requests| requests.take())
+}
+
+#[cfg(test)]
+thread_local! {
+    static DEVICE_BAUDRATE: std::cell::Cell<u32> = const { std::cell::Cell::new(115_200) };
+}
+
+// the baudrate of the device simulated by the test ports of the thread
+#[cfg(test)]
+pub fn set_device_baudrate(baudrate: u32) {
+    DEVICE_BAUDRATE.with(|device_baudrate| device_baudrate.set(baudrate));
+}
+
+#[cfg(test)]
+fn device_baudrate() -> u32 {
+    DEVICE_BAUDRATE.with(std::cell::Cell::get)
+}
+
+#[cfg(not(test))]
+fn device_baudrate() -> u32 {
+    115_200
+}
+
+// a scenario file of a test, which is removed when it is dropped, also when the test fails
+#[cfg(test)]
+pub struct TestScenario {
+    pub path: PathBuf,
+}
+
+#[cfg(test)]
+impl TestScenario {
+    pub fn new(json: &str) -> TestScenario {
+        static COUNTER: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+        let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!(
+            "mcumgr-client-test-{}-scenario-{}.json",
+            std::process::id(),
+            n
+        ));
+        std::fs::write(&path, json).unwrap();
+        TestScenario { path }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestScenario {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// the specs of the test device with the scripted responses of the JSON scenario, which are
+// available until the scenario is dropped
+#[cfg(test)]
+pub fn scenario_specs(json: &str) -> (SerialSpecs, TestScenario) {
+    let scenario = TestScenario::new(json);
+    let specs = SerialSpecs {
+        device: "test".to_string(),
+        test_scenario: Some(scenario.path.clone()),
+        ..Default::default()
+    };
+    (specs, scenario)
+}
+
+// a scripted response of a scenario file, for the requests with this group, id and op
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScenarioEntry {
+    pub group: u16,
+    pub id: u8,
+    // "read" or "write", or both, if missing
+    #[serde(default)]
+    pub op: Option<String>,
+    // converted to the CBOR body of the response
+    #[serde(default)]
+    pub response: Option<serde_json::Value>,
+    // the raw CBOR body of the response in hex, which can be invalid CBOR
+    #[serde(default, deserialize_with = "deserialize_hex")]
+    pub response_hex: Option<Vec<u8>>,
+    // leave the request unanswered
+    #[serde(default)]
+    pub drop: bool,
+}
+
+// a hex typo fails when the scenario is loaded, not when the response is sent
+fn deserialize_hex<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let hex: Option<String> = Option::deserialize(deserializer)?;
+    hex.map(|hex| {
+        hex::decode(&hex)
+            .map_err(|e| serde::de::Error::custom(format!("invalid response_hex {}: {}", hex, e)))
+    })
+    .transpose()
+}
+
+impl ScenarioEntry {
+    fn matches(&self, header: &NmpHdr) -> bool {
+        let op_matches = match self.op.as_deref() {
+            Some("read") => header.op == NmpOp::Read,
+            Some("write") => header.op == NmpOp::Write,
+            _ => true,
+        };
+        header.group.to_u16() == self.group && header.id == self.id && op_matches
+    }
+}
 
 pub struct TestSerialPort {
     data: Vec<u8>,
     position: usize,
     total_len: u32,
+    upload_base: u32,
     images: Vec<ImageStateEntry>,
+    // number of requests to leave unanswered, for simulating timeouts
+    pub skip_responses: u32,
+    pub crc: Crc,
+    // requests at another baudrate than the one of the device aren't answered
+    pub baud_rate: u32,
+    // reject upload requests with a sha field, like some old MCUboot versions
+    pub reject_sha: bool,
+    // added to the upload offset, like a device which accepted more than the chunk
+    pub extra_offset: u32,
+    // reported by bytes_to_read, without any data being available
+    pub stale_bytes_to_read: u32,
+    // the image data received with upload requests
+    pub uploaded: Vec<u8>,
+    // result code of erase requests, like for an already erased slot
+    pub erase_rc: u32,
+    // longer requests are dropped, like by a device with a small receive buffer
+    pub max_request_len: usize,
+    // send the hash of the received image with the last upload answer
+    pub report_sha: bool,
+    // send if the hash matched with the last upload answer, like MCUboot
+    pub report_match: bool,
+    // change the received image, for a hash mismatch
+    pub corrupt_upload: bool,
+    // reject upload requests without the len field, like firmware which needs it in every chunk
+    pub require_len: bool,
+    // time to erase the slot with the first upload request, its answer is lost with a shorter
+    // timeout, like from a device which answers too late
+    pub erase_time: Duration,
+    // number of requests before the device disconnects, like a bootloader which resets
+    pub disconnect_after: Option<u32>,
+    // scripted responses, used in order for each matching request, the last one repeatedly
+    pub scenario: Vec<ScenarioEntry>,
+    // the answers of a transport log, sent after the requests which were sent before them
+    replay: Option<ReplayEntries>,
+    // only reported, reads without data time out immediately
+    timeout: Duration,
 }
 
 impl TestSerialPort {
@@ -31,6 +201,7 @@ impl TestSerialPort {
             data: Vec::new(),
             position: 0,
             total_len: 0,
+            upload_base: 0,
             images: vec![ImageStateEntry {
                 image: 1,
                 slot: 0,
@@ -45,12 +216,136 @@ impl TestSerialPort {
                 active: true,
                 permanent: false,
             }],
+            skip_responses: 0,
+            baud_rate: 115_200,
+            require_len: false,
+            erase_time: Duration::ZERO,
+            disconnect_after: None,
+            timeout: Duration::from_secs(1),
+            crc: Crc::Xmodem,
+            reject_sha: false,
+            extra_offset: 0,
+            stale_bytes_to_read: 0,
+            uploaded: Vec::new(),
+            erase_rc: 0,
+            max_request_len: usize::MAX,
+            report_sha: false,
+            report_match: false,
+            corrupt_upload: false,
+            scenario: Vec::new(),
+            replay: None,
+        }
+    }
+
+    // load the scripted responses from a JSON file with a list of scenario entries, like
+    // [{"group": 1, "id": 1, "response": {"rc": 3}}]. Requests without a scripted response
+    // are answered like without a scenario.
+    pub fn from_scenario(path: &Path) -> Result<TestSerialPort, Error> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("failed to read scenario {}", path.to_string_lossy()))?;
+        let scenario: Vec<ScenarioEntry> = serde_json::from_slice(&data)
+            .with_context(|| format!("invalid scenario {}", path.to_string_lossy()))?;
+        for entry in &scenario {
+            log::debug!("scenario entry: {:?}", entry);
+        }
+        let mut port = TestSerialPort::new();
+        port.scenario = scenario;
+        Ok(port)
+    }
+
+    // replay the bytes received in a session which was recorded with a transport log, for
+    // reproducing its errors without the device
+    pub fn from_transport_log(path: &Path) -> Result<TestSerialPort, Error> {
+        let replay = REPLAY.with(|replay| -> Result<ReplayEntries, Error> {
+            let mut replay = replay.borrow_mut();
+            if !matches!(&*replay, Some((replayed, _)) if replayed == path) {
+                let entries = read_transport_log(path)?;
+                log::debug!("replaying {} transport log entries", entries.len());
+                *replay = Some((path.to_path_buf(), Arc::new(Mutex::new(entries.into()))));
+            }
+            Ok(replay.as_ref().unwrap().1.clone())
+        })?;
+        let mut port = TestSerialPort::new();
+        port.replay = Some(replay);
+        Ok(port)
+    }
+
+    // consume the recorded bytes of the request, and queue the bytes received before and after
+    // it as the answer
+    fn replay_write(&mut self, replay: &Mutex<VecDeque<TransportLogEntry>>, buf: &[u8]) {
+        let mut entries = replay.lock().unwrap();
+        self.replay_received(&mut entries);
+        let mut written = buf;
+        while !written.is_empty() {
+            match entries.front_mut() {
+                Some(entry) if entry.dir == Direction::Tx => {
+                    let n = written.len().min(entry.data.len());
+                    if entry.data[..n] != written[..n] {
+                        log::warn!("the request differs from the transport log");
+                    }
+                    entry.data.drain(..n);
+                    written = &written[n..];
+                    if entry.data.is_empty() {
+                        entries.pop_front();
+                    }
+                }
+                _ => {
+                    log::warn!("more bytes sent than in the transport log");
+                    break;
+                }
+            }
         }
+        self.replay_received(&mut entries);
+    }
+
+    fn replay_received(&mut self, entries: &mut VecDeque<TransportLogEntry>) {
+        while entries
+            .front()
+            .is_some_and(|entry| entry.dir == Direction::Rx)
+        {
+            let entry = entries.pop_front().unwrap();
+            self.data.extend_from_slice(&entry.data);
+        }
+    }
+
+    // add bytes to the input, like a previous response which is still arriving
+    #[cfg(test)]
+    pub fn queue_input(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    // the next scripted response body for the request, None for no response
+    fn scripted_response(&mut self, header: &NmpHdr) -> Option<Option<Vec<u8>>> {
+        let matching: Vec<usize> = (0..self.scenario.len())
+            .filter(|i| self.scenario[*i].matches(header))
+            .collect();
+        let index = *matching.first()?;
+        let entry = if matching.len() > 1 {
+            self.scenario.remove(index)
+        } else {
+            self.scenario[index].clone()
+        };
+        if entry.drop {
+            return Some(None);
+        }
+        let body = match (&entry.response_hex, &entry.response) {
+            (Some(body), _) => body.clone(),
+            (None, Some(response)) => serde_cbor::to_vec(response).unwrap(),
+            (None, None) => serde_cbor::to_vec(&serde_json::json!({})).unwrap(),
+        };
+        Some(Some(body))
     }
 }
 
 impl Read for TestSerialPort {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // like a real serial port, time out when there is nothing to read
+        if self.position >= self.data.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Operation timed out",
+            ));
+        }
         let available_data = &self.data[self.position..];
         let bytes_to_read = std::cmp::min(available_data.len(), buf.len());
         buf[..bytes_to_read].copy_from_slice(&available_data[..bytes_to_read]);
@@ -61,6 +356,26 @@ impl Read for TestSerialPort {
 
 impl Write for TestSerialPort {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(replay) = self.replay.clone() {
+            self.replay_write(&replay, buf);
+            return Ok(buf.len());
+        }
+
+        match self.disconnect_after {
+            Some(0) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "device disconnected",
+                ))
+            }
+            Some(n) => self.disconnect_after = Some(n - 1),
+            None => {}
+        }
+
+        if buf.len() > self.max_request_len {
+            return Ok(buf.len());
+        }
+
         let mut cursor = Cursor::new(buf);
         let mut received_data = Vec::new();
 
@@ -80,7 +395,7 @@ impl Write for TestSerialPort {
 
         let data = received_data[2..received_data.len() - 2].to_vec();
         let read_checksum = BigEndian::read_u16(&received_data[received_data.len() - 2..]);
-        let calculated_checksum = State::<XMODEM>::calculate(&data);
+        let calculated_checksum = self.crc.calculate(&data);
         if read_checksum != calculated_checksum {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
@@ -90,11 +405,51 @@ impl Write for TestSerialPort {
 
         let mut request_cursor = Cursor::new(&data);
         let request_header = NmpHdr::deserialize(&mut request_cursor).unwrap();
+        // an empty body, like of a reset request, is recorded as null
+        #[cfg(test)]
+        {
+            let body = match &data[HEADER_LEN..] {
+                [] => Ok(serde_cbor::Value::Null),
+                body => serde_cbor::from_slice(body),
+            };
+            if let Ok(body) = body {
+                REQUESTS.with(|requests| requests.borrow_mut().push((request_header, body)));
+            }
+        }
+
+        if self.skip_responses > 0 {
+            self.skip_responses -= 1;
+            return Ok(buf.len());
+        }
+        if self.baud_rate != device_baudrate() {
+            return Ok(buf.len());
+        }
         // let header_len: usize = 8;
         // let request_body = data[header_len..].to_vec();
 
+        if let Some(response) = self.scripted_response(&request_header) {
+            if let Some(body) = response {
+                let op = match request_header.op {
+                    NmpOp::Read => NmpOp::ReadRsp,
+                    _ => NmpOp::WriteRsp,
+                };
+                let (encoded_response, _) = encode_request(
+                    4096,
+                    self.crc,
+                    op,
+                    request_header.group,
+                    RawId(request_header.id),
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            return Ok(buf.len());
+        }
+
         match request_header.id {
-            id if id == NmpIdImage::State as u8 => {
+            id if id == NmpIdImage::State as u8 && request_header.group == NmpGroup::Image => {
                 if request_header.op == NmpOp::Read {
                     let state_response = ImageStateRsp {
                         images: self.images.clone(),
@@ -103,6 +458,7 @@ impl Write for TestSerialPort {
                     let body = serde_cbor::to_vec(&state_response).unwrap();
                     let (encoded_response, _) = encode_request(
                         100,
+                        self.crc,
                         NmpOp::ReadRsp,
                         NmpGroup::Image,
                         NmpIdImage::State,
@@ -113,12 +469,17 @@ impl Write for TestSerialPort {
                     self.data.extend_from_slice(&encoded_response);
                 } else if request_header.op == NmpOp::Write {
                     // let request: ImageStateReq = serde_cbor::from_slice(request_body.as_slice()).unwrap();
-                    let body = serde_cbor::to_vec(&serde_cbor::Value::Null).unwrap();
+                    let state_response = ImageStateWriteRsp {
+                        rc: Some(0),
+                        images: self.images.clone(),
+                    };
+                    let body = serde_cbor::to_vec(&state_response).unwrap();
                     let (encoded_response, _) = encode_request(
                         100,
+                        self.crc,
                         NmpOp::WriteRsp,
                         NmpGroup::Image,
-                        NmpIdImage::Erase,
+                        NmpIdImage::State,
                         &body,
                         request_header.seq,
                     )
@@ -126,41 +487,101 @@ impl Write for TestSerialPort {
                     self.data.extend_from_slice(&encoded_response);
                 }
             }
-            id if id == NmpIdImage::Upload as u8 => {
+            id if id == NmpIdImage::Upload as u8 && request_header.group == NmpGroup::Image => {
                 let body_start = request_cursor.position() as usize;
                 let body_end = data.len();
                 let body = &data[body_start..body_end];
 
                 let image_upload_req: ImageUploadReq = serde_cbor::from_slice(body).unwrap();
-                if image_upload_req.off == 0 {
-                    self.total_len = image_upload_req.len.unwrap();
+                let first = image_upload_req.len.is_some()
+                    && (self.total_len == 0 || image_upload_req.off == self.upload_base);
+                if first && self.timeout < self.erase_time {
+                    return Ok(buf.len());
+                }
+                // the first request has the length, and the start offset for raw uploads
+                if let Some(len) = image_upload_req.len {
+                    if self.total_len == 0 || image_upload_req.off == self.upload_base {
+                        self.total_len = len;
+                        self.upload_base = image_upload_req.off;
+                    }
+                } else if self.require_len {
+                    let body = serde_cbor::to_vec(&ImageUploadRsp {
+                        rc: Some(NmpErr::EInvalid as u32),
+                        off: None,
+                        matched: None,
+                        sha: None,
+                    })
+                    .unwrap();
+                    let (encoded_response, _) = encode_request(
+                        4096,
+                        self.crc,
+                        NmpOp::WriteRsp,
+                        NmpGroup::Image,
+                        NmpIdImage::Upload,
+                        &body,
+                        request_header.seq,
+                    )
+                    .unwrap();
+                    self.data.extend_from_slice(&encoded_response);
+                    return Ok(buf.len());
                 }
-                let mut off_value = image_upload_req.off + data.len() as u32;
-                if off_value > self.total_len {
-                    off_value = self.total_len;
+                self.uploaded
+                    .truncate((image_upload_req.off - self.upload_base) as usize);
+                self.uploaded.extend_from_slice(&image_upload_req.data);
+                let mut off_value = image_upload_req.off + image_upload_req.data.len() as u32;
+                if off_value > self.upload_base + self.total_len {
+                    off_value = self.upload_base + self.total_len;
                 }
 
-                let mut response_map = std::collections::BTreeMap::new();
-                response_map.insert("rc", 0);
-                response_map.insert("off", off_value);
+                let response = if self.reject_sha && image_upload_req.data_sha.is_some() {
+                    ImageUploadRsp {
+                        rc: Some(NmpErr::EInvalid as u32),
+                        off: None,
+                        matched: None,
+                        sha: None,
+                    }
+                } else {
+                    // with the last chunk, report the hash of the received image
+                    let done = off_value == self.upload_base + self.total_len;
+                    let sha = if done && self.report_sha {
+                        let mut image = self.uploaded.clone();
+                        if self.corrupt_upload {
+                            image[0] ^= 0xff;
+                        }
+                        Some(Sha256::digest(&image).to_vec())
+                    } else {
+                        None
+                    };
+                    let matched = (done && self.report_match).then_some(!self.corrupt_upload);
+                    ImageUploadRsp {
+                        rc: Some(0),
+                        off: Some(off_value + self.extra_offset),
+                        matched,
+                        sha,
+                    }
+                };
 
-                let cbor_body = serde_cbor::to_vec(&response_map).unwrap();
+                let cbor_body = serde_cbor::to_vec(&response).unwrap();
                 let (encoded_response, _) = encode_request(
                     4096,
+                    self.crc,
                     NmpOp::WriteRsp,
                     NmpGroup::Image,
-                    NmpIdImage::State,
+                    NmpIdImage::Upload,
                     &cbor_body,
                     request_header.seq,
                 )
                 .unwrap();
                 self.data.extend_from_slice(&encoded_response);
             }
-            id if id == NmpIdImage::Erase as u8 => {
+            id if id == NmpIdImage::Erase as u8 && request_header.group == NmpGroup::Image => {
                 // let request: ImageEraseReq = serde_cbor::from_slice(request_body.as_slice()).unwrap();
-                let body = serde_cbor::to_vec(&serde_cbor::Value::Null).unwrap();
+                let mut response_map = std::collections::BTreeMap::new();
+                response_map.insert("rc", self.erase_rc);
+                let body = serde_cbor::to_vec(&response_map).unwrap();
                 let (encoded_response, _) = encode_request(
                     100,
+                    self.crc,
                     NmpOp::WriteRsp,
                     NmpGroup::Image,
                     NmpIdImage::Erase,
@@ -170,6 +591,340 @@ impl Write for TestSerialPort {
                 .unwrap();
                 self.data.extend_from_slice(&encoded_response);
             }
+            id if id == NmpIdImage::SlotInfo as u8 && request_header.group == NmpGroup::Image => {
+                let slot_info_response = SlotInfoRsp {
+                    images: vec![SlotInfoImage {
+                        image: 0,
+                        slots: vec![
+                            SlotInfoSlot {
+                                slot: 0,
+                                size: 0x60000,
+                                upload_image_id: None,
+                            },
+                            SlotInfoSlot {
+                                slot: 1,
+                                size: 0x60000,
+                                upload_image_id: Some(1),
+                            },
+                        ],
+                        max_image_size: Some(0x5f000),
+                    }],
+                };
+                let body = serde_cbor::to_vec(&slot_info_response).unwrap();
+                let (encoded_response, _) = encode_request(
+                    100,
+                    self.crc,
+                    NmpOp::ReadRsp,
+                    NmpGroup::Image,
+                    NmpIdImage::SlotInfo,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            id if id == NmpIdDef::McumgrParams as u8
+                && request_header.group == NmpGroup::Default =>
+            {
+                let params_response = McumgrParamsRsp {
+                    buf_size: 2048,
+                    buf_count: 4,
+                };
+                let body = serde_cbor::to_vec(&params_response).unwrap();
+                let (encoded_response, _) = encode_request(
+                    100,
+                    self.crc,
+                    NmpOp::ReadRsp,
+                    NmpGroup::Default,
+                    NmpIdDef::McumgrParams,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            id if id == NmpIdLog::Show as u8 && request_header.group == NmpGroup::Log => {
+                let body_start = request_cursor.position() as usize;
+                let request: LogShowReq = serde_cbor::from_slice(&data[body_start..]).unwrap();
+                let log_show_response = LogShowRsp {
+                    next_index: 2,
+                    logs: vec![LogShowLog {
+                        name: "reboot_log".to_string(),
+                        entries: (request.index..2)
+                            .map(|index| LogEntry {
+                                msg: serde_cbor::Value::Text(format!("entry {}", index)),
+                                timestamp: index as i64 * 1000,
+                                level: 1,
+                                index,
+                                module: 0,
+                            })
+                            .collect(),
+                    }],
+                };
+                let body = serde_cbor::to_vec(&log_show_response).unwrap();
+                let (encoded_response, _) = encode_request(
+                    100,
+                    self.crc,
+                    NmpOp::ReadRsp,
+                    NmpGroup::Log,
+                    NmpIdLog::Show,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            id if id == NmpIdLog::ModuleList as u8 && request_header.group == NmpGroup::Log => {
+                let response = LogModuleListRsp {
+                    module_map: [("DEFAULT", 0), ("OS", 1), ("TEST", 8)]
+                        .iter()
+                        .map(|(name, number)| (name.to_string(), *number))
+                        .collect(),
+                };
+                let body = serde_cbor::to_vec(&response).unwrap();
+                let (encoded_response, _) = encode_request(
+                    100,
+                    self.crc,
+                    NmpOp::ReadRsp,
+                    NmpGroup::Log,
+                    NmpIdLog::ModuleList,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            id if id == NmpIdLog::LevelList as u8 && request_header.group == NmpGroup::Log => {
+                let response = LogLevelListRsp {
+                    level_map: [("DEBUG", 0), ("INFO", 1), ("WARN", 2), ("ERROR", 3)]
+                        .iter()
+                        .map(|(name, number)| (name.to_string(), *number))
+                        .collect(),
+                };
+                let body = serde_cbor::to_vec(&response).unwrap();
+                let (encoded_response, _) = encode_request(
+                    100,
+                    self.crc,
+                    NmpOp::ReadRsp,
+                    NmpGroup::Log,
+                    NmpIdLog::LevelList,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            id if id == NmpIdRun::Test as u8 && request_header.group == NmpGroup::Run => {
+                let request: RunTestReq = serde_cbor::from_slice(&data[HEADER_LEN..]).unwrap();
+                let rc = match request.name.as_deref() {
+                    None | Some("test_flash") | Some("test_crypto") => 0,
+                    Some(_) => NmpErr::ENoEnt as u32,
+                };
+                let mut response_map = std::collections::BTreeMap::new();
+                response_map.insert("rc", rc);
+                let body = serde_cbor::to_vec(&response_map).unwrap();
+                let (encoded_response, _) = encode_request(
+                    100,
+                    self.crc,
+                    NmpOp::WriteRsp,
+                    NmpGroup::Run,
+                    NmpIdRun::Test,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            id if id == NmpIdRun::List as u8 && request_header.group == NmpGroup::Run => {
+                let run_list_response = RunListRsp {
+                    tests: vec!["test_flash".to_string(), "test_crypto".to_string()],
+                };
+                let body = serde_cbor::to_vec(&run_list_response).unwrap();
+                let (encoded_response, _) = encode_request(
+                    100,
+                    self.crc,
+                    NmpOp::ReadRsp,
+                    NmpGroup::Run,
+                    NmpIdRun::List,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            id if id == NmpIdDef::Reset as u8 && request_header.group == NmpGroup::Default => {
+                let mut response_map = std::collections::BTreeMap::new();
+                response_map.insert("rc", 0);
+                let body = serde_cbor::to_vec(&response_map).unwrap();
+                let (encoded_response, _) = encode_request(
+                    100,
+                    self.crc,
+                    NmpOp::WriteRsp,
+                    NmpGroup::Default,
+                    NmpIdDef::Reset,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            id if id == NmpIdConfig::Val as u8 && request_header.group == NmpGroup::Config => {
+                let body_start = request_cursor.position() as usize;
+                let request: ConfigValReq = serde_cbor::from_slice(&data[body_start..]).unwrap();
+                let (op, response) = if request_header.op == NmpOp::Write {
+                    (
+                        NmpOp::WriteRsp,
+                        ConfigValRsp {
+                            rc: Some(0),
+                            val: None,
+                        },
+                    )
+                } else {
+                    let val = match request.name.as_str() {
+                        "test/text" => Some(serde_cbor::Value::Bytes(b"hello".to_vec())),
+                        "test/number" => Some(serde_cbor::Value::Integer(42)),
+                        _ => None,
+                    };
+                    let rc = match val {
+                        Some(_) => 0,
+                        None => NmpErr::ENoEnt as u32,
+                    };
+                    (NmpOp::ReadRsp, ConfigValRsp { rc: Some(rc), val })
+                };
+                let body = serde_cbor::to_vec(&response).unwrap();
+                let (encoded_response, _) = encode_request(
+                    100,
+                    self.crc,
+                    op,
+                    NmpGroup::Config,
+                    NmpIdConfig::Val,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            id if id == NmpIdFs::Status as u8 && request_header.group == NmpGroup::Fs => {
+                let body_start = request_cursor.position() as usize;
+                let request: FsStatusReq = serde_cbor::from_slice(&data[body_start..]).unwrap();
+                let response = if request.name == "/lfs/test.txt" {
+                    FsStatusRsp {
+                        rc: Some(0),
+                        len: Some(42),
+                    }
+                } else {
+                    FsStatusRsp {
+                        rc: Some(NmpErr::ENoEnt as u32),
+                        len: None,
+                    }
+                };
+                let body = serde_cbor::to_vec(&response).unwrap();
+                let (encoded_response, _) = encode_request(
+                    100,
+                    self.crc,
+                    NmpOp::ReadRsp,
+                    NmpGroup::Fs,
+                    NmpIdFs::Status,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            id if id == NmpIdDef::AppInfo as u8 && request_header.group == NmpGroup::Default => {
+                let body_start = request_cursor.position() as usize;
+                let request: AppInfoReq = serde_cbor::from_slice(&data[body_start..]).unwrap();
+                let output = match request.format.as_deref() {
+                    Some("a") => {
+                        "Zephyr test 3.6.0 v3.6.0 Jan  1 2024 00:00:00 test test test Zephyr"
+                    }
+                    _ => "Zephyr",
+                };
+                let body = serde_cbor::to_vec(&AppInfoRsp {
+                    output: output.to_string(),
+                })
+                .unwrap();
+                let (encoded_response, _) = encode_request(
+                    100,
+                    self.crc,
+                    NmpOp::ReadRsp,
+                    NmpGroup::Default,
+                    NmpIdDef::AppInfo,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            id if id == NmpIdDef::BootloaderInfo as u8
+                && request_header.group == NmpGroup::Default =>
+            {
+                let body_start = request_cursor.position() as usize;
+                let request: BootloaderInfoReq =
+                    serde_cbor::from_slice(&data[body_start..]).unwrap();
+                let response = match request.query.as_deref() {
+                    Some("mode") => BootloaderInfoRsp {
+                        rc: None,
+                        bootloader: None,
+                        mode: Some(3),
+                        no_downgrade: Some(false),
+                    },
+                    _ => BootloaderInfoRsp {
+                        rc: None,
+                        bootloader: Some("MCUboot".to_string()),
+                        mode: None,
+                        no_downgrade: None,
+                    },
+                };
+                let body = serde_cbor::to_vec(&response).unwrap();
+                let (encoded_response, _) = encode_request(
+                    100,
+                    self.crc,
+                    NmpOp::ReadRsp,
+                    NmpGroup::Default,
+                    NmpIdDef::BootloaderInfo,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            id if id == NmpIdDef::ConsEchoCtrl as u8
+                && request_header.group == NmpGroup::Default =>
+            {
+                let body_start = request_cursor.position() as usize;
+                let request: ConsEchoCtrlReq = serde_cbor::from_slice(&data[body_start..]).unwrap();
+                let mut response_map = std::collections::BTreeMap::new();
+                response_map.insert("rc", if request.echo > 1 { 3 } else { 0 });
+                let body = serde_cbor::to_vec(&response_map).unwrap();
+                let (encoded_response, _) = encode_request(
+                    100,
+                    self.crc,
+                    NmpOp::WriteRsp,
+                    NmpGroup::Default,
+                    NmpIdDef::ConsEchoCtrl,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
+            id if id == NmpIdDef::Echo as u8 && request_header.group == NmpGroup::Default => {
+                let body_start = request_cursor.position() as usize;
+                let request: EchoReq = serde_cbor::from_slice(&data[body_start..]).unwrap();
+                let body = serde_cbor::to_vec(&EchoRsp { text: request.text }).unwrap();
+                let (encoded_response, _) = encode_request(
+                    100,
+                    self.crc,
+                    NmpOp::WriteRsp,
+                    NmpGroup::Default,
+                    NmpIdDef::Echo,
+                    &body,
+                    request_header.seq,
+                )
+                .unwrap();
+                self.data.extend_from_slice(&encoded_response);
+            }
             _ => {
                 // Handle other cases or return an error
             }
@@ -193,7 +948,7 @@ impl SerialPort for TestSerialPort {
     }
 
     fn baud_rate(&self) -> serialport::Result<u32> {
-        Ok(115200)
+        Ok(self.baud_rate)
     }
 
     fn data_bits(&self) -> serialport::Result<DataBits> {
@@ -213,10 +968,11 @@ impl SerialPort for TestSerialPort {
     }
 
     fn timeout(&self) -> Duration {
-        Duration::from_secs(1)
+        self.timeout
     }
 
-    fn set_baud_rate(&mut self, _baud_rate: u32) -> serialport::Result<()> {
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.baud_rate = baud_rate;
         Ok(())
     }
 
@@ -239,7 +995,8 @@ impl SerialPort for TestSerialPort {
         Ok(())
     }
 
-    fn set_timeout(&mut self, _timeout: Duration) -> serialport::Result<()> {
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
         Ok(())
     }
 
@@ -268,7 +1025,7 @@ impl SerialPort for TestSerialPort {
     }
 
     fn bytes_to_read(&self) -> serialport::Result<u32> {
-        Ok(0)
+        Ok((self.data.len() - self.position) as u32 + self.stale_bytes_to_read)
     }
 
     fn bytes_to_write(&self) -> serialport::Result<u32> {
@@ -280,7 +1037,19 @@ impl SerialPort for TestSerialPort {
     }
 
     fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
-        unimplemented!()
+        // a replaying port can be kept open, the clones continue with the same log
+        match &self.replay {
+            Some(replay) => {
+                let mut port = TestSerialPort::new();
+                port.replay = Some(replay.clone());
+                port.timeout = self.timeout;
+                Ok(Box::new(port))
+            }
+            None => Err(serialport::Error::new(
+                serialport::ErrorKind::Unknown,
+                "cannot clone the test port",
+            )),
+        }
     }
 
     fn set_break(&self) -> serialport::Result<()> {
@@ -4,33 +4,505 @@ use anyhow::{bail, Context, Error, Result};
 use base64::{engine::general_purpose, Engine as _};
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use crc16::*;
-use hex;
-use lazy_static::lazy_static;
-use log::debug;
+use humantime::format_duration;
+use log::{debug, info, warn};
 use rand::{thread_rng, Rng};
-use serde_cbor;
-use serialport::SerialPort;
+use serialport::{
+    available_ports, DataBits, FlowControl, Parity, SerialPort, SerialPortInfo, SerialPortType,
+    StopBits,
+};
+use std::cell::{Cell, RefCell};
 use std::cmp::min;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
 use std::io::Cursor;
-use std::sync::atomic::{AtomicU8, Ordering};
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::buffered_port::BufferedPort;
+use crate::events::{emit_event, has_event_hook, ProtocolEvent};
 use crate::nmp_hdr::*;
+use crate::raw_port::RawPort;
 use crate::test_serial_port::TestSerialPort;
+use crate::transport_log::LoggingPort;
 
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Crc {
+    /// CRC16/XMODEM, initial value 0x0000
+    Xmodem,
+    /// CRC16/CCITT-FALSE, initial value 0xffff
+    CcittFalse,
+}
+
+impl Crc {
+    pub fn calculate(&self, data: &[u8]) -> u16 {
+        match self {
+            Crc::Xmodem => State::<XMODEM>::calculate(data),
+            Crc::CcittFalse => State::<CCITT_FALSE>::calculate(data),
+        }
+    }
+}
+
+// length of the SMP header, with the body length in bytes 2 and 3
+pub(crate) const HEADER_LEN: usize = 8;
+
+// the packet length before and the CRC16 after the SMP packet in a console frame
+const FRAME_LEN_LEN: usize = 2;
+const FRAME_CRC_LEN: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Framing {
+    /// SMP console transport: base64 lines with start markers, length and checksum
+    Console,
+    /// raw SMP transport: the packets as they are, for the raw UART transport of Zephyr
+    Raw,
+}
+
+#[derive(Clone)]
 pub struct SerialSpecs {
     pub device: String,
     pub initial_timeout_s: u32,
     pub subsequent_timeout_ms: u32,
+    // timeout for the retries of the first upload chunk, instead of the initial timeout
+    pub erase_timeout_s: Option<u32>,
+    // multiplies all timeouts, for slow links
+    pub timeout_scale: f32,
     pub nb_retry: u32,
     pub linelength: usize,
     pub mtu: usize,
-    pub baudrate: u32
+    // maximum length of the SMP body of a request, the MTU limits the framed length
+    pub max_payload: Option<usize>,
+    pub baudrate: u32,
+    pub retry_base_delay_ms: u32,
+    pub retry_max_delay_ms: u32,
+    pub retry_jitter: f32,
+    pub crc: Crc,
+    pub framing: Framing,
+    pub no_sha: bool,
+    pub no_decompress: bool,
+    pub no_slot_check: bool,
+    // correct the image number of an upload with the dependencies of the image
+    pub auto_image: bool,
+    // erase the upload slot before an upload, which clears the upload state of the device
+    pub reset_upload_state: bool,
+    // send the image length with every upload request, not only with the first
+    pub len_every_chunk: bool,
+    pub canonical_cbor: bool,
+    pub keep_open: bool,
+    pub no_flush: bool,
+    // reopen the port and continue an upload, when the device disconnects during the upload
+    pub auto_recover: bool,
+    // abort an upload which sends more image bytes or takes longer, for unattended flashing
+    pub max_upload_bytes: Option<u64>,
+    pub max_upload_duration: Option<Duration>,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    pub read_chunk_size: usize,
+    pub strict_id: bool,
+    // scripted responses for the "test" device
+    pub test_scenario: Option<PathBuf>,
+    // file for the bytes sent and received, to replay the session
+    pub transport_log: Option<PathBuf>,
+    // added to the CBOR map of each request, for vendor specific fields
+    pub extra_fields: Vec<(String, serde_cbor::Value)>,
+}
+
+impl Default for SerialSpecs {
+    fn default() -> SerialSpecs {
+        SerialSpecs {
+            device: String::new(),
+            initial_timeout_s: 60,
+            subsequent_timeout_ms: 200,
+            erase_timeout_s: None,
+            timeout_scale: 1.0,
+            nb_retry: 4,
+            linelength: 128,
+            mtu: 512,
+            max_payload: None,
+            baudrate: 115_200,
+            retry_base_delay_ms: 50,
+            retry_max_delay_ms: 2000,
+            retry_jitter: 0.0,
+            crc: Crc::Xmodem,
+            framing: Framing::Console,
+            no_sha: false,
+            no_decompress: false,
+            no_slot_check: false,
+            auto_image: false,
+            reset_upload_state: false,
+            len_every_chunk: false,
+            canonical_cbor: false,
+            keep_open: false,
+            no_flush: false,
+            auto_recover: false,
+            max_upload_bytes: None,
+            max_upload_duration: None,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            read_chunk_size: 1024,
+            strict_id: false,
+            test_scenario: None,
+            transport_log: None,
+            extra_fields: Vec::new(),
+        }
+    }
+}
+
+// the longest timeout, also for a timeout scale which would overflow
+const MAX_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+impl SerialSpecs {
+    fn scale_timeout(&self, timeout: Duration) -> Duration {
+        Duration::try_from_secs_f32(timeout.as_secs_f32() * self.timeout_scale)
+            .map_or(MAX_TIMEOUT, |timeout| timeout.min(MAX_TIMEOUT))
+    }
+
+    // timeout for the first answer, which can take long, like when the device erases a slot
+    pub fn initial_timeout(&self) -> Duration {
+        self.scale_timeout(Duration::from_secs(self.initial_timeout_s as u64))
+    }
+
+    // timeout for the retries of the first upload chunk, for a device which erases the whole
+    // slot before it answers
+    pub fn erase_timeout(&self) -> Duration {
+        match self.erase_timeout_s {
+            Some(secs) => self.scale_timeout(Duration::from_secs(secs as u64)),
+            None => self.initial_timeout(),
+        }
+    }
+
+    // timeout for the other answers
+    pub fn subsequent_timeout(&self) -> Duration {
+        self.scale_timeout(Duration::from_millis(self.subsequent_timeout_ms as u64))
+    }
+
+    // the longest SMP packet, the header and the body, of a request of at most mtu bytes after
+    // the framing and with a body of at most max_payload bytes, like for the buffer of the device
+    pub fn max_packet_len(&self) -> usize {
+        let max_body_len = match self.framing {
+            Framing::Console => (0..self.mtu)
+                .rev()
+                .find(|len| framed_len(self.linelength, *len) <= self.mtu)
+                .unwrap_or(0),
+            Framing::Raw => self.mtu.saturating_sub(HEADER_LEN),
+        };
+        HEADER_LEN
+            + self
+                .max_payload
+                .map_or(max_body_len, |max| max_body_len.min(max))
+    }
+
+    // limit the requests to SMP packets of at most buf_size bytes, the buffer size of the device,
+    // and use the MTU of the longest of them
+    pub fn use_buffer_size(&mut self, buf_size: usize) {
+        let max_body_len = buf_size.saturating_sub(HEADER_LEN);
+        self.max_payload = Some(
+            self.max_payload
+                .map_or(max_body_len, |max| max.min(max_body_len)),
+        );
+        self.mtu = match self.framing {
+            Framing::Console => framed_len(self.linelength, max_body_len),
+            Framing::Raw => HEADER_LEN + max_body_len,
+        };
+    }
+
+    // delay before the given retry attempt (starting at 0), doubling per attempt, capped,
+    // plus a random jitter of up to retry_jitter times the base delay
+    pub fn retry_delay(&self, attempt: u32) -> Duration {
+        let delay = (self.retry_base_delay_ms as u64)
+            .saturating_mul(1 << attempt.min(31))
+            .min(self.retry_max_delay_ms as u64);
+        let max_jitter = (self.retry_base_delay_ms as f32 * self.retry_jitter) as u64;
+        let jitter = if max_jitter > 0 {
+            thread_rng().gen_range(0..=max_jitter)
+        } else {
+            0
+        };
+        Duration::from_millis(delay + jitter)
+    }
+}
+
+pub fn is_timeout(e: &Error) -> bool {
+    match e.downcast_ref::<std::io::Error>() {
+        Some(io_error) => io_error.kind() == std::io::ErrorKind::TimedOut,
+        None => false,
+    }
+}
+
+pub fn is_disconnected(e: &Error) -> bool {
+    if let Some(serial_error) = e.downcast_ref::<serialport::Error>() {
+        return match serial_error.kind() {
+            serialport::ErrorKind::NoDevice => true,
+            serialport::ErrorKind::Io(kind) => kind == std::io::ErrorKind::NotFound,
+            _ => false,
+        };
+    }
+    match e.downcast_ref::<std::io::Error>() {
+        Some(io_error) => matches!(
+            io_error.kind(),
+            std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::NotConnected
+                | std::io::ErrorKind::NotFound
+                | std::io::ErrorKind::PermissionDenied
+        ),
+        None => false,
+    }
+}
+
+pub fn port_description(port: &SerialPortInfo) -> String {
+    match &port.port_type {
+        SerialPortType::UsbPort(usb) => format!(
+            "{} (USB {:04x}:{:04x}{}{}{})",
+            port.port_name,
+            usb.vid,
+            usb.pid,
+            usb.manufacturer
+                .as_ref()
+                .map(|m| format!(", {}", m))
+                .unwrap_or_default(),
+            usb.product
+                .as_ref()
+                .map(|p| format!(", {}", p))
+                .unwrap_or_default(),
+            usb.serial_number
+                .as_ref()
+                .map(|s| format!(", serial {}", s))
+                .unwrap_or_default()
+        ),
+        SerialPortType::BluetoothPort => format!("{} (Bluetooth, not a device)", port.port_name),
+        SerialPortType::PciPort => format!("{} (PCI)", port.port_name),
+        SerialPortType::Unknown => port.port_name.clone(),
+    }
+}
+
+// choose the device from the available ports: the only USB port, like the CDC ACM port of a
+// bootloader, or else the only port which isn't a Bluetooth port
+pub fn detect_device(ports: &[SerialPortInfo]) -> Result<String, Error> {
+    choose_device(ports, &bluetooth_com_ports())
+}
+
+// serialport only reports Bluetooth ports on macOS, on Linux they are rfcomm devices, and on
+// Windows the COM ports of the Bluetooth modem in the registry
+fn is_bluetooth_port(port: &SerialPortInfo, bluetooth_com_ports: &[String]) -> bool {
+    matches!(port.port_type, SerialPortType::BluetoothPort)
+        || port.port_name.starts_with("/dev/rfcomm")
+        || bluetooth_com_ports.contains(&port.port_name)
+}
+
+// the COM ports of Bluetooth serial links, from the serial port map of the Windows registry
+#[cfg(windows)]
+fn bluetooth_com_ports() -> Vec<String> {
+    match std::process::Command::new("reg")
+        .args(["query", r"HKLM\HARDWARE\DEVICEMAP\SERIALCOMM"])
+        .output()
+    {
+        Ok(output) => parse_serialcomm(&String::from_utf8_lossy(&output.stdout)),
+        Err(e) => {
+            debug!("no Bluetooth ports from the registry: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn bluetooth_com_ports() -> Vec<String> {
+    Vec::new()
+}
+
+// the ports of the Bluetooth modem devices in the output of reg query, with lines like
+// "    \Device\BthModem0    REG_SZ    COM5"
+#[cfg_attr(not(windows), allow(dead_code))]
+fn parse_serialcomm(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| line.contains("BthModem"))
+        .filter_map(|line| line.split_whitespace().last())
+        .map(|port| port.to_string())
+        .collect()
+}
+
+fn choose_device(
+    ports: &[SerialPortInfo],
+    bluetooth_com_ports: &[String],
+) -> Result<String, Error> {
+    // on Mac, each device has a tty and a cu port, use only the cu port
+    let ports: Vec<&SerialPortInfo> = ports
+        .iter()
+        .filter(|port| env::consts::OS != "macos" || !port.port_name.contains("/dev/tty."))
+        .collect();
+
+    let bootloaders: Vec<&&SerialPortInfo> = ports
+        .iter()
+        .filter(|port| matches!(port.port_type, SerialPortType::UsbPort(_)))
+        .collect();
+    let candidates: Vec<&&SerialPortInfo> = ports
+        .iter()
+        .filter(|port| !is_bluetooth_port(port, bluetooth_com_ports))
+        .collect();
+
+    if bootloaders.len() == 1 {
+        info!(
+            "One bootloader device found, setting device to: {}",
+            bootloaders[0].port_name
+        );
+        Ok(bootloaders[0].port_name.clone())
+    } else if bootloaders.is_empty() && candidates.len() == 1 {
+        info!(
+            "Only one serial port found, setting device to: {}",
+            candidates[0].port_name
+        );
+        Ok(candidates[0].port_name.clone())
+    } else if ports.is_empty() {
+        bail!("No serial port found.");
+    } else {
+        let list: Vec<String> = ports.iter().map(|port| port_description(port)).collect();
+        bail!(
+            "More than one serial port found, please specify one:\n{}",
+            list.join("\n")
+        );
+    }
+}
+
+/// Returns the port of the device: the detected port for an empty name or "auto", the port of
+/// the USB device for usb:<serial number>, otherwise the name as it is.
+pub fn resolve_device(device: &str) -> Result<String, Error> {
+    if device.is_empty() || device == "auto" {
+        detect_device(&available_ports()?)
+    } else if let Some(serial_number) = device.strip_prefix("usb:") {
+        find_usb_device(&available_ports()?, serial_number)
+    } else {
+        Ok(device.to_string())
+    }
+}
+
+// the port of the USB device with this serial number, which stays the same when the port name
+// changes, like after the device enumerates again
+pub fn find_usb_device(ports: &[SerialPortInfo], serial_number: &str) -> Result<String, Error> {
+    let found = ports.iter().find(|port| match &port.port_type {
+        SerialPortType::UsbPort(info) => info.serial_number.as_deref() == Some(serial_number),
+        _ => false,
+    });
+    match found {
+        Some(port) => {
+            info!(
+                "USB serial number {} found, setting device to: {}",
+                serial_number, port.port_name
+            );
+            Ok(port.port_name.clone())
+        }
+        None => {
+            let list: Vec<String> = ports.iter().map(port_description).collect();
+            bail!(
+                "No USB device with serial number {} found, the serial ports are:\n{}",
+                serial_number,
+                list.join("\n")
+            );
+        }
+    }
+}
+
+pub fn usb_serial_number(device: &str) -> Option<String> {
+    available_ports()
+        .ok()?
+        .into_iter()
+        .find(|port| port.port_name == device)
+        .and_then(|port| match port.port_type {
+            SerialPortType::UsbPort(info) => info.serial_number,
+            _ => None,
+        })
+}
+
+// wait until a port with the same name or USB serial number is available again
+pub fn wait_for_port(
+    device: &str,
+    serial_number: Option<&str>,
+    timeout: Duration,
+) -> Result<String, Error> {
+    // the test device is always there
+    if device.to_lowercase() == "test" {
+        return Ok(device.to_string());
+    }
+    let start_time = Instant::now();
+    while start_time.elapsed() < timeout {
+        for port in available_ports().unwrap_or_default() {
+            let same_serial_number = match (&port.port_type, serial_number) {
+                (SerialPortType::UsbPort(info), Some(serial_number)) => {
+                    info.serial_number.as_deref() == Some(serial_number)
+                }
+                _ => false,
+            };
+            if port.port_name == device || same_serial_number {
+                return Ok(port.port_name);
+            }
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    bail!("device {} did not reappear", device);
+}
+
+// run the operation, and when the device disconnects, wait for it and run it again,
+// up to the given number of times
+pub fn with_reconnect<T, F>(
+    specs: &SerialSpecs,
+    reconnect: u32,
+    mut operation: F,
+) -> Result<T, Error>
+where
+    F: FnMut(&SerialSpecs) -> Result<T, Error>,
+{
+    let serial_number = usb_serial_number(&specs.device);
+    let mut specs = specs.clone();
+    let mut attempts_left = reconnect;
+    loop {
+        match operation(&specs) {
+            Err(e) if attempts_left > 0 && is_disconnected(&e) => {
+                attempts_left -= 1;
+                warn!(
+                    "device disconnected, waiting for it to reappear, {} attempts left: {:#}",
+                    attempts_left, e
+                );
+                specs.device = wait_for_port(
+                    &specs.device,
+                    serial_number.as_deref(),
+                    specs.initial_timeout(),
+                )?;
+                info!("reconnecting to {}", specs.device);
+            }
+            result => return result,
+        }
+    }
+}
+
+// run the operation, and when it fails, wait and run it again, up to the given number of times
+pub fn with_repeat<T, F>(repeat: u32, delay: Duration, mut operation: F) -> Result<T, Error>
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    let mut attempts_left = repeat;
+    loop {
+        match operation() {
+            Err(e) if attempts_left > 0 => {
+                attempts_left -= 1;
+                warn!(
+                    "command failed, trying again, {} attempts left: {:#}",
+                    attempts_left, e
+                );
+                thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
 }
 
 fn read_byte(port: &mut dyn SerialPort) -> Result<u8, Error> {
     let mut byte = [0u8];
-    port.read(&mut byte)?;
+    port.read_exact(&mut byte)?;
     Ok(byte[0])
 }
 
@@ -42,27 +514,140 @@ fn expect_byte(port: &mut dyn SerialPort, b: u8) -> Result<(), Error> {
     Ok(())
 }
 
+// the number of bytes shown when the answer doesn't start like an SMP frame
+const NON_SMP_CONTEXT_LEN: usize = 16;
+
+// wait for the start marker of a response frame. Other bytes come from something else than an
+// SMP server, like a GPS on the wrong port or a device with another baudrate, so show them.
+fn expect_frame_start(port: &mut dyn SerialPort) -> Result<(), Error> {
+    let mut received = vec![read_byte(port)?];
+    if received[0] == 6 {
+        received.push(read_byte(port)?);
+        if received[1] == 9 {
+            return Ok(());
+        }
+    }
+    while received.len() < NON_SMP_CONTEXT_LEN && port.bytes_to_read().unwrap_or(0) > 0 {
+        received.push(read_byte(port)?);
+    }
+    let text: String = received
+        .iter()
+        .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+        .collect();
+    bail!(
+        "no SMP response, received {} ({}) instead of a frame start, is this an MCUmgr device \
+         at the right baudrate?",
+        hex::encode(&received),
+        text
+    );
+}
+
+thread_local! {
+    // the port which stays open with keep_open, for the commands of an interactive session
+    static KEPT_PORT: RefCell<Option<(String, Box<dyn SerialPort>)>> = const { RefCell::new(None) };
+}
+
 pub fn open_port(specs: &SerialSpecs) -> Result<Box<dyn SerialPort>, Error> {
-    if specs.device.to_lowercase() == "test" {
-        Ok(Box::new(TestSerialPort::new()))
+    let port: Box<dyn SerialPort> = if specs.device.to_lowercase() == "test" {
+        let mut port = match &specs.test_scenario {
+            Some(path) => TestSerialPort::from_scenario(path)?,
+            None => TestSerialPort::new(),
+        };
+        port.crc = specs.crc;
+        port.baud_rate = specs.baudrate;
+        Box::new(port)
+    } else if specs.keep_open {
+        // opening the port again can reset the device, so use a handle to the open port
+        KEPT_PORT.with(|kept| -> Result<Box<dyn SerialPort>, Error> {
+            let mut kept = kept.borrow_mut();
+            if !matches!(&*kept, Some((device, _)) if *device == specs.device) {
+                *kept = Some((specs.device.clone(), open_serial_port(specs)?));
+            }
+            let (_, port) = kept.as_ref().unwrap();
+            let mut port = port.try_clone()?;
+            port.set_timeout(specs.initial_timeout())?;
+            Ok(port)
+        })?
     } else {
-        serialport::new(&specs.device, specs.baudrate)
-            .timeout(Duration::from_secs(specs.initial_timeout_s as u64))
-            .open()
-            .with_context(|| format!("failed to open serial port {}", &specs.device))
+        open_serial_port(specs)?
+    };
+    let port: Box<dyn SerialPort> = match &specs.transport_log {
+        Some(path) => Box::new(LoggingPort::new(port, path)?),
+        None => port,
+    };
+    let port = Box::new(BufferedPort::new(port, specs.read_chunk_size));
+    // the test device only has the console transport
+    Ok(match specs.framing {
+        Framing::Raw if specs.device.to_lowercase() != "test" => {
+            Box::new(RawPort::new(port, specs.crc))
+        }
+        _ => port,
+    })
+}
+
+fn open_serial_port(specs: &SerialSpecs) -> Result<Box<dyn SerialPort>, Error> {
+    // a recorded session, with the answers from its transport log
+    if let Some(path) = specs.device.strip_prefix("replay:") {
+        return Ok(Box::new(TestSerialPort::from_transport_log(Path::new(
+            path,
+        ))?));
     }
+    serialport::new(&specs.device, specs.baudrate)
+        .data_bits(specs.data_bits)
+        .parity(specs.parity)
+        .stop_bits(specs.stop_bits)
+        .flow_control(specs.flow_control)
+        .timeout(specs.initial_timeout())
+        .open()
+        .with_context(|| format!("failed to open serial port {}", &specs.device))
 }
 
-// thread-safe counter, initialized with a random value on first call
+// close the port kept open with keep_open, like after an error, to open it again next time
+pub fn close_kept_port() {
+    KEPT_PORT.with(|kept| kept.borrow_mut().take());
+}
+
+thread_local! {
+    static SEQ_COUNTER: Cell<u8> = Cell::new(thread_rng().gen::<u8>());
+}
+
+// per-thread counter, initialized with a random value on first call
 pub fn next_seq_id() -> u8 {
-    lazy_static! {
-        static ref COUNTER: AtomicU8 = AtomicU8::new(thread_rng().gen::<u8>());
-    }
-    COUNTER.fetch_add(1, Ordering::SeqCst)
+    SEQ_COUNTER.with(|counter| {
+        let id = counter.get();
+        counter.set(id.wrapping_add(1));
+        id
+    })
 }
 
+// set the sequence id of the next request on this thread, for reproducible frames
+pub fn set_seq_id(seq_id: u8) {
+    SEQ_COUNTER.with(|counter| counter.set(seq_id));
+}
+
+// the length of a request with this body length as encoded by encode_request, with the packet
+// length, header and checksum in base64 lines with start markers and newlines
+pub fn framed_len(linelength: usize, body_len: usize) -> usize {
+    let packet_len = FRAME_LEN_LEN + HEADER_LEN + body_len + FRAME_CRC_LEN;
+    let base64_len = packet_len.div_ceil(3) * 4;
+    let lines = base64_len.div_ceil(line_payload_len(linelength));
+    base64_len + lines * 3
+}
+
+// the shortest line with room for base64 data after the start marker and before the newline
+pub const MIN_LINE_LENGTH: usize = 8;
+
+// the base64 bytes per line, without the start marker and the newline
+fn line_payload_len(linelength: usize) -> usize {
+    linelength.saturating_sub(4).max(1)
+}
+
+/// Encodes a request as it is sent to the device. Returns the framed bytes, split in
+/// base64 lines of at most `linelength` bytes with start markers, and the SMP header
+/// which was used, with the sequence id and body length filled in.
 pub fn encode_request(
     linelength: usize,
+    crc: Crc,
     op: NmpOp,
     group: NmpGroup,
     id: impl NmpId,
@@ -70,6 +655,15 @@ pub fn encode_request(
     seq_id: u8,
 ) -> Result<(Vec<u8>, NmpHdr), Error> {
     // create request
+    // the SMP header and the frame have 16 bit lengths, larger data is transferred with offsets
+    // in several requests, like for uploads
+    if body.len() > u16::MAX as usize - HEADER_LEN - 2 {
+        bail!(
+            "request body too long: {} bytes, the maximum is {} bytes",
+            body.len(),
+            u16::MAX as usize - HEADER_LEN - 2
+        );
+    }
     let mut request_header = NmpHdr::new_req(op, group, id);
     request_header.seq = seq_id;
     request_header.len = body.len() as u16;
@@ -78,8 +672,26 @@ pub fn encode_request(
     serialized.extend(body);
     debug!("serialized: {}", hex::encode(&serialized));
 
+    Ok((encode_frame(linelength, crc, serialized)?, request_header))
+}
+
+// frame an SMP packet, the header and body, for the console transport: with packet length and
+// checksum, base64 encoded in lines of at most linelength bytes with start markers
+pub fn encode_frame(
+    linelength: usize,
+    crc: Crc,
+    mut serialized: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    if linelength < MIN_LINE_LENGTH {
+        bail!(
+            "line length {} too short, the minimum is {}",
+            linelength,
+            MIN_LINE_LENGTH
+        );
+    }
+
     // calculate CRC16 of it and append to the request
-    let checksum = State::<XMODEM>::calculate(&serialized);
+    let checksum = crc.calculate(&serialized);
     serialized.write_u16::<BigEndian>(checksum)?;
 
     // prepend chunk length
@@ -108,55 +720,216 @@ pub fn encode_request(
             // thread::sleep(Duration::from_millis(20));
             data.extend_from_slice(&[4, 20]);
         }
-        let write_len = min(linelength - 4, totlen - written);
+        let write_len = min(line_payload_len(linelength), totlen - written);
         data.extend_from_slice(&base64_data[written..written + write_len]);
         data.push(b'\n');
         written += write_len;
     }
 
-    Ok((data, request_header))
+    Ok(data)
+}
+
+pub fn get_rc(response_body: &serde_cbor::Value) -> Option<u32> {
+    let mut rc: Option<u32> = None;
+    if let serde_cbor::Value::Map(object) = response_body {
+        for (key, val) in object.iter() {
+            match key {
+                serde_cbor::Value::Text(rc_key) if rc_key == "rc" => {
+                    if let serde_cbor::Value::Integer(parsed_rc) = val {
+                        rc = Some(*parsed_rc as u32);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    rc
+}
+
+// (group, request id, response id) of answers with another id than the request, which are
+// accepted with strict_id. None are known so far.
+const ID_MISMATCH_EXCEPTIONS: &[(NmpGroup, u8, u8)] = &[];
+
+// with strict_id, an answer with another id than the request is rejected, otherwise only logged
+pub fn check_answer(request_header: &NmpHdr, response_header: &NmpHdr, strict_id: bool) -> bool {
+    // verify sequence id
+    if response_header.seq != request_header.seq {
+        log::debug!("wrong sequence number");
+        return false;
+    }
+
+    let expected_op_type = match request_header.op {
+        NmpOp::Read => NmpOp::ReadRsp,
+        NmpOp::Write => NmpOp::WriteRsp,
+        _ => return false,
+    };
+
+    // verify response
+    if response_header.op != expected_op_type || response_header.group != request_header.group {
+        log::debug!("wrong response types");
+        return false;
+    }
+
+    if response_header.id != request_header.id {
+        let exception = ID_MISMATCH_EXCEPTIONS.contains(&(
+            request_header.group,
+            request_header.id,
+            response_header.id,
+        ));
+        if !exception {
+            warn!(
+                "answer id {} does not match the request id {} of group {:?}",
+                response_header.id, request_header.id, request_header.group
+            );
+            if strict_id {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+// discard pending input, with a short timeout, because bytes_to_read can be stale
+fn drain_input(port: &mut dyn SerialPort) -> Result<(), Error> {
+    const MAX_DRAIN: usize = 64 * 1024;
+    if port.bytes_to_read()? == 0 {
+        return Ok(());
+    }
+    let timeout = port.timeout();
+    port.set_timeout(Duration::from_millis(10))?;
+    let mut buffer = [0u8; 256];
+    let mut drained = 0;
+    let result = loop {
+        if drained >= MAX_DRAIN {
+            break Ok(());
+        }
+        match port.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(n) => drained += n,
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    debug!("discarded {} bytes of input", drained);
+    port.set_timeout(timeout)?;
+    Ok(result?)
 }
 
 pub fn transceive(
     port: &mut dyn SerialPort,
-    data: &Vec<u8>,
+    crc: Crc,
+    data: &[u8],
+    seq: u8,
+    flush_input: bool,
 ) -> Result<(NmpHdr, serde_cbor::Value), Error> {
-    // empty input buffer
-    let to_read = port.bytes_to_read()?;
-    for _ in 0..to_read {
-        read_byte(&mut *port)?;
+    send_frame(&mut *port, data, flush_input)?;
+    receive_response(&mut *port, crc, seq)
+}
+
+pub fn send_frame(port: &mut dyn SerialPort, data: &[u8], flush_input: bool) -> Result<(), Error> {
+    // empty input buffer, unless a response can still arrive, which would be dropped
+    if flush_input {
+        drain_input(&mut *port)?;
     }
 
     // write request
     port.write_all(data)?;
+    if has_event_hook() {
+        if let Some(header) = frame_header(data) {
+            emit_event(ProtocolEvent::RequestSent {
+                header,
+                len: data.len(),
+            });
+        }
+    }
+    Ok(())
+}
 
-    // read result
+// the SMP header of a request encoded by encode_request, from the start of the first line
+fn frame_header(data: &[u8]) -> Option<NmpHdr> {
+    // 16 base64 characters are 12 bytes, for the packet length and the header
+    let encoded = data.strip_prefix(&[6, 9])?.get(..16)?;
+    let decoded = general_purpose::STANDARD.decode(encoded).ok()?;
+    NmpHdr::deserialize(&mut Cursor::new(&decoded[2..].to_vec())).ok()
+}
+
+// wait for the response with the sequence id of the request, and skip stale responses, like to
+// an earlier request which timed out. The port timeout is for each read, so the stale responses
+// are only skipped until it has passed once, for a device which keeps sending other frames.
+pub fn receive_response(
+    port: &mut dyn SerialPort,
+    crc: Crc,
+    seq: u8,
+) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+    let mut decode_time = Duration::ZERO;
+    receive_response_timed(port, crc, seq, &mut decode_time)
+}
+
+// like receive_response, and add the time for decoding the frames to decode_time, to tell it
+// from the time waiting for the device
+pub fn receive_response_timed(
+    port: &mut dyn SerialPort,
+    crc: Crc,
+    seq: u8,
+    decode_time: &mut Duration,
+) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+    let deadline = Instant::now() + port.timeout();
+    loop {
+        let data = receive_frame(&mut *port)?;
+        let decode_start = Instant::now();
+        let (header, body) = decode_base64_frame(crc, &data)?;
+        *decode_time += decode_start.elapsed();
+        if header.seq == seq {
+            emit_event(ProtocolEvent::ResponseReceived { header });
+            return Ok((header, body));
+        }
+        debug!(
+            "skipping stale response with sequence id {}, expected {}",
+            header.seq, seq
+        );
+        if Instant::now() >= deadline {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("only stale responses, none with sequence id {}", seq),
+            )
+            .into());
+        }
+    }
+}
+
+// wait for a response frame and return the base64 data of all its lines
+fn receive_frame(port: &mut dyn SerialPort) -> Result<Vec<u8>, Error> {
     let mut bytes_read = 0;
     let mut expected_len = 0;
     let mut result: Vec<u8> = Vec::new();
     loop {
         // first wait for the chunk start marker
         if bytes_read == 0 {
-            expect_byte(&mut *port, 6)?;
-            expect_byte(&mut *port, 9)?;
+            expect_frame_start(&mut *port)?;
         } else {
             expect_byte(&mut *port, 4)?;
             expect_byte(&mut *port, 20)?;
         }
 
-        // next read until newline
+        // next read until newline, without the carriage return of devices which send \r\n
         loop {
             let b = read_byte(&mut *port)?;
             if b == 0xa {
                 break;
+            } else if b == 0xd {
+                continue;
             } else {
                 result.push(b);
                 bytes_read += 1;
             }
         }
 
-        // try to extract length
-        let decoded: Vec<u8> = general_purpose::STANDARD.decode(&result)?;
+        // try to extract length, a line without data has none
+        let decoded: Vec<u8> = decode_base64(&result)?;
+        if decoded.len() < 2 {
+            bail!("frame too short: {} bytes", decoded.len());
+        }
         if expected_len == 0 {
             let len = BigEndian::read_u16(&decoded);
             if len > 0 {
@@ -171,12 +944,172 @@ pub fn transceive(
         }
     }
 
-    // decode base64
-    debug!("result string: {}", String::from_utf8(result.clone())?);
-    let decoded: Vec<u8> = general_purpose::STANDARD.decode(&result)?;
+    Ok(result)
+}
+
+// add the extra fields to the CBOR map of a request body, without replacing existing fields
+pub fn add_extra_fields(specs: &SerialSpecs, body: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if specs.extra_fields.is_empty() {
+        return Ok(body);
+    }
+    let mut map = if body.is_empty() {
+        BTreeMap::new()
+    } else {
+        match serde_cbor::from_slice(&body)? {
+            serde_cbor::Value::Map(map) => map,
+            _ => bail!("extra fields need a request body which is a map"),
+        }
+    };
+    for (key, value) in &specs.extra_fields {
+        let text = serde_cbor::Value::Text(key.clone());
+        if map.contains_key(&text) {
+            bail!("extra field {} would replace a field of the request", key);
+        }
+        map.insert(text, value.clone());
+    }
+    Ok(serde_cbor::to_vec(&serde_cbor::Value::Map(map))?)
+}
+
+// sort the keys of the CBOR map of a request body in the canonical order of RFC 7049, shorter
+// keys first, for firmware which expects the keys in this order
+pub fn canonical_cbor(body: &[u8]) -> Result<Vec<u8>, Error> {
+    // the map of a Value is ordered like this
+    let value: serde_cbor::Value = serde_cbor::from_slice(body)?;
+    Ok(serde_cbor::to_vec(&value)?)
+}
 
+// send a request on a new connection and return the response body, after verifying the header
+pub fn send_request(
+    specs: &SerialSpecs,
+    op: NmpOp,
+    group: NmpGroup,
+    id: impl NmpId,
+    body: &[u8],
+) -> Result<serde_cbor::Value, Error> {
+    // open serial port
+    let mut port = open_port(specs)?;
+
+    // send request
+    let body = add_extra_fields(specs, body.to_vec())?;
+    let (data, request_header) = encode_request(
+        specs.linelength,
+        specs.crc,
+        op,
+        group,
+        id,
+        &body,
+        next_seq_id(),
+    )?;
+    let (response_header, response_body) = transceive(
+        &mut *port,
+        specs.crc,
+        &data,
+        request_header.seq,
+        !specs.no_flush,
+    )
+    .map_err(|e| {
+        if is_timeout(&e) {
+            let timeout = format_duration(port.timeout());
+            e.context(format!("no answer from device (initial={})", timeout))
+        } else {
+            e
+        }
+    })?;
+
+    if !check_answer(&request_header, &response_header, specs.strict_id) {
+        bail!("wrong answer types")
+    }
+
+    debug!("{:?}", response_body);
+    Ok(response_body)
+}
+
+/// Encodes a request like `send_request` sends it, with the extra fields of the specs and the
+/// next sequence id, but without sending it. Returns the framed bytes, the SMP header and the
+/// CBOR body in the frame. With the raw framing the frame is the SMP packet, the header and
+/// the body, as it is written to the device.
+pub fn encode_raw_request(
+    specs: &SerialSpecs,
+    op: NmpOp,
+    group: NmpGroup,
+    id: u8,
+    body: &[u8],
+) -> Result<(Vec<u8>, NmpHdr, Vec<u8>), Error> {
+    let mut body = add_extra_fields(specs, body.to_vec())?;
+    if specs.canonical_cbor {
+        body = canonical_cbor(&body)?;
+    }
+    let (frame, header) = encode_request(
+        specs.linelength,
+        specs.crc,
+        op,
+        group,
+        RawId(id),
+        &body,
+        next_seq_id(),
+    )?;
+    let frame = match specs.framing {
+        Framing::Console => frame,
+        Framing::Raw => {
+            let mut packet = header.serialize()?;
+            packet.extend(&body);
+            packet
+        }
+    };
+    Ok((frame, header, body))
+}
+
+// fail if the response has a non-zero result code
+pub fn check_rc(response_body: &serde_cbor::Value) -> Result<(), Error> {
+    if let Some(rc) = get_rc(response_body) {
+        if rc != 0 {
+            emit_event(ProtocolEvent::RcError { rc });
+            return Err(DeviceError {
+                rc,
+                response: response_json(response_body),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+// a non-zero result code from the device, with the response for the explanation
+#[derive(Debug)]
+pub struct DeviceError {
+    pub rc: u32,
+    pub response: String,
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Error from device: {}, response: {}",
+            self.rc, self.response
+        )
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+// the result code of a failed request, if the device answered with one
+pub fn device_rc(e: &Error) -> Option<u32> {
+    e.downcast_ref::<DeviceError>().map(|e| e.rc)
+}
+
+// the response as compact JSON for error messages, as it can explain the rc, like with "rsn"
+pub fn response_json(response_body: &serde_cbor::Value) -> String {
+    serde_json::to_string(response_body).unwrap_or_else(|_| format!("{:?}", response_body))
+}
+
+// the SMP packet of a decoded frame, after verifying the length and checksum
+pub fn decode_packet(crc: Crc, decoded: &[u8]) -> Result<Vec<u8>, Error> {
     // verify length: must be the decoded length, minus the 2 bytes to encode the length
-    let len = BigEndian::read_u16(&decoded) as usize;
+    if decoded.len() < 4 {
+        bail!("wrong chunk length");
+    }
+    let len = BigEndian::read_u16(decoded) as usize;
     if len != decoded.len() - 2 {
         bail!("wrong chunk length");
     }
@@ -184,14 +1117,76 @@ pub fn transceive(
     // verify checksum
     let data = decoded[2..decoded.len() - 2].to_vec();
     let read_checksum = BigEndian::read_u16(&decoded[decoded.len() - 2..]);
-    let calculated_checksum = State::<XMODEM>::calculate(&data);
+    let calculated_checksum = crc.calculate(&data);
     if read_checksum != calculated_checksum {
         bail!("wrong checksum");
     }
+    Ok(data)
+}
+
+// decode base64, with the position and the bytes around it in the error
+pub fn decode_base64(data: &[u8]) -> Result<Vec<u8>, Error> {
+    general_purpose::STANDARD.decode(data).map_err(|e| {
+        let position = match e {
+            base64::DecodeError::InvalidByte(position, _)
+            | base64::DecodeError::InvalidLastSymbol(position, _) => position,
+            _ => data.len().saturating_sub(1),
+        };
+        let start = position.saturating_sub(16);
+        let end = min(position + 16, data.len());
+        anyhow::format_err!(
+            "base64 decode error: {}, at byte {} of {}, bytes {}..{}: {}",
+            e,
+            position,
+            data.len(),
+            start,
+            end,
+            hex::encode(&data[start..end])
+        )
+    })
+}
+
+/// Decodes a complete frame as received from the device, including the start markers
+/// and line endings of all lines, or only the base64 data of all lines, like the result
+/// string of verbose mode, and returns the SMP header and the CBOR body.
+pub fn decode_frame(crc: Crc, frame: &[u8]) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+    // the start marker isn't a base64 character
+    if frame.starts_with(&[6, 9]) {
+        decode_base64_frame(crc, &frame_base64(frame)?)
+    } else {
+        decode_base64_frame(crc, frame)
+    }
+}
+
+// the base64 data of all lines of a frame, without markers and line endings
+pub fn frame_base64(frame: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut result: Vec<u8> = Vec::new();
+    for (i, line) in frame
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty())
+        .enumerate()
+    {
+        let marker: &[u8] = if i == 0 { &[6, 9] } else { &[4, 20] };
+        match line.strip_prefix(marker) {
+            Some(data) => result.extend_from_slice(data),
+            None => bail!("line {} does not start with {:?}", i + 1, marker),
+        }
+    }
+    Ok(result)
+}
+
+// decode the base64 data of all lines of a frame, without markers and line endings
+pub fn decode_base64_frame(crc: Crc, result: &[u8]) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+    // decode base64
+    debug!("result string: {}", String::from_utf8(result.to_vec())?);
+    let decoded: Vec<u8> = decode_base64(result)?;
+    let data = decode_packet(crc, &decoded)?;
 
     // read header
     let mut cursor = Cursor::new(&data);
-    let response_header = NmpHdr::deserialize(&mut cursor).unwrap();
+    let response_header = NmpHdr::deserialize(&mut cursor)
+        .map_err(|e| anyhow::format_err!("invalid header: {}", e))?;
     debug!("response header: {:?}", response_header);
 
     debug!("cbor: {}", hex::encode(&data[8..]));
@@ -204,8 +1199,654 @@ pub fn transceive(
 
 #[cfg(test)]
 mod tests {
-    use super::next_seq_id;
-    use std::collections::HashSet;
+    use super::{
+        add_extra_fields, canonical_cbor, check_answer, check_rc, choose_device, decode_frame,
+        detect_device, encode_raw_request, encode_request, find_usb_device, frame_base64,
+        framed_len, is_disconnected, is_timeout, next_seq_id, parse_serialcomm, receive_response,
+        send_request, set_seq_id, transceive, with_reconnect, with_repeat, Crc, Framing,
+        SerialSpecs, HEADER_LEN, MIN_LINE_LENGTH,
+    };
+    use crate::buffered_port::BufferedPort;
+    use crate::nmp_hdr::*;
+    use crate::test_serial_port::{scenario_specs, take_requests, TestSerialPort};
+    use anyhow::{bail, Error};
+    use serde_cbor::Value;
+    use serialport::{SerialPort, SerialPortInfo, SerialPortType, UsbPortInfo};
+    use std::collections::{BTreeMap, HashSet};
+    use std::time::Duration;
+
+    #[test]
+    fn test_short_line_length() {
+        let body = serde_cbor::to_vec(&ImageEraseReq { slot: Some(1) }).unwrap();
+        let encode = |linelength| {
+            encode_request(
+                linelength,
+                Crc::Xmodem,
+                NmpOp::Write,
+                NmpGroup::Image,
+                NmpIdImage::Erase,
+                &body,
+                42,
+            )
+        };
+        for linelength in 0..MIN_LINE_LENGTH {
+            assert!(encode(linelength).is_err());
+            assert!(framed_len(linelength, body.len()) > 0);
+        }
+        let (frame, _) = encode(MIN_LINE_LENGTH).unwrap();
+        assert_eq!(frame.len(), framed_len(MIN_LINE_LENGTH, body.len()));
+        assert!(frame
+            .split(|byte| *byte == b'\n')
+            .all(|line| line.len() < MIN_LINE_LENGTH));
+    }
+
+    #[test]
+    fn test_encode_decode_frame() {
+        let body = serde_cbor::to_vec(&ImageEraseReq { slot: Some(1) }).unwrap();
+        let (frame, request_header) = encode_request(
+            16,
+            Crc::Xmodem,
+            NmpOp::Write,
+            NmpGroup::Image,
+            NmpIdImage::Erase,
+            &body,
+            42,
+        )
+        .unwrap();
+        assert!(frame.starts_with(&[6, 9]));
+        assert_eq!(request_header.seq, 42);
+        assert_eq!(request_header.len as usize, body.len());
+
+        let (header, value) = decode_frame(Crc::Xmodem, &frame).unwrap();
+        assert_eq!(header.op, NmpOp::Write);
+        assert_eq!(header.group, NmpGroup::Image);
+        assert_eq!(header.id, NmpIdImage::Erase as u8);
+        assert_eq!(header.seq, 42);
+        assert_eq!(
+            value,
+            serde_cbor::from_slice::<serde_cbor::Value>(&body).unwrap()
+        );
+
+        assert!(decode_frame(Crc::CcittFalse, &frame).is_err());
+
+        let mut corrupted = frame.clone();
+        corrupted[5] = b'*';
+        let e = decode_frame(Crc::Xmodem, &corrupted).unwrap_err();
+        assert!(e.to_string().contains("at byte 3 of"), "{}", e);
+
+        // the base64 data of all lines, like the result string of verbose mode
+        let data = frame_base64(&frame).unwrap();
+        let (header, _) = decode_frame(Crc::Xmodem, &data).unwrap();
+        assert_eq!(header.seq, 42);
+    }
+
+    #[test]
+    fn test_encode_raw_request() {
+        let specs = SerialSpecs {
+            extra_fields: vec![("force".to_string(), serde_cbor::Value::Integer(1))],
+            canonical_cbor: true,
+            ..Default::default()
+        };
+        let request = serde_cbor::to_vec(&ImageEraseReq { slot: Some(1) }).unwrap();
+        let (frame, header, body) =
+            encode_raw_request(&specs, NmpOp::Write, NmpGroup::Other(100), 5, &request).unwrap();
+        assert_eq!(header.group, NmpGroup::Other(100));
+        assert_eq!(header.id, 5);
+        assert_eq!(header.len as usize, body.len());
+        // the shorter key first
+        let (_, value) = decode_frame(specs.crc, &frame).unwrap();
+        assert_eq!(serde_cbor::to_vec(&value).unwrap(), body);
+        let keys: Vec<String> = match value {
+            Value::Map(map) => map
+                .into_keys()
+                .map(|key| match key {
+                    Value::Text(key) => key,
+                    key => panic!("{:?}", key),
+                })
+                .collect(),
+            value => panic!("{:?}", value),
+        };
+        assert_eq!(keys, ["slot", "force"]);
+
+        // the raw framing has the packet without base64 lines, length and checksum
+        let specs = SerialSpecs {
+            framing: Framing::Raw,
+            ..specs
+        };
+        let (packet, header, body) =
+            encode_raw_request(&specs, NmpOp::Write, NmpGroup::Other(100), 5, &request).unwrap();
+        assert_eq!(packet.len(), HEADER_LEN + body.len());
+        assert_eq!(packet[..HEADER_LEN], header.serialize().unwrap()[..]);
+        assert_eq!(packet[HEADER_LEN..], body[..]);
+    }
+
+    #[test]
+    fn test_framed_len() {
+        for linelength in [8, 100, 128, 8192] {
+            for body_len in 0..600 {
+                let (data, _) = encode_request(
+                    linelength,
+                    Crc::Xmodem,
+                    NmpOp::Write,
+                    NmpGroup::Image,
+                    NmpIdImage::Upload,
+                    &vec![0; body_len],
+                    0,
+                )
+                .unwrap();
+                assert_eq!(framed_len(linelength, body_len), data.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_rc() {
+        let mut map = BTreeMap::new();
+        map.insert(Value::Text("rc".to_string()), Value::Integer(0));
+        check_rc(&Value::Map(map.clone())).unwrap();
+
+        map.insert(Value::Text("rc".to_string()), Value::Integer(8));
+        map.insert(
+            Value::Text("rsn".to_string()),
+            Value::Text("no free slot".to_string()),
+        );
+        let e = check_rc(&Value::Map(map)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            r#"Error from device: 8, response: {"rc":8,"rsn":"no free slot"}"#
+        );
+    }
+
+    #[test]
+    fn test_canonical_cbor() {
+        let req = ImageUploadReq {
+            data: vec![1, 2, 3],
+            image_num: 1,
+            len: Some(3),
+            off: 0,
+            data_sha: None,
+            upgrade: None,
+        };
+        let body = serde_cbor::to_vec(&req).unwrap();
+        assert_eq!(body[1..6], *b"ddata");
+        let mut expected = vec![0xa4];
+        expected.extend_from_slice(b"\x63len\x03");
+        expected.extend_from_slice(b"\x63off\x00");
+        expected.extend_from_slice(b"\x64data\x43\x01\x02\x03");
+        expected.extend_from_slice(b"\x65image\x01");
+        assert_eq!(canonical_cbor(&body).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_set_seq_id() {
+        set_seq_id(254);
+        assert_eq!(next_seq_id(), 254);
+        assert_eq!(next_seq_id(), 255);
+        assert_eq!(next_seq_id(), 0);
+    }
+
+    #[test]
+    fn test_crc() {
+        assert_eq!(Crc::Xmodem.calculate(b"123456789"), 0x31c3);
+        assert_eq!(Crc::CcittFalse.calculate(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn test_transceive_with_stale_bytes_to_read() {
+        let mut port = TestSerialPort::new();
+        port.stale_bytes_to_read = 100;
+        let body = serde_cbor::to_vec(&ImageEraseReq { slot: None }).unwrap();
+        let (data, _) = encode_request(
+            128,
+            Crc::Xmodem,
+            NmpOp::Write,
+            NmpGroup::Image,
+            NmpIdImage::Erase,
+            &body,
+            7,
+        )
+        .unwrap();
+        let (header, _) = transceive(&mut port, Crc::Xmodem, &data, 7, true).unwrap();
+        assert_eq!(header.seq, 7);
+    }
+
+    fn echo_frame(op: NmpOp, text: &str, seq: u8) -> Vec<u8> {
+        let text = text.to_string();
+        let body = match op {
+            NmpOp::Write => serde_cbor::to_vec(&EchoReq { text }),
+            _ => serde_cbor::to_vec(&EchoRsp { text }),
+        }
+        .unwrap();
+        let (frame, _) = encode_request(
+            128,
+            Crc::Xmodem,
+            op,
+            NmpGroup::Default,
+            NmpIdDef::Echo,
+            &body,
+            seq,
+        )
+        .unwrap();
+        frame
+    }
+
+    fn echo_answer(body: serde_cbor::Value) -> String {
+        let ans: EchoRsp = serde_cbor::value::from_value(body).unwrap();
+        ans.text
+    }
+
+    #[test]
+    fn test_transceive_without_flush() {
+        let request = echo_frame(NmpOp::Write, "request", 5);
+
+        // the response which was still arriving is parsed first, instead of being discarded
+        for (flush_input, expected) in [(true, "request"), (false, "buffered")] {
+            let mut port = TestSerialPort::new();
+            port.queue_input(&echo_frame(NmpOp::WriteRsp, "buffered", 5));
+            let (_, body) = transceive(&mut port, Crc::Xmodem, &request, 5, flush_input).unwrap();
+            assert_eq!(echo_answer(body), expected);
+        }
+    }
+
+    #[test]
+    fn test_transceive_skips_stale_response() {
+        let request = echo_frame(NmpOp::Write, "request", 5);
+        let mut port = TestSerialPort::new();
+        port.queue_input(&echo_frame(NmpOp::WriteRsp, "stale", 4));
+        let (header, body) = transceive(&mut port, Crc::Xmodem, &request, 5, false).unwrap();
+        assert_eq!(header.seq, 5);
+        assert_eq!(echo_answer(body), "request");
+
+        // stale responses are skipped only until the timeout
+        let mut port = TestSerialPort::new();
+        port.set_timeout(Duration::ZERO).unwrap();
+        for _ in 0..3 {
+            port.queue_input(&echo_frame(NmpOp::WriteRsp, "stale", 4));
+        }
+        let e = receive_response(&mut port, Crc::Xmodem, 5).unwrap_err();
+        assert!(is_timeout(&e));
+        assert_eq!(
+            e.to_string(),
+            "only stale responses, none with sequence id 5"
+        );
+    }
+
+    #[test]
+    fn test_receive_crlf() {
+        // a frame of several lines, with \r\n line endings
+        let frame = echo_frame(NmpOp::WriteRsp, &"crlf ".repeat(40), 6);
+        let mut crlf = Vec::new();
+        for &b in &frame {
+            if b == b'\n' {
+                crlf.push(b'\r');
+            }
+            crlf.push(b);
+        }
+        assert!(crlf.windows(3).any(|w| w == b"\r\n\x04"));
+
+        let mut port = TestSerialPort::new();
+        port.queue_input(&crlf);
+        let (_, body) = receive_response(&mut port, Crc::Xmodem, 6).unwrap();
+        assert_eq!(echo_answer(body), "crlf ".repeat(40));
+        let (_, body) = decode_frame(Crc::Xmodem, &crlf).unwrap();
+        assert_eq!(echo_answer(body), "crlf ".repeat(40));
+
+        // a line with only the line ending
+        let mut port = TestSerialPort::new();
+        port.queue_input(b"\x06\x09\r\n");
+        let e = receive_response(&mut port, Crc::Xmodem, 6).unwrap_err();
+        assert_eq!(e.to_string(), "frame too short: 0 bytes");
+    }
+
+    #[test]
+    fn test_receive_non_smp() {
+        let mut port = TestSerialPort::new();
+        port.queue_input(b"$GPGGA,123519,4807.038,N\r\n");
+        let e = receive_response(&mut port, Crc::Xmodem, 6).unwrap_err();
+        assert!(!is_timeout(&e));
+        let message = e.to_string();
+        assert!(message.starts_with(
+            "no SMP response, received 2447504747412c3132333531392c3438 ($GPGGA,123519,48)"
+        ));
+        assert!(message.ends_with("is this an MCUmgr device at the right baudrate?"));
+
+        // without any answer, it is a timeout
+        let mut port = TestSerialPort::new();
+        assert!(is_timeout(
+            &receive_response(&mut port, Crc::Xmodem, 6).unwrap_err()
+        ));
+    }
+
+    #[test]
+    fn test_receive_long_response() {
+        // a response of many lines, with the largest body which fits in the 16 bit lengths
+        let text: String = (0..65_000)
+            .map(|i| (b'a' + (i % 26) as u8) as char)
+            .collect();
+        let body = serde_cbor::to_vec(&EchoRsp { text: text.clone() }).unwrap();
+        let body_len = body.len();
+        let (frame, _) = encode_request(
+            128,
+            Crc::Xmodem,
+            NmpOp::WriteRsp,
+            NmpGroup::Default,
+            NmpIdDef::Echo,
+            &body,
+            3,
+        )
+        .unwrap();
+        let mut port = TestSerialPort::new();
+        port.queue_input(&frame);
+        let (header, body) = receive_response(&mut port, Crc::Xmodem, 3).unwrap();
+        assert_eq!(header.len as usize, body_len);
+        assert_eq!(echo_answer(body), text);
+
+        // a longer body doesn't fit
+        let body = vec![0; 65_526];
+        assert!(encode_request(
+            128,
+            Crc::Xmodem,
+            NmpOp::Write,
+            NmpGroup::Default,
+            NmpIdDef::Echo,
+            &body,
+            3
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_transceive_buffered() {
+        for chunk_size in [1, 7, 1024] {
+            let mut port = BufferedPort::new(Box::new(TestSerialPort::new()), chunk_size);
+            let body = serde_cbor::to_vec(&ImageEraseReq { slot: None }).unwrap();
+            let (data, _) = encode_request(
+                128,
+                Crc::Xmodem,
+                NmpOp::Read,
+                NmpGroup::Image,
+                NmpIdImage::State,
+                &body,
+                9,
+            )
+            .unwrap();
+            let (header, body) = transceive(&mut port, Crc::Xmodem, &data, 9, true).unwrap();
+            assert_eq!(header.seq, 9);
+            let state: ImageStateRsp = serde_cbor::value::from_value(body).unwrap();
+            assert_eq!(state.images.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_add_extra_fields() {
+        let mut specs = SerialSpecs {
+            extra_fields: vec![("force".to_string(), serde_cbor::Value::Integer(1))],
+            ..Default::default()
+        };
+        let body = add_extra_fields(&specs, Vec::new()).unwrap();
+        let map: BTreeMap<String, u32> = serde_cbor::from_slice(&body).unwrap();
+        assert_eq!(map.get("force"), Some(&1));
+
+        let body = serde_cbor::to_vec(&ImageEraseReq { slot: Some(1) }).unwrap();
+        let body = add_extra_fields(&specs, body).unwrap();
+        let map: BTreeMap<String, u32> = serde_cbor::from_slice(&body).unwrap();
+        assert_eq!(map.get("slot"), Some(&1));
+        assert_eq!(map.get("force"), Some(&1));
+
+        // required fields are not replaced
+        specs.extra_fields = vec![("slot".to_string(), serde_cbor::Value::Integer(3))];
+        let body = serde_cbor::to_vec(&ImageEraseReq { slot: Some(1) }).unwrap();
+        assert!(add_extra_fields(&specs, body).is_err());
+    }
+
+    #[test]
+    fn test_send_request_vendor_group() {
+        // like the raw command, with a group which has no name
+        let (specs, _scenario) =
+            scenario_specs(r#"[{"group": 100, "id": 2, "response": {"rc": 0, "value": 7}}]"#);
+        take_requests();
+        let body = serde_cbor::to_vec(&BTreeMap::<String, String>::new()).unwrap();
+        let response =
+            send_request(&specs, NmpOp::Read, NmpGroup::Other(100), RawId(2), &body).unwrap();
+        let response: BTreeMap<String, u32> = serde_cbor::value::from_value(response).unwrap();
+        assert_eq!(response.get("value"), Some(&7));
+        let requests = take_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0.group, NmpGroup::Other(100));
+        assert_eq!(requests[0].0.id, 2);
+    }
+
+    #[test]
+    fn test_check_answer_id() {
+        let request = NmpHdr::new_req(NmpOp::Write, NmpGroup::Image, NmpIdImage::Upload);
+        let mut response = NmpHdr::new_req(NmpOp::WriteRsp, NmpGroup::Image, NmpIdImage::Upload);
+        assert!(check_answer(&request, &response, true));
+
+        response.id = NmpIdImage::State as u8;
+        assert!(check_answer(&request, &response, false));
+        assert!(!check_answer(&request, &response, true));
+    }
+
+    #[test]
+    fn test_max_packet_len() {
+        // the defaults, with 512 bytes after the base64 encoding
+        let mut specs = SerialSpecs::default();
+        let packet_len = specs.max_packet_len();
+        assert!(framed_len(128, packet_len - HEADER_LEN) <= 512);
+        assert!(framed_len(128, packet_len - HEADER_LEN + 1) > 512);
+        assert!(packet_len < 384);
+
+        specs.use_buffer_size(384);
+        assert_eq!(specs.max_payload, Some(384 - HEADER_LEN));
+        assert_eq!(specs.mtu, framed_len(128, 384 - HEADER_LEN));
+        assert_eq!(specs.max_packet_len(), 384);
+
+        let mut specs = SerialSpecs {
+            framing: Framing::Raw,
+            max_payload: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(specs.max_packet_len(), HEADER_LEN + 100);
+        specs.use_buffer_size(384);
+        assert_eq!(specs.max_payload, Some(100));
+        assert_eq!(specs.mtu, 384);
+    }
+
+    #[test]
+    fn test_timeout_scale() {
+        let specs = SerialSpecs {
+            timeout_scale: 2.5,
+            ..Default::default()
+        };
+        assert_eq!(specs.initial_timeout(), Duration::from_secs(150));
+        assert_eq!(specs.subsequent_timeout(), Duration::from_millis(500));
+
+        // a scale which would overflow is limited to a day
+        let specs = SerialSpecs {
+            timeout_scale: f32::MAX,
+            ..Default::default()
+        };
+        assert_eq!(specs.initial_timeout(), Duration::from_secs(24 * 60 * 60));
+        assert_eq!(
+            specs.subsequent_timeout(),
+            Duration::from_secs(24 * 60 * 60)
+        );
+    }
+
+    fn port(name: &str, port_type: SerialPortType) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type,
+        }
+    }
+
+    fn usb() -> SerialPortType {
+        SerialPortType::UsbPort(UsbPortInfo {
+            vid: 0x2fe3,
+            pid: 0x0100,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        })
+    }
+
+    #[test]
+    fn test_detect_device() {
+        // serialport reports rfcomm ports on Linux without a type
+        let ports = vec![
+            port("/dev/ttyS0", SerialPortType::Unknown),
+            port("/dev/ttyACM0", usb()),
+            port("/dev/rfcomm0", SerialPortType::Unknown),
+        ];
+        assert_eq!(detect_device(&ports).unwrap(), "/dev/ttyACM0");
+
+        let ports = vec![
+            port("/dev/ttyS0", SerialPortType::Unknown),
+            port("/dev/rfcomm0", SerialPortType::Unknown),
+        ];
+        assert_eq!(detect_device(&ports).unwrap(), "/dev/ttyS0");
+
+        // and on macOS as Bluetooth ports
+        let ports = vec![
+            port("/dev/cu.usbserial-1", SerialPortType::Unknown),
+            port(
+                "/dev/cu.Bluetooth-Incoming-Port",
+                SerialPortType::BluetoothPort,
+            ),
+        ];
+        assert_eq!(detect_device(&ports).unwrap(), "/dev/cu.usbserial-1");
+
+        // on Windows, the Bluetooth COM ports are only known from the registry
+        let serialcomm = "\r\nHKEY_LOCAL_MACHINE\\HARDWARE\\DEVICEMAP\\SERIALCOMM\r\n    \
+            \\Device\\Serial0    REG_SZ    COM1\r\n    \
+            \\Device\\BthModem0    REG_SZ    COM5\r\n    \
+            \\Device\\BthModem1    REG_SZ    COM6\r\n";
+        let bluetooth_com_ports = parse_serialcomm(serialcomm);
+        assert_eq!(bluetooth_com_ports, vec!["COM5", "COM6"]);
+        let ports = vec![
+            port("COM1", SerialPortType::Unknown),
+            port("COM5", SerialPortType::Unknown),
+            port("COM6", SerialPortType::Unknown),
+        ];
+        assert_eq!(choose_device(&ports, &bluetooth_com_ports).unwrap(), "COM1");
+        assert!(choose_device(&ports, &[]).is_err());
+
+        let ports = vec![port("/dev/ttyACM0", usb()), port("/dev/ttyACM1", usb())];
+        let e = detect_device(&ports).unwrap_err().to_string();
+        assert!(e.contains("/dev/ttyACM0 (USB 2fe3:0100)"));
+        assert!(e.contains("/dev/ttyACM1"));
+
+        assert!(detect_device(&[]).is_err());
+    }
+
+    #[test]
+    fn test_find_usb_device() {
+        let with_serial_number = |serial_number: &str| {
+            SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x2fe3,
+                pid: 0x0100,
+                serial_number: Some(serial_number.to_string()),
+                manufacturer: None,
+                product: None,
+            })
+        };
+        let ports = vec![
+            port("/dev/ttyS0", SerialPortType::Unknown),
+            port("/dev/ttyACM0", with_serial_number("A1B2")),
+            port("/dev/ttyACM1", with_serial_number("C3D4")),
+        ];
+        assert_eq!(find_usb_device(&ports, "C3D4").unwrap(), "/dev/ttyACM1");
+        let e = find_usb_device(&ports, "E5F6").unwrap_err().to_string();
+        assert!(e.contains("/dev/ttyACM0 (USB 2fe3:0100, serial A1B2)"));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let specs = SerialSpecs {
+            retry_base_delay_ms: 10,
+            retry_max_delay_ms: 50,
+            ..Default::default()
+        };
+        assert_eq!(specs.retry_delay(0), Duration::from_millis(10));
+        assert_eq!(specs.retry_delay(1), Duration::from_millis(20));
+        assert_eq!(specs.retry_delay(2), Duration::from_millis(40));
+        assert_eq!(specs.retry_delay(3), Duration::from_millis(50));
+        assert_eq!(specs.retry_delay(100), Duration::from_millis(50));
+
+        // with jitter, parallel uploads don't retry at the same time
+        let specs = SerialSpecs {
+            retry_jitter: 0.5,
+            ..specs
+        };
+        for _ in 0..100 {
+            let delay = specs.retry_delay(0);
+            assert!(delay >= Duration::from_millis(10) && delay <= Duration::from_millis(15));
+        }
+    }
+
+    #[test]
+    fn test_with_repeat() {
+        let mut calls = 0;
+        let result = with_repeat(2, Duration::from_millis(1), || {
+            calls += 1;
+            if calls < 3 {
+                bail!("failed");
+            }
+            Ok(calls)
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut calls = 0;
+        let result: Result<(), Error> = with_repeat(1, Duration::from_millis(1), || {
+            calls += 1;
+            bail!("failed");
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn test_with_reconnect() {
+        let specs = SerialSpecs {
+            device: "test".to_string(),
+            ..Default::default()
+        };
+        // a port which disconnects at the first request
+        let request = echo_frame(NmpOp::Write, "again", 5);
+        let mut calls = 0;
+        let mut operation = |specs: &SerialSpecs| {
+            calls += 1;
+            let mut port = TestSerialPort::new();
+            if calls == 1 {
+                port.disconnect_after = Some(0);
+            }
+            transceive(&mut port, specs.crc, &request, 5, false)
+        };
+        let (_, body) = with_reconnect(&specs, 1, &mut operation).unwrap();
+        assert_eq!(echo_answer(body), "again");
+        assert_eq!(calls, 2);
+
+        // without reconnects, the disconnect is an error
+        calls = 0;
+        let e = with_reconnect(&specs, 0, |specs| {
+            calls += 1;
+            let mut port = TestSerialPort::new();
+            port.disconnect_after = Some(0);
+            transceive(&mut port, specs.crc, &request, 5, false)
+        })
+        .unwrap_err();
+        assert!(is_disconnected(&e));
+        assert_eq!(calls, 1);
+
+        // other errors aren't retried
+        calls = 0;
+        let result: Result<(), Error> = with_reconnect(&specs, 3, |_| {
+            calls += 1;
+            bail!("failed");
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
 
     #[test]
     fn test_next_seq_id() {
@@ -213,7 +1854,7 @@ mod tests {
         let initial_id = next_seq_id();
         ids.insert(initial_id);
 
-        for _ in 0..std::u8::MAX {
+        for _ in 0..u8::MAX {
             let id = next_seq_id();
             assert!(ids.insert(id), "Duplicate ID: {}", id);
         }
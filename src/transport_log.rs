@@ -0,0 +1,313 @@
+// Copyright © 2023-2024 Vouch.io LLC
+
+use anyhow::{Context, Error, Result};
+use hex_buffer_serde::{Hex as _, HexForm};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::nmp_hdr::NmpHdr;
+use crate::transfer::{decode_frame, Crc, Framing};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    // sent to the device
+    Tx,
+    // received from the device
+    Rx,
+}
+
+// a line of the transport log, the bytes of one write to or read from the port
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TransportLogEntry {
+    // milliseconds since the Unix epoch
+    pub ms: u64,
+    pub dir: Direction,
+    #[serde(with = "HexForm")]
+    pub data: Vec<u8>,
+}
+
+// the entries of a transport log file, with one JSON object per line
+pub fn read_transport_log(path: &Path) -> Result<Vec<TransportLogEntry>, Error> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open transport log {}", path.to_string_lossy()))?;
+    let mut entries = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line).with_context(|| {
+            format!(
+                "invalid line {} of transport log {}",
+                number + 1,
+                path.to_string_lossy()
+            )
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// the sequence id of the first request in the log, for a replay with the same sequence ids
+pub fn first_seq_id(entries: &[TransportLogEntry], crc: Crc, framing: Framing) -> Option<u8> {
+    let request = entries.iter().find(|entry| entry.dir == Direction::Tx)?;
+    let header = match framing {
+        Framing::Console => decode_frame(crc, &request.data).ok()?.0,
+        Framing::Raw => NmpHdr::deserialize(&mut Cursor::new(&request.data)).ok()?,
+    };
+    Some(header.seq)
+}
+
+// serial port which appends the bytes written to and read from the port to a transport log, to
+// replay the session with the test device
+pub struct LoggingPort {
+    inner: Box<dyn SerialPort>,
+    log: File,
+}
+
+impl LoggingPort {
+    pub fn new(inner: Box<dyn SerialPort>, path: &Path) -> Result<LoggingPort, Error> {
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open transport log {}", path.to_string_lossy()))?;
+        Ok(LoggingPort { inner, log })
+    }
+
+    fn record(&mut self, dir: Direction, data: &[u8]) {
+        let entry = TransportLogEntry {
+            ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_millis() as u64)
+                .unwrap_or(0),
+            dir,
+            data: data.to_vec(),
+        };
+        let result = serde_json::to_string(&entry)
+            .map_err(std::io::Error::from)
+            .and_then(|line| writeln!(self.log, "{}", line));
+        if let Err(e) = result {
+            warn!("failed to write the transport log: {}", e);
+        }
+    }
+}
+
+impl Read for LoggingPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.record(Direction::Rx, &buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl Write for LoggingPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.record(Direction::Tx, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl SerialPort for LoggingPort {
+    fn name(&self) -> Option<String> {
+        self.inner.name()
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        self.inner.baud_rate()
+    }
+
+    fn data_bits(&self) -> serialport::Result<serialport::DataBits> {
+        self.inner.data_bits()
+    }
+
+    fn flow_control(&self) -> serialport::Result<serialport::FlowControl> {
+        self.inner.flow_control()
+    }
+
+    fn parity(&self) -> serialport::Result<serialport::Parity> {
+        self.inner.parity()
+    }
+
+    fn stop_bits(&self) -> serialport::Result<serialport::StopBits> {
+        self.inner.stop_bits()
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.inner.set_baud_rate(baud_rate)
+    }
+
+    fn set_data_bits(&mut self, data_bits: serialport::DataBits) -> serialport::Result<()> {
+        self.inner.set_data_bits(data_bits)
+    }
+
+    fn set_flow_control(
+        &mut self,
+        flow_control: serialport::FlowControl,
+    ) -> serialport::Result<()> {
+        self.inner.set_flow_control(flow_control)
+    }
+
+    fn set_parity(&mut self, parity: serialport::Parity) -> serialport::Result<()> {
+        self.inner.set_parity(parity)
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: serialport::StopBits) -> serialport::Result<()> {
+        self.inner.set_stop_bits(stop_bits)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.inner.write_request_to_send(level)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.inner.write_data_terminal_ready(level)
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        self.inner.read_clear_to_send()
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        self.inner.read_data_set_ready()
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        self.inner.read_ring_indicator()
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        self.inner.read_carrier_detect()
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        self.inner.bytes_to_read()
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        self.inner.bytes_to_write()
+    }
+
+    fn clear(&self, buffer_to_clear: serialport::ClearBuffer) -> serialport::Result<()> {
+        self.inner.clear(buffer_to_clear)
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        // the clone appends to the same log
+        let inner = self.inner.try_clone()?;
+        let log = self.log.try_clone()?;
+        Ok(Box::new(LoggingPort { inner, log }))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        self.inner.set_break()
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        self.inner.clear_break()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{first_seq_id, read_transport_log, Direction, LoggingPort, TransportLogEntry};
+    use crate::nmp_hdr::{EchoReq, NmpGroup, NmpIdDef, NmpOp};
+    use crate::test_serial_port::TestSerialPort;
+    use crate::transfer::{encode_request, transceive, Crc, Framing};
+    use serialport::SerialPort;
+    use std::io::Write;
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!(
+            "mcumgr-client-test-{}-transport.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let body = serde_cbor::to_vec(&EchoReq {
+            text: "hello".to_string(),
+        })
+        .unwrap();
+        let (request, _) = encode_request(
+            128,
+            Crc::Xmodem,
+            NmpOp::Write,
+            NmpGroup::Default,
+            NmpIdDef::Echo,
+            &body,
+            7,
+        )
+        .unwrap();
+
+        // record an echo with the test device
+        let mut port = LoggingPort::new(Box::new(TestSerialPort::new()), &path).unwrap();
+        let (_, recorded) = transceive(&mut port, Crc::Xmodem, &request, 7, true).unwrap();
+        let entries = read_transport_log(&path).unwrap();
+        assert_eq!(entries[0].dir, Direction::Tx);
+        assert_eq!(entries[0].data, request);
+        assert!(entries[1..].iter().all(|entry| entry.dir == Direction::Rx));
+        assert_eq!(
+            first_seq_id(&entries, Crc::Xmodem, Framing::Console),
+            Some(7)
+        );
+
+        // the replay answers with the recorded bytes
+        let mut port = TestSerialPort::from_transport_log(&path).unwrap();
+        let (_, replayed) = transceive(&mut port, Crc::Xmodem, &request, 7, true).unwrap();
+        assert_eq!(replayed, recorded);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_clone_shares_log() {
+        let path = std::env::temp_dir().join(format!(
+            "mcumgr-client-test-{}-clone-transport.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        // a port without a clone fails with an error
+        let port = LoggingPort::new(Box::new(TestSerialPort::new()), &path).unwrap();
+        assert!(port.try_clone().is_err());
+
+        // the clone of a replay port writes to the same log
+        let replay = path.with_extension("replay");
+        let entry = TransportLogEntry {
+            ms: 0,
+            dir: Direction::Rx,
+            data: vec![1, 2, 3],
+        };
+        std::fs::write(&replay, serde_json::to_string(&entry).unwrap()).unwrap();
+        let inner = TestSerialPort::from_transport_log(&replay).unwrap();
+        let port = LoggingPort::new(Box::new(inner), &path).unwrap();
+        let mut clone = port.try_clone().unwrap();
+        clone.write_all(&[4, 5]).unwrap();
+        let entries = read_transport_log(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].dir, Direction::Tx);
+        assert_eq!(entries[0].data, vec![4, 5]);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&replay).unwrap();
+    }
+}